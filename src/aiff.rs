@@ -0,0 +1,272 @@
+//! A decoder for AIFF and AIFF-C files, which `hound` doesn't handle.
+//!
+//! AIFF files consist of a `FORM` chunk containing a `COMM` chunk, which describes the format, and
+//! an `SSND` chunk with the interleaved samples. Uncompressed AIFF-C files share the same layout,
+//! but name the sample encoding in the `COMM` chunk.
+
+use anyhow::{bail, Context};
+
+use crate::sound::Sound;
+
+/// Decodes an AIFF or AIFF-C file.
+pub fn decode(data: &[u8]) -> anyhow::Result<Sound> {
+    let (form, aifc) = match data {
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F', rest @ ..] => (rest, false),
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'C', rest @ ..] => (rest, true),
+        _ => bail!("not an AIFF file"),
+    };
+
+    let mut comm = None;
+    let mut ssnd = None;
+    let mut chunks = form;
+    while chunks.len() >= 8 {
+        let id = &chunks[..4];
+        let size = u32::from_be_bytes(chunks[4..8].try_into().unwrap()) as usize;
+        let body = chunks[8..]
+            .get(..size)
+            .context("AIFF chunk extends past the end of the file")?;
+        match id {
+            b"COMM" => comm = Some(body),
+            b"SSND" => ssnd = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        let next = 8 + size + size % 2;
+        chunks = chunks.get(next..).unwrap_or_default();
+    }
+    let comm = comm.context("AIFF file has no `COMM` chunk")?;
+    let ssnd = ssnd.context("AIFF file has no `SSND` chunk")?;
+
+    if comm.len() < 18 {
+        bail!("AIFF `COMM` chunk is too short");
+    }
+    let channels = u16::from_be_bytes([comm[0], comm[1]]);
+    let frames = u32::from_be_bytes(comm[2..6].try_into().unwrap()) as usize;
+    let bits = u16::from_be_bytes([comm[6], comm[7]]);
+    let sample_rate = extended_to_f64(comm[8..18].try_into().unwrap());
+    let encoding: [u8; 4] = if aifc {
+        comm.get(18..22)
+            .context("AIFF-C `COMM` chunk has no compression type")?
+            .try_into()
+            .unwrap()
+    } else {
+        *b"NONE"
+    };
+
+    if channels == 0 {
+        bail!("AIFF file has no channels");
+    }
+    if !(1.0..=f64::from(u32::MAX)).contains(&sample_rate) {
+        bail!("AIFF file has an invalid sample rate of {sample_rate}");
+    }
+
+    if ssnd.len() < 8 {
+        bail!("AIFF `SSND` chunk is too short");
+    }
+    let offset = u32::from_be_bytes(ssnd[..4].try_into().unwrap()) as usize;
+    let audio = ssnd
+        .get(8 + offset..)
+        .context("AIFF `SSND` offset extends past the chunk")?;
+
+    let encoding = Encoding::new(&encoding, bits)?;
+    let count = frames * usize::from(channels);
+    let width = encoding.width();
+    if audio.len() < count * width {
+        bail!("AIFF file is truncated");
+    }
+    let samples = audio
+        .chunks_exact(width)
+        .take(count)
+        .map(|sample| encoding.decode(sample))
+        .collect();
+
+    Ok(Sound {
+        channels,
+        sample_rate: sample_rate.round() as u32,
+        samples,
+    })
+}
+
+/// The ways samples are stored in the `SSND` chunk.
+enum Encoding {
+    /// Signed integers with the given number of bytes, left-justified.
+    BigEndian(usize),
+    /// Like `BigEndian`, but little-endian (AIFF-C `sowt`).
+    LittleEndian(usize),
+    Float32,
+    Float64,
+}
+
+impl Encoding {
+    fn new(compression: &[u8; 4], bits: u16) -> anyhow::Result<Self> {
+        let int_width = || match bits {
+            1..=32 => Ok(usize::from(bits).div_ceil(8)),
+            _ => bail!("{bits}-bit AIFF files are not supported"),
+        };
+        Ok(match compression {
+            b"NONE" | b"twos" => Encoding::BigEndian(int_width()?),
+            b"sowt" => Encoding::LittleEndian(int_width()?),
+            b"fl32" | b"FL32" => Encoding::Float32,
+            b"fl64" | b"FL64" => Encoding::Float64,
+            _ => bail!(
+                "AIFF-C compression type '{}' is not supported",
+                String::from_utf8_lossy(compression).trim_end(),
+            ),
+        })
+    }
+
+    /// Returns the number of bytes per sample.
+    fn width(&self) -> usize {
+        match self {
+            Encoding::BigEndian(width) | Encoding::LittleEndian(width) => *width,
+            Encoding::Float32 => 4,
+            Encoding::Float64 => 8,
+        }
+    }
+
+    /// Decodes a single sample of `self.width()` bytes.
+    fn decode(&self, bytes: &[u8]) -> f32 {
+        match self {
+            Encoding::BigEndian(width) | Encoding::LittleEndian(width) => {
+                // Place the sample in the top bytes of an `i32`, which also sign-extends it.
+                let mut buf = [0; 4];
+                if let Encoding::BigEndian(_) = self {
+                    buf[..*width].copy_from_slice(bytes);
+                } else {
+                    for (out, byte) in buf.iter_mut().zip(bytes.iter().rev()) {
+                        *out = *byte;
+                    }
+                }
+                i32::from_be_bytes(buf) as f32 / 2f32.powi(31)
+            }
+            Encoding::Float32 => f32::from_be_bytes(bytes.try_into().unwrap()),
+            Encoding::Float64 => f64::from_be_bytes(bytes.try_into().unwrap()) as f32,
+        }
+    }
+}
+
+/// Converts an 80-bit IEEE 754 extended precision number, which AIFF uses for the sample rate.
+fn extended_to_f64(bytes: [u8; 10]) -> f64 {
+    let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from(u16::from_be_bytes([bytes[0] & 0x7F, bytes[1]]));
+    let mantissa = u64::from_be_bytes(bytes[2..].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    // The mantissa has an explicit integer bit, so it represents `mantissa / 2^63`.
+    sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 44100 as an 80-bit extended precision number.
+    const RATE_44100: [u8; 10] = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((body.len() as u32).to_be_bytes());
+        chunk.extend(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// Builds an AIFF file, or an AIFF-C file if `compression` is given.
+    fn aiff(channels: u16, bits: u16, compression: Option<&[u8; 4]>, audio: &[u8]) -> Vec<u8> {
+        let width = usize::from(bits).div_ceil(8);
+        let frames = audio.len() / width / usize::from(channels);
+        let mut comm = channels.to_be_bytes().to_vec();
+        comm.extend((frames as u32).to_be_bytes());
+        comm.extend(bits.to_be_bytes());
+        comm.extend(RATE_44100);
+        if let Some(compression) = compression {
+            comm.extend(compression);
+            // An empty Pascal string as the compression name.
+            comm.extend([0, 0]);
+        }
+        let mut ssnd = vec![0; 8];
+        ssnd.extend(audio);
+
+        let mut form = if compression.is_some() {
+            b"AIFC".to_vec()
+        } else {
+            b"AIFF".to_vec()
+        };
+        form.extend(chunk(b"COMM", &comm));
+        // Unknown chunks are skipped.
+        form.extend(chunk(b"NAME", b"odd"));
+        form.extend(chunk(b"SSND", &ssnd));
+        chunk(b"FORM", &form)
+    }
+
+    #[test]
+    fn parses_extended_sample_rates() {
+        assert_eq!(extended_to_f64(RATE_44100), 44100.0);
+        assert_eq!(
+            extended_to_f64([0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]),
+            48000.0
+        );
+        assert_eq!(
+            extended_to_f64([0x40, 0x0B, 0xFA, 0, 0, 0, 0, 0, 0, 0]),
+            8000.0
+        );
+        assert_eq!(
+            extended_to_f64([0x3F, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            1.0
+        );
+        assert_eq!(
+            extended_to_f64([0xBF, 0xFF, 0xC0, 0, 0, 0, 0, 0, 0, 0]),
+            -1.5
+        );
+        assert_eq!(extended_to_f64([0; 10]), 0.0);
+    }
+
+    #[test]
+    fn decodes_big_endian_16_bit() {
+        let audio = [0x7F, 0xFF, 0x80, 0x00, 0x40, 0x00, 0x00, 0x00];
+        let sound = decode(&aiff(2, 16, None, &audio)).unwrap();
+        assert_eq!(sound.channels, 2);
+        assert_eq!(sound.sample_rate, 44100);
+        assert_eq!(sound.samples, [1.0 - 2f32.powi(-15), -1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn decodes_big_endian_24_and_8_bit() {
+        let audio = [0x7F, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0xFF, 0xFF, 0xFF];
+        let sound = decode(&aiff(1, 24, None, &audio)).unwrap();
+        assert_eq!(sound.samples, [1.0 - 2f32.powi(-23), -1.0, -2f32.powi(-23)]);
+
+        let sound = decode(&aiff(1, 8, None, &[0x7F, 0x80, 0x40])).unwrap();
+        assert_eq!(sound.samples, [1.0 - 2f32.powi(-7), -1.0, 0.5]);
+    }
+
+    #[test]
+    fn decodes_aifc_encodings() {
+        let sound = decode(&aiff(1, 16, Some(b"twos"), &[0x40, 0x00])).unwrap();
+        assert_eq!(sound.samples, [0.5]);
+        let sound = decode(&aiff(1, 16, Some(b"sowt"), &[0x00, 0x40, 0x00, 0x80])).unwrap();
+        assert_eq!(sound.samples, [0.5, -1.0]);
+        let audio = [0.25f32.to_be_bytes(), (-1.0f32).to_be_bytes()].concat();
+        let sound = decode(&aiff(1, 32, Some(b"fl32"), &audio)).unwrap();
+        assert_eq!(sound.samples, [0.25, -1.0]);
+        let sound = decode(&aiff(1, 64, Some(b"fl64"), &0.75f64.to_be_bytes())).unwrap();
+        assert_eq!(sound.samples, [0.75]);
+    }
+
+    #[test]
+    fn rejects_unsupported_and_broken_files() {
+        let error = decode(&aiff(1, 8, Some(b"ulaw"), &[0])).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "AIFF-C compression type 'ulaw' is not supported"
+        );
+
+        let mut truncated = aiff(1, 16, None, &[0, 1, 2, 3]);
+        truncated.truncate(truncated.len() - 2);
+        assert!(decode(&truncated).is_err());
+        assert!(decode(b"FORM\0\0\0\x04AIFF").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! A decoder for the standard base64 encoding (RFC 4648), used for sounds embedded in the config.
+
+use anyhow::bail;
+
+/// Decodes base64 `text`, ignoring whitespace so that long strings can be wrapped.
+///
+/// The `=` padding at the end is optional.
+pub fn decode(text: &str) -> anyhow::Result<Vec<u8>> {
+    let text = text.trim_end_matches(|c: char| c == '=' || c.is_ascii_whitespace());
+    let mut out = Vec::with_capacity(text.len() / 4 * 3 + 2);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.chars().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => bail!("invalid character '{c}' in base64 data"),
+        };
+        bits = bits << 6 | value;
+        count += 1;
+        if count == 4 {
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+            count = 0;
+        }
+    }
+
+    // The last group encodes one or two bytes in 2 or 3 characters.
+    match count {
+        0 => {}
+        2 => out.push((bits >> 4) as u8),
+        3 => out.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+        _ => bail!("base64 data has an invalid length"),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded() {
+        assert_eq!(decode("").unwrap(), b"");
+        assert_eq!(decode("Zg==").unwrap(), b"f");
+        assert_eq!(decode("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode("Zm9v").unwrap(), b"foo");
+        assert_eq!(decode("Zm9vYmFy").unwrap(), b"foobar");
+        assert_eq!(decode("+/8=").unwrap(), [0xfb, 0xff]);
+    }
+
+    #[test]
+    fn unpadded() {
+        assert_eq!(decode("Zg").unwrap(), b"f");
+        assert_eq!(decode("Zm8").unwrap(), b"fo");
+        assert_eq!(decode("Zm9vYg").unwrap(), b"foob");
+        assert_eq!(decode("Zm9vYmE").unwrap(), b"fooba");
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(decode("Zm9v\nYmFy\n").unwrap(), b"foobar");
+        assert_eq!(decode("  Zm 9v\r\n\tYmE=\n\n").unwrap(), b"fooba");
+        assert_eq!(decode("Zm8=\n").unwrap(), b"fo");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode("Zm9v!").is_err());
+        assert!(decode("Zm9v-_").is_err());
+        assert!(decode("Zg==Zg==").is_err());
+        assert!(decode("Zm9vY").is_err());
+        assert!(decode("Z").is_err());
+    }
+}
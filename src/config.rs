@@ -1,29 +1,123 @@
 use std::{
+    collections::HashMap,
     fmt,
+    hash::{Hash, Hasher},
+    iter,
+    ops::Range,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use anyhow::{bail, Context};
+use evdev::RelativeAxisType;
 use serde::{
-    de::{Error, Unexpected, Visitor},
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer, StrDeserializer},
+        Error, MapAccess, SeqAccess, Unexpected, Visitor,
+    },
     Deserialize, Deserializer,
 };
 
+use crate::{
+    lock,
+    log::{debug, warn},
+    xkb,
+};
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    devices: Option<Vec<String>>,
+    devices: Option<Devices>,
+    ids: Option<Vec<DeviceId>>,
+    ignore_devices: Option<Vec<IgnoredDevice>>,
     audio: Option<PathBuf>,
-    #[serde(default = "default_volume")]
-    volume: f32,
-    buttons: Option<Vec<Key>>,
-    #[serde(default = "default_tray")]
-    tray: bool,
+    audio_inline: Option<String>,
+    audio_release: Option<PathBuf>,
+    release_volume: Option<f32>,
+    audio_double: Option<PathBuf>,
+    #[serde(default)]
+    trigger: Trigger,
+    double_click_ms: Option<u64>,
+    audio_first: Option<PathBuf>,
+    idle_threshold_ms: Option<u64>,
+    hold_ms: Option<u64>,
+    volume: Option<f32>,
+    volume_db: Option<f32>,
+    buttons: Option<Buttons>,
+    keys: Option<HashMap<Key, Button>>,
+    keysyms: Option<HashMap<String, Button>>,
+    keyboard_layout: Option<String>,
+    default_key_sound: Option<PathBuf>,
+    volume_up: Option<Key>,
+    volume_down: Option<Key>,
+    scroll: Option<HashMap<Axis, Button>>,
+    output_device: Option<OutputDevices>,
+    audio_host: Option<String>,
+    buffer_frames: Option<u32>,
+    cooldown_ms: Option<u64>,
+    fade_ms: Option<u64>,
+    #[serde(default)]
+    resample_quality: ResampleQuality,
+    stream_threshold_ms: Option<u64>,
+    #[serde(default)]
+    normalize: bool,
+    #[serde(default)]
+    sound_weights: HashMap<String, f32>,
+    #[serde(default)]
+    pitch_variation: f32,
+    tray: Option<bool>,
+    // The tray settings are still accepted without the tray, so that configurations keep working.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))]
+    tray_toggle_on: TrayToggle,
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))]
+    icon_enabled: Option<PathBuf>,
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))]
+    icon_disabled: Option<PathBuf>,
+    #[serde(default)]
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))]
+    notifications: bool,
+    status_file: Option<StatusFile>,
+    trigger_fifo: Option<PathBuf>,
+    #[serde(default)]
+    grab: bool,
+    #[serde(default = "default_limiter")]
+    limiter: bool,
+    max_voices: Option<usize>,
+    #[serde(default)]
+    latency: Latency,
+    #[serde(default)]
+    repeat: bool,
+    #[serde(default)]
+    startup_sound: bool,
+    #[serde(default = "default_soft_start")]
+    soft_start: bool,
+    #[serde(default)]
+    mute_when_locked: bool,
+    watchdog_ms: Option<u64>,
+    #[serde(default = "default_watchdog_reopen")]
+    watchdog_reopen: bool,
+}
+
+/// Long enough to avoid a pop at the end of a sound, but too short to be heard as a fade.
+const DEFAULT_FADE_MS: u64 = 5;
+
+const DEFAULT_HOLD_MS: u64 = 500;
+
+const DEFAULT_MAX_VOICES: usize = 16;
+
+/// The shortest watchdog interval, below which checking idle devices would keep `clickd` busy.
+const MIN_WATCHDOG_MS: u64 = 100;
+
+fn default_limiter() -> bool {
+    true
 }
 
-fn default_volume() -> f32 {
-    1.0
+fn default_soft_start() -> bool {
+    true
 }
 
-fn default_tray() -> bool {
+fn default_watchdog_reopen() -> bool {
     true
 }
 
@@ -31,38 +125,1079 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             devices: None,
+            ids: None,
+            ignore_devices: None,
             audio: None,
-            volume: default_volume(),
+            audio_inline: None,
+            audio_release: None,
+            release_volume: None,
+            audio_double: None,
+            trigger: Trigger::default(),
+            double_click_ms: None,
+            audio_first: None,
+            idle_threshold_ms: None,
+            hold_ms: None,
+            volume: None,
+            volume_db: None,
             buttons: None,
-            tray: default_tray(),
+            keys: None,
+            keysyms: None,
+            keyboard_layout: None,
+            default_key_sound: None,
+            volume_up: None,
+            volume_down: None,
+            scroll: None,
+            output_device: None,
+            audio_host: None,
+            buffer_frames: None,
+            cooldown_ms: None,
+            fade_ms: None,
+            resample_quality: ResampleQuality::default(),
+            stream_threshold_ms: None,
+            normalize: false,
+            sound_weights: HashMap::new(),
+            pitch_variation: 0.0,
+            tray: None,
+            tray_toggle_on: TrayToggle::default(),
+            icon_enabled: None,
+            icon_disabled: None,
+            notifications: false,
+            status_file: None,
+            trigger_fifo: None,
+            grab: false,
+            limiter: default_limiter(),
+            max_voices: None,
+            latency: Latency::default(),
+            repeat: false,
+            startup_sound: false,
+            soft_start: default_soft_start(),
+            mute_when_locked: false,
+            watchdog_ms: None,
+            watchdog_reopen: default_watchdog_reopen(),
         }
     }
 }
 
 impl Config {
+    /// Checks the settings for values that can't work, and warns about values that probably won't
+    /// do what the user wants.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.audio.is_some() && self.audio_inline.is_some() {
+            bail!("only one of `audio` and `audio_inline` may be set");
+        }
+        match (self.volume, self.volume_db) {
+            (Some(_), Some(_)) => bail!("only one of `volume` and `volume_db` may be set"),
+            (_, Some(db)) if db.is_nan() => bail!("`volume_db` must be a number, but is {db}"),
+            (_, Some(db)) => check_volume(&format!("`volume_db` ({db} dB)"), self.volume())?,
+            _ => check_volume("`volume`", self.volume())?,
+        }
+        if let Some(volume) = self.release_volume {
+            check_volume("`release_volume`", volume)?;
+        }
+        for (name, weight) in &self.sound_weights {
+            if !(weight.is_finite() && *weight >= 0.0) {
+                bail!(
+                    "the weight of '{name}' in `sound_weights` must be at least 0, but is {weight}"
+                );
+            }
+        }
+        let buttons = self.buttons().into_iter().flatten();
+        for (key, button) in buttons {
+            button.check_volumes(&format!("{key:?}"))?;
+        }
+        for (name, device) in self.device_overrides() {
+            let buttons = device.buttons().into_iter().flatten();
+            for (key, button) in buttons {
+                button.check_volumes(&format!("{key:?} on '{name}'"))?;
+            }
+        }
+        for (axis, settings) in self.scroll() {
+            settings.check_volumes(&format!("{axis:?}"))?;
+            if settings.release.is_some()
+                || settings.hold.is_some()
+                || settings.loop_audio.is_some()
+                || settings.release_volume.is_some()
+                || settings.hold_volume.is_some()
+                || settings.loop_volume.is_some()
+                || settings.pan.is_some()
+                || settings.cooldown_ms.is_some()
+                || !settings.modifiers.is_empty()
+            {
+                warn!("scroll axis {axis:?} has release, hold, pan, cooldown or modifier settings, which only apply to buttons");
+            }
+        }
+
+        if let Some(ms @ 1..MIN_WATCHDOG_MS) = self.watchdog_ms {
+            bail!("`watchdog_ms` must be at least {MIN_WATCHDOG_MS} (or 0 to turn it off), but is {ms}");
+        }
+
+        if !cfg!(feature = "tray") && self.tray == Some(true) {
+            warn!("`tray` is set, but `clickd` was built without the `tray` feature; ignoring it");
+        }
+        match (&self.audio_double, self.double_click().is_zero()) {
+            (Some(_), true) => {
+                warn!("`audio_double` is set, but `double_click_ms` isn't; it will never play")
+            }
+            (None, false) => warn!("`double_click_ms` is set, but `audio_double` isn't"),
+            _ => {}
+        }
+        match (&self.audio_first, self.idle_threshold().is_zero()) {
+            (Some(_), true) => {
+                warn!("`audio_first` is set, but `idle_threshold_ms` isn't; it will never play")
+            }
+            (None, false) => warn!("`idle_threshold_ms` is set, but `audio_first` isn't"),
+            _ => {}
+        }
+
+        let has_modifiers = self
+            .buttons()
+            .into_iter()
+            .flatten()
+            .chain(
+                self.device_overrides()
+                    .flat_map(|(_, device)| device.buttons().into_iter().flatten()),
+            )
+            .any(|(_, button)| !button.modifiers.is_empty());
+        if has_modifiers && self.grab {
+            warn!("`grab` is set and buttons have `modifiers`; keyboards will be grabbed too");
+        }
+
+        if self.max_voices == Some(0) {
+            bail!("`max_voices` must be at least 1");
+        }
+
+        if self.buffer_frames == Some(0) {
+            bail!("`buffer_frames` must be at least 1");
+        }
+
+        if !(0.0..1.0).contains(&self.pitch_variation) {
+            bail!(
+                "`pitch_variation` must be at least 0.0 and less than 1.0, but is {}",
+                self.pitch_variation,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of the devices to open, if they are restricted.
     pub fn devices(&self) -> Option<impl Iterator<Item = &str>> {
-        self.devices.as_ref().map(|devs| devs.iter().map(|s| &**s))
+        self.devices
+            .as_ref()
+            .map(|devices| -> Box<dyn Iterator<Item = _>> {
+                match devices {
+                    Devices::List(names) => Box::new(names.iter().map(|name| &**name)),
+                    Devices::Map(map) => Box::new(map.keys().map(|name| &**name)),
+                }
+            })
+    }
+
+    /// Returns the USB vendor and product IDs of the devices to open, if they are restricted.
+    pub fn ids(&self) -> Option<impl Iterator<Item = (u16, u16)> + '_> {
+        self.ids
+            .as_ref()
+            .map(|ids| ids.iter().map(|id| (id.vendor, id.product)))
+    }
+
+    /// Returns the names of the devices to never open, even if they are listed in `devices`.
+    pub fn ignored_devices(&self) -> impl Iterator<Item = &str> {
+        self.ignore_devices
+            .iter()
+            .flatten()
+            .filter_map(|dev| match dev {
+                IgnoredDevice::Name(name) => Some(&**name),
+                IgnoredDevice::Id(_) => None,
+            })
+    }
+
+    /// Returns the USB vendor and product IDs of the devices to never open, even if they are
+    /// listed in `ids`.
+    pub fn ignored_ids(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.ignore_devices
+            .iter()
+            .flatten()
+            .filter_map(|dev| match dev {
+                IgnoredDevice::Name(_) => None,
+                IgnoredDevice::Id(id) => Some((id.vendor, id.product)),
+            })
+    }
+
+    /// Returns the devices that have their own settings, along with those settings.
+    pub fn device_overrides(&self) -> impl Iterator<Item = (&str, &DeviceSettings)> {
+        let map = match &self.devices {
+            Some(Devices::Map(map)) => Some(map),
+            _ => None,
+        };
+        map.into_iter()
+            .flatten()
+            .map(|(name, settings)| (&**name, settings))
     }
 
     pub fn audio_path(&self) -> Option<&Path> {
         self.audio.as_deref()
     }
 
+    /// Returns the base64-encoded sound file to use instead of `audio`.
+    pub fn audio_inline(&self) -> Option<&str> {
+        self.audio_inline.as_deref()
+    }
+
+    pub fn audio_release_path(&self) -> Option<&Path> {
+        self.audio_release.as_deref()
+    }
+
+    /// Returns the volume of `audio_release`, relative to the global volume.
+    pub fn release_volume(&self) -> f32 {
+        self.release_volume.unwrap_or(1.0)
+    }
+
+    /// Returns whether button sounds are played when buttons are pressed, released, or both.
+    pub fn trigger(&self) -> Trigger {
+        self.trigger
+    }
+
+    pub fn audio_double_path(&self) -> Option<&Path> {
+        self.audio_double.as_deref()
+    }
+
+    /// Returns the longest time between two presses of a button that counts as a double click.
+    ///
+    /// A zero duration disables double click detection.
+    pub fn double_click(&self) -> Duration {
+        Duration::from_millis(self.double_click_ms.unwrap_or(0))
+    }
+
+    pub fn audio_first_path(&self) -> Option<&Path> {
+        self.audio_first.as_deref()
+    }
+
+    /// Returns how long no button has to be pressed for the next press to play `audio_first`.
+    ///
+    /// A zero duration disables the first click sound.
+    pub fn idle_threshold(&self) -> Duration {
+        Duration::from_millis(self.idle_threshold_ms.unwrap_or(0))
+    }
+
+    /// Returns how long a button has to be held down to play its `hold` sound.
+    pub fn hold(&self) -> Duration {
+        Duration::from_millis(self.hold_ms.unwrap_or(DEFAULT_HOLD_MS))
+    }
+
+    /// Returns the master volume as a linear factor, converting it from `volume_db` if needed.
     pub fn volume(&self) -> f32 {
-        self.volume
+        match (self.volume, self.volume_db) {
+            (_, Some(db)) => 10f32.powf(db / 20.0),
+            (Some(volume), None) => volume,
+            (None, None) => 1.0,
+        }
+    }
+
+    /// Returns the configured buttons, along with their per-button settings.
+    ///
+    /// Buttons configured via the plain list form use the default (global) settings. The `keys`
+    /// table is added to the buttons, and takes precedence over them. If only `keys` are set,
+    /// `BTN_LEFT` still plays the default sound.
+    pub fn buttons(&self) -> Option<impl Iterator<Item = (evdev::Key, &Button)> + '_> {
+        if self.buttons.is_none() && self.keys.is_none() {
+            return None;
+        }
+        let buttons = match &self.buttons {
+            Some(buttons) => buttons.iter(),
+            None => Box::new(iter::once((evdev::Key::BTN_LEFT, &DEFAULT_BUTTON))),
+        };
+        let keys = self
+            .keys
+            .iter()
+            .flatten()
+            .map(|(key, button)| (key.0, button));
+        Some(buttons.chain(keys))
+    }
+
+    /// Adds the `keysyms` table to `keys`, translated to the keys that produce each keysym with
+    /// the `keyboard_layout`.
+    ///
+    /// Keys that are also listed in `keys` keep the settings given there.
+    pub fn resolve_keysyms(&mut self) -> anyhow::Result<()> {
+        let Some(keysyms) = &self.keysyms else {
+            if self.keyboard_layout.is_some() {
+                warn!("`keyboard_layout` is set, but `keysyms` isn't; it has no effect");
+            }
+            return Ok(());
+        };
+        let keymap = xkb::Keymap::new(self.keyboard_layout.as_deref())
+            .context("`keysyms` requires libxkbcommon")?;
+        let keys = self.keys.get_or_insert_with(HashMap::new);
+        for (name, button) in keysyms {
+            let found = keymap.keys(name)?;
+            if found.is_empty() {
+                warn!("no key produces the keysym `{name}` with the keyboard layout");
+            }
+            for key in found {
+                debug!("keysym `{name}` is produced by {key:?}");
+                keys.entry(Key(key)).or_insert_with(|| button.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the sound to play for keyboard keys that aren't bound to anything else.
+    pub fn default_key_sound_path(&self) -> Option<&Path> {
+        self.default_key_sound.as_deref()
+    }
+
+    /// Returns the key that raises the volume.
+    pub fn volume_up(&self) -> Option<evdev::Key> {
+        self.volume_up.as_ref().map(|key| key.0)
+    }
+
+    /// Returns the key that lowers the volume.
+    pub fn volume_down(&self) -> Option<evdev::Key> {
+        self.volume_down.as_ref().map(|key| key.0)
+    }
+
+    /// Returns the scroll wheel axes to play sounds for, along with their settings.
+    pub fn scroll(&self) -> impl Iterator<Item = (RelativeAxisType, &Button)> {
+        self.scroll
+            .iter()
+            .flatten()
+            .map(|(axis, button)| (axis.0, button))
+    }
+
+    /// Returns the names of the output devices to try, in order of preference.
+    ///
+    /// When none of them can be opened (or there are none), the default device is used.
+    pub fn output_devices(&self) -> &[String] {
+        match &self.output_device {
+            Some(OutputDevices(names)) => names,
+            None => &[],
+        }
+    }
+
+    /// Returns the name of the audio host (backend) to use, if it isn't the default one.
+    pub fn audio_host(&self) -> Option<&str> {
+        self.audio_host.as_deref()
+    }
+
+    /// Returns the size of the audio buffer in frames, if the device's default shouldn't be used.
+    pub fn buffer_frames(&self) -> Option<u32> {
+        self.buffer_frames
+    }
+
+    /// Returns the minimum time between two sounds triggered by the same button.
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_millis(self.cooldown_ms.unwrap_or(0))
+    }
+
+    /// Returns how long the end of each sound is faded out for.
+    pub fn fade(&self) -> Duration {
+        Duration::from_millis(self.fade_ms.unwrap_or(DEFAULT_FADE_MS))
     }
 
-    pub fn buttons(&self) -> Option<impl Iterator<Item = evdev::Key> + '_> {
-        self.buttons.as_ref().map(|v| v.iter().map(|key| key.0))
+    /// Returns how sounds are resampled to the output sample rate.
+    pub fn resample_quality(&self) -> ResampleQuality {
+        self.resample_quality
     }
 
+    /// Returns the length above which sounds are streamed from disk instead of being loaded into
+    /// memory, if streaming is enabled.
+    pub fn stream_threshold(&self) -> Option<Duration> {
+        self.stream_threshold_ms.map(Duration::from_millis)
+    }
+
+    /// Returns whether sounds should be scaled to the same peak level when they're loaded.
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+
+    /// Returns how often each sound file in a directory is picked, relative to the others, by
+    /// file name. Files that aren't listed have a weight of 1.
+    pub fn sound_weights(&self) -> &HashMap<String, f32> {
+        &self.sound_weights
+    }
+
+    pub fn pitch_variation(&self) -> f32 {
+        self.pitch_variation
+    }
+
+    #[cfg(feature = "tray")]
     pub fn tray(&self) -> bool {
-        self.tray
+        self.tray.unwrap_or(true)
+    }
+
+    /// Returns whether clicking the tray icon toggles `clickd`, or only its menu does.
+    #[cfg(feature = "tray")]
+    pub fn tray_toggle_on(&self) -> TrayToggle {
+        self.tray_toggle_on
+    }
+
+    /// Returns the PNG file to use as the tray icon while clicking is enabled.
+    #[cfg(feature = "tray")]
+    pub fn icon_enabled(&self) -> Option<&Path> {
+        self.icon_enabled.as_deref()
+    }
+
+    /// Returns the PNG file to use as the tray icon while clicking is disabled.
+    #[cfg(feature = "tray")]
+    pub fn icon_disabled(&self) -> Option<&Path> {
+        self.icon_disabled.as_deref()
+    }
+
+    /// Returns whether to show a notification when `clickd` is toggled from the tray.
+    #[cfg(feature = "tray")]
+    pub fn notifications(&self) -> bool {
+        self.notifications
+    }
+
+    /// Returns where to write the status file, if it's enabled.
+    pub fn status_file(&self) -> Option<PathBuf> {
+        match &self.status_file {
+            Some(StatusFile::Enabled(true)) => Some(lock::runtime_file("status")),
+            Some(StatusFile::Path(path)) => Some(path.clone()),
+            Some(StatusFile::Enabled(false)) | None => None,
+        }
+    }
+
+    /// Returns the path of the FIFO that plays a sound for every line written to it, if any.
+    pub fn trigger_fifo(&self) -> Option<&Path> {
+        self.trigger_fifo.as_deref()
+    }
+
+    /// Returns whether input devices should be grabbed, hiding their events from other programs.
+    pub fn grab(&self) -> bool {
+        self.grab
+    }
+
+    /// Returns whether overlapping sounds should be soft-clipped instead of hard-clipped when
+    /// they get too loud.
+    pub fn limiter(&self) -> bool {
+        self.limiter
+    }
+
+    /// Returns how many sounds may play at the same time.
+    pub fn max_voices(&self) -> usize {
+        self.max_voices.unwrap_or(DEFAULT_MAX_VOICES)
+    }
+
+    /// Returns when the audio stream may be paused while nothing is playing.
+    pub fn latency(&self) -> Latency {
+        self.latency
+    }
+
+    /// Returns whether holding down a key should repeatedly play its sound.
+    pub fn repeat(&self) -> bool {
+        self.repeat
+    }
+
+    /// Returns whether to play the default sound once `clickd` has started.
+    pub fn startup_sound(&self) -> bool {
+        self.startup_sound
+    }
+
+    /// Returns whether the first sound after the output stream (re)starts should be faded in.
+    pub fn soft_start(&self) -> bool {
+        self.soft_start
+    }
+
+    /// Returns whether to stay silent while the login session is locked or inactive.
+    pub fn mute_when_locked(&self) -> bool {
+        self.mute_when_locked
+    }
+
+    /// Returns how long an input device may go without a heartbeat before the watchdog complains
+    /// about it, or `None` if the watchdog is off.
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis)
+    }
+
+    /// Returns whether the watchdog reopens input devices that stopped responding.
+    pub fn watchdog_reopen(&self) -> bool {
+        self.watchdog_reopen
+    }
+}
+
+/// When to play the sound of a button.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// Play it when the button is pressed. Releases only play a release sound, if there is one.
+    #[default]
+    Press,
+    /// Play it when the button is released, instead of when it's pressed.
+    Release,
+    /// Play it both when the button is pressed and when it's released.
+    Both,
+}
+
+/// How sounds are converted to the sample rate of the output device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleQuality {
+    /// Linear interpolation, which is fast, but lets some high frequencies alias.
+    #[default]
+    Linear,
+    /// A windowed sinc filter, which is much slower, but doesn't alias noticeably.
+    Sinc,
+}
+
+/// The trade-off between click latency and power usage, which decides when the audio stream is
+/// paused.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Latency {
+    /// Never pause the stream, so that it doesn't have to start up again for a click.
+    Low,
+    /// Pause the stream after a few seconds of silence.
+    #[default]
+    Balanced,
+    /// Pause the stream as soon as nothing is playing.
+    PowerSave,
+}
+
+/// What toggles `clickd` on and off in the tray.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrayToggle {
+    /// Clicking the icon, as well as the "Enabled" menu item.
+    #[default]
+    Activate,
+    /// Only the "Enabled" menu item, so that clicking the icon does nothing.
+    MenuOnly,
+}
+
+/// Rejects negative volumes, and warns about volumes that amplify the sound.
+fn check_volume(name: &str, volume: f32) -> anyhow::Result<()> {
+    if volume.is_nan() || volume < 0.0 {
+        bail!("{name} must be a non-negative number, but is {volume}");
+    }
+    if volume > 1.0 {
+        warn!("{name} is {volume}, which is above 1.0; loud sounds may clip");
+    }
+    Ok(())
+}
+
+/// Per-button (or per-scroll-wheel) settings, overriding the global ones.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Button {
+    #[serde(alias = "press")]
+    audio: Option<PathBuf>,
+    release: Option<PathBuf>,
+    hold: Option<PathBuf>,
+    #[serde(rename = "loop")]
+    loop_audio: Option<PathBuf>,
+    volume: Option<f32>,
+    press_volume: Option<f32>,
+    release_volume: Option<f32>,
+    hold_volume: Option<f32>,
+    loop_volume: Option<f32>,
+    pan: Option<f32>,
+    cooldown_ms: Option<u64>,
+    #[serde(default)]
+    modifiers: Vec<Key>,
+}
+
+static DEFAULT_BUTTON: Button = Button {
+    audio: None,
+    release: None,
+    hold: None,
+    loop_audio: None,
+    volume: None,
+    press_volume: None,
+    release_volume: None,
+    hold_volume: None,
+    loop_volume: None,
+    pan: None,
+    cooldown_ms: None,
+    modifiers: Vec::new(),
+};
+
+impl Button {
+    pub fn audio_path(&self) -> Option<&Path> {
+        self.audio.as_deref()
+    }
+
+    /// Returns the sound to play when the button is released, instead of the global one.
+    pub fn release_path(&self) -> Option<&Path> {
+        self.release.as_deref()
+    }
+
+    /// Returns the sound to play when the button has been held down for a while.
+    pub fn hold_path(&self) -> Option<&Path> {
+        self.hold.as_deref()
+    }
+
+    /// Returns the sound to loop while the button is held down.
+    pub fn loop_path(&self) -> Option<&Path> {
+        self.loop_audio.as_deref()
+    }
+
+    pub fn volume(&self) -> Option<f32> {
+        self.volume
+    }
+
+    /// Returns the volume of the press sound, relative to the button's `volume`.
+    pub fn press_volume(&self) -> f32 {
+        self.press_volume.unwrap_or(1.0)
+    }
+
+    /// Returns the volume of the release sound, relative to the button's `volume`.
+    pub fn release_volume(&self) -> f32 {
+        self.release_volume.unwrap_or(1.0)
+    }
+
+    /// Returns the volume of the hold sound, relative to the button's `volume`.
+    pub fn hold_volume(&self) -> f32 {
+        self.hold_volume.unwrap_or(1.0)
+    }
+
+    /// Returns the volume of the looping sound, relative to the button's `volume`.
+    pub fn loop_volume(&self) -> f32 {
+        self.loop_volume.unwrap_or(1.0)
+    }
+
+    /// Checks all volumes of the button, which is called `name` in errors.
+    fn check_volumes(&self, name: &str) -> anyhow::Result<()> {
+        let volumes = [
+            ("volume", self.volume),
+            ("press_volume", self.press_volume),
+            ("release_volume", self.release_volume),
+            ("hold_volume", self.hold_volume),
+            ("loop_volume", self.loop_volume),
+        ];
+        for (setting, volume) in volumes {
+            if let Some(volume) = volume {
+                check_volume(&format!("`{setting}` of {name}"), volume)?;
+            }
+        }
+        if !(-1.0..=1.0).contains(&self.pan()) {
+            bail!(
+                "`pan` of {name} must be between -1.0 and 1.0, but is {}",
+                self.pan(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the stereo position of the button's sounds, from -1.0 (left) to 1.0 (right).
+    pub fn pan(&self) -> f32 {
+        self.pan.unwrap_or(0.0)
+    }
+
+    /// Returns the cooldown of the button, if it overrides the global `cooldown_ms`.
+    pub fn cooldown(&self) -> Option<Duration> {
+        self.cooldown_ms.map(Duration::from_millis)
+    }
+
+    /// Returns the keys that must be held down for the button to play a sound.
+    pub fn modifiers(&self) -> Vec<evdev::Key> {
+        self.modifiers.iter().map(|key| key.0).collect()
+    }
+}
+
+/// Settings for a single input device, overriding the global ones.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSettings {
+    audio: Option<PathBuf>,
+    buttons: Option<Buttons>,
+}
+
+impl DeviceSettings {
+    pub fn audio_path(&self) -> Option<&Path> {
+        self.audio.as_deref()
+    }
+
+    /// Returns the buttons to play sounds for on this device, if they differ from the global ones.
+    pub fn buttons(&self) -> Option<impl Iterator<Item = (evdev::Key, &Button)> + '_> {
+        self.buttons.as_ref().map(Buttons::iter)
+    }
+}
+
+/// Merges the parsed config file `other` into `base`, with the settings in `other` taking
+/// precedence.
+///
+/// Tables are merged key by key, recursively, so that a later file can change a single button in
+/// `[keys]` without repeating the others. All other values, including lists, replace the earlier
+/// ones entirely. Setting one of several alternative settings (see [`Table::alternatives`])
+/// removes the others from `base`, so that a later file can switch from `volume` to `volume_db`.
+pub fn merge(base: &mut toml::Table, other: toml::Table) {
+    merge_table(base, other, Table::Top);
+}
+
+fn merge_table(base: &mut toml::Table, other: toml::Table, table: Table) {
+    for key in other.keys() {
+        let groups = table
+            .alternatives()
+            .iter()
+            .filter(|group| group.contains(&key.as_str()))
+            .copied();
+        for alternative in groups.flatten() {
+            // Setting both in the same file is still an error.
+            if !other.contains_key(*alternative) {
+                base.remove(*alternative);
+            }
+        }
+    }
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(other)) => {
+                merge_table(base, other, table.child(&key));
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The kinds of tables in a config file, which have different settings.
+#[derive(Clone, Copy)]
+enum Table {
+    Top,
+    /// A table of button settings by button, like `[buttons]`.
+    Buttons,
+    Button,
+    /// The `[devices]` table of per-device settings.
+    Devices,
+    Device,
+    Other,
+}
+
+impl Table {
+    /// Returns the kind of the table at `key` in this table.
+    fn child(self, key: &str) -> Self {
+        match (self, key) {
+            (Table::Top, "buttons" | "keys" | "keysyms" | "scroll") => Table::Buttons,
+            (Table::Top, "devices") => Table::Devices,
+            (Table::Devices, _) => Table::Device,
+            (Table::Device, "buttons") => Table::Buttons,
+            (Table::Buttons, _) => Table::Button,
+            _ => Table::Other,
+        }
+    }
+
+    /// Returns the groups of settings in this table that set the same thing in different ways, of
+    /// which only one may be set.
+    fn alternatives(self) -> &'static [&'static [&'static str]] {
+        match self {
+            Table::Top => &[&["audio", "audio_inline"], &["volume", "volume_db"]],
+            Table::Button => &[&["audio", "press"]],
+            _ => &[],
+        }
+    }
+}
+
+/// The `devices` setting: either a list of device names, or a table mapping device names to
+/// [`DeviceSettings`].
+enum Devices {
+    List(Vec<String>),
+    Map(HashMap<String, DeviceSettings>),
+}
+
+impl<'de> Deserialize<'de> for Devices {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DevicesVisitor;
+        impl<'de> Visitor<'de> for DevicesVisitor {
+            type Value = Devices;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("list of device names or table of per-device settings")
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Devices::List)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                HashMap::deserialize(MapAccessDeserializer::new(map)).map(Devices::Map)
+            }
+        }
+
+        deserializer.deserialize_any(DevicesVisitor)
     }
 }
 
+/// A device's vendor and product ID, written as a pair of hex numbers like `046d:c52b`.
+struct DeviceId {
+    vendor: u16,
+    product: u16,
+}
+
+impl<'de> Deserialize<'de> for DeviceId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DeviceIdVisitor;
+        impl<'de> Visitor<'de> for DeviceIdVisitor {
+            type Value = DeviceId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("vendor and product ID in hex (like `046d:c52b`)")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let parse = |id: &str| u16::from_str_radix(id, 16).ok();
+                match v.split_once(':') {
+                    Some((vendor, product)) => match (parse(vendor), parse(product)) {
+                        (Some(vendor), Some(product)) => Ok(DeviceId { vendor, product }),
+                        _ => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                    },
+                    None => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_str(DeviceIdVisitor)
+    }
+}
+
+/// An entry of the `ignore_devices` setting: a vendor and product ID like in `ids`, or else a
+/// device name.
+enum IgnoredDevice {
+    Name(String),
+    Id(DeviceId),
+}
+
+impl<'de> Deserialize<'de> for IgnoredDevice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        let id = DeviceId::deserialize(StrDeserializer::<D::Error>::new(&text));
+        Ok(match id {
+            Ok(id) => IgnoredDevice::Id(id),
+            Err(_) => IgnoredDevice::Name(text),
+        })
+    }
+}
+
+/// The `output_device` setting: either a single device name, or a list of them.
+struct OutputDevices(Vec<String>);
+
+impl<'de> Deserialize<'de> for OutputDevices {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct OutputDevicesVisitor;
+        impl<'de> Visitor<'de> for OutputDevicesVisitor {
+            type Value = OutputDevices;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("device name or list of device names")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(OutputDevices(vec![v.to_string()]))
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(OutputDevices)
+            }
+        }
+
+        deserializer.deserialize_any(OutputDevicesVisitor)
+    }
+}
+
+/// The `status_file` setting: either whether to write the status file to its default location,
+/// or the path to write it to.
+enum StatusFile {
+    Enabled(bool),
+    Path(PathBuf),
+}
+
+impl<'de> Deserialize<'de> for StatusFile {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct StatusFileVisitor;
+        impl<'de> Visitor<'de> for StatusFileVisitor {
+            type Value = StatusFile;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("boolean or file path")
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(StatusFile::Enabled(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(StatusFile::Path(v.into()))
+            }
+        }
+
+        deserializer.deserialize_any(StatusFileVisitor)
+    }
+}
+
+/// The `buttons` setting: either a list of keys, or a table mapping keys to [`Button`]s.
+///
+/// The strings `"all-mouse"` and `"all"` are shorthands for lists of all mouse buttons, and of all
+/// mouse buttons and keyboard keys.
+enum Buttons {
+    List(Vec<Key>),
+    Map(HashMap<Key, Button>),
+}
+
+impl Buttons {
+    /// Returns the keys along with their settings, which are the default ones for plain lists.
+    fn iter(&self) -> Box<dyn Iterator<Item = (evdev::Key, &Button)> + '_> {
+        match self {
+            Buttons::List(keys) => Box::new(keys.iter().map(|key| (key.0, &DEFAULT_BUTTON))),
+            Buttons::Map(map) => Box::new(map.iter().map(|(key, button)| (key.0, button))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Buttons {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ButtonsVisitor;
+        impl<'de> Visitor<'de> for ButtonsVisitor {
+            type Value = Buttons;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter
+                    .write_str("list of keys, table of per-key settings, \"all-mouse\" or \"all\"")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let keyboard = match v {
+                    "all-mouse" => 0..0,
+                    "all" => KEYBOARD_KEYS,
+                    _ => return Err(E::invalid_value(Unexpected::Str(v), &self)),
+                };
+                Ok(Buttons::List(
+                    keyboard
+                        .chain(MOUSE_BUTTONS)
+                        .map(|code| Key(evdev::Key::new(code)))
+                        .collect(),
+                ))
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Buttons::List)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                HashMap::deserialize(MapAccessDeserializer::new(map)).map(Buttons::Map)
+            }
+        }
+
+        deserializer.deserialize_any(ButtonsVisitor)
+    }
+}
+
+/// Parses a key from its name, which is written like in the config file.
+pub fn parse_key(name: &str) -> Option<evdev::Key> {
+    Key::from_name(name).map(|key| key.0)
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct Key(evdev::Key);
 
+impl Key {
+    /// Parses a key from its name, like `BTN_LEFT` or `KEY_A`.
+    ///
+    /// For convenience, names are case-insensitive and the `KEY_` prefix may be omitted. The
+    /// kernel's alternative names for some buttons (see [`KEY_ALIASES`]) are accepted as well.
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_uppercase();
+        let alias = || {
+            KEY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map(|(_, key)| *key)
+                .ok_or(())
+        };
+        name.parse()
+            .or_else(|_| format!("KEY_{name}").parse())
+            .or_else(|_| alias())
+            .ok()
+            .map(Key)
+    }
+
+    /// Returns the names of the known keys that are most similar to `name`.
+    fn suggestions(name: &str) -> Vec<String> {
+        let name = name.to_ascii_uppercase();
+        let mut names = (0..KEY_CODES)
+            .map(|code| format!("{:?}", evdev::Key::new(code)))
+            .filter(|known| !known.starts_with("unknown"))
+            .chain(KEY_ALIASES.iter().map(|(alias, _)| alias.to_string()))
+            .map(|known| (edit_distance(&name, &known), known))
+            .filter(|(dist, _)| *dist <= 2)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().take(3).map(|(_, known)| known).collect()
+    }
+}
+
+/// Names from `input-event-codes.h` that share their code with another name, and so aren't known
+/// to `evdev`.
+const KEY_ALIASES: &[(&str, evdev::Key)] = &[
+    ("BTN_MISC", evdev::Key::BTN_0),
+    ("BTN_MOUSE", evdev::Key::BTN_LEFT),
+    ("BTN_JOYSTICK", evdev::Key::BTN_TRIGGER),
+    ("BTN_GAMEPAD", evdev::Key::BTN_SOUTH),
+    ("BTN_A", evdev::Key::BTN_SOUTH),
+    ("BTN_B", evdev::Key::BTN_EAST),
+    ("BTN_X", evdev::Key::BTN_NORTH),
+    ("BTN_Y", evdev::Key::BTN_WEST),
+    ("BTN_DIGI", evdev::Key::BTN_TOOL_PEN),
+    ("BTN_WHEEL", evdev::Key::BTN_GEAR_DOWN),
+];
+
+/// The codes of the keyboard keys, from `KEY_ESC` up to (but not including) `BTN_0`.
+const KEYBOARD_KEYS: Range<u16> = 1..0x100;
+
+/// The codes of the mouse buttons, from `BTN_LEFT` to `BTN_TASK`.
+const MOUSE_BUTTONS: Range<u16> = 0x110..0x118;
+
+/// The number of key codes defined by the kernel (`KEY_CNT`).
+const KEY_CODES: u16 = 0x300;
+
 impl<'de> Deserialize<'de> for Key {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -73,20 +1208,327 @@ impl<'de> Deserialize<'de> for Key {
             type Value = Key;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("evdev `Key` enum value")
+                formatter.write_str("key name (like `BTN_LEFT` or `KEY_A`) or numeric key code")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                match u16::try_from(v) {
+                    Ok(code) if code < KEY_CODES => Ok(Key(evdev::Key::new(code))),
+                    _ => Err(E::invalid_value(Unexpected::Unsigned(v), &self)),
+                }
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                match u64::try_from(v) {
+                    Ok(v) => self.visit_u64(v),
+                    Err(_) => Err(E::invalid_value(Unexpected::Signed(v), &self)),
+                }
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                if let Some(key) = Key::from_name(v) {
+                    return Ok(key);
+                }
+                // Table keys are always strings, even when they're key codes.
+                if let Ok(code) = v.parse::<u64>() {
+                    return self.visit_u64(code);
+                }
+
+                let suggestions = Key::suggestions(v);
+                if suggestions.is_empty() {
+                    Err(E::custom(format_args!(
+                        "unknown key `{v}`; see `/usr/include/linux/input-event-codes.h` for \
+                         valid names"
+                    )))
+                } else {
+                    let suggestions = suggestions
+                        .iter()
+                        .map(|name| format!("`{name}`"))
+                        .collect::<Vec<_>>();
+                    Err(E::custom(format_args!(
+                        "unknown key `{v}`; did you mean {}?",
+                        suggestions.join(" or "),
+                    )))
+                }
+            }
+        }
+
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+/// Computes the Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let subst = diag + usize::from(ca != *cb);
+            diag = row[j + 1];
+            row[j + 1] = subst.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The scroll wheel axes that can be bound to sounds.
+const SCROLL_AXES: &[RelativeAxisType] = &[
+    RelativeAxisType::REL_WHEEL,
+    RelativeAxisType::REL_HWHEEL,
+    RelativeAxisType::REL_WHEEL_HI_RES,
+    RelativeAxisType::REL_HWHEEL_HI_RES,
+];
+
+#[derive(PartialEq, Eq)]
+struct Axis(RelativeAxisType);
+
+impl Hash for Axis {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0 .0.hash(state);
+    }
+}
+
+impl<'de> Deserialize<'de> for Axis {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AxisVisitor;
+        impl<'de> Visitor<'de> for AxisVisitor {
+            type Value = Axis;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str(
+                    "scroll wheel axis (`REL_WHEEL`, `REL_HWHEEL`, `REL_WHEEL_HI_RES` or \
+                     `REL_HWHEEL_HI_RES`)",
+                )
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
             where
                 E: Error,
             {
-                let key = v
-                    .parse::<evdev::Key>()
-                    .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))?;
-                Ok(Key(key))
+                match v.parse::<RelativeAxisType>() {
+                    Ok(axis) if SCROLL_AXES.contains(&axis) => Ok(Axis(axis)),
+                    _ => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_str(AxisVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn button_keys(toml: &str) -> Vec<evdev::Key> {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.buttons().unwrap().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn all_mouse_excludes_touch_keys() {
+        let touch_keys = [
+            evdev::Key::BTN_TOUCH,
+            evdev::Key::BTN_TOOL_FINGER,
+            evdev::Key::BTN_TOOL_DOUBLETAP,
+            evdev::Key::BTN_TOOL_TRIPLETAP,
+        ];
+        for buttons in ["all-mouse", "all"] {
+            let keys = button_keys(&format!("buttons = \"{buttons}\""));
+            assert!(keys.contains(&evdev::Key::BTN_LEFT));
+            assert!(keys.contains(&evdev::Key::BTN_TASK));
+            for key in touch_keys {
+                assert!(!keys.contains(&key), "{buttons} contains {key:?}");
             }
         }
 
-        deserializer.deserialize_str(KeyVisitor)
+        let keys = button_keys("buttons = \"all-mouse\"");
+        assert!(!keys.contains(&evdev::Key::KEY_A));
+        assert_eq!(keys.len(), MOUSE_BUTTONS.len());
+    }
+
+    #[test]
+    fn extended_mouse_buttons_parse() {
+        let buttons = [
+            ("BTN_SIDE", evdev::Key::BTN_SIDE),
+            ("BTN_EXTRA", evdev::Key::BTN_EXTRA),
+            ("BTN_FORWARD", evdev::Key::BTN_FORWARD),
+            ("BTN_BACK", evdev::Key::BTN_BACK),
+            ("BTN_TASK", evdev::Key::BTN_TASK),
+        ];
+        for (name, key) in buttons {
+            assert_eq!(Key::from_name(name).map(|key| key.0), Some(key), "{name}");
+            assert_eq!(
+                Key::from_name(&name.to_ascii_lowercase()).map(|key| key.0),
+                Some(key),
+                "{name}"
+            );
+            assert!(MOUSE_BUTTONS.contains(&key.code()), "{name}");
+        }
+
+        let keys = button_keys(
+            "buttons = [\"BTN_SIDE\", \"BTN_EXTRA\", \"BTN_FORWARD\", \"BTN_BACK\", \"BTN_TASK\"]",
+        );
+        assert_eq!(keys, buttons.map(|(_, key)| key));
+    }
+
+    /// Merges the config files `files`, in order, and parses the result.
+    fn merged(files: &[&str]) -> anyhow::Result<Config> {
+        let mut merged = toml::Table::new();
+        for file in files {
+            merge(&mut merged, toml::from_str(file).unwrap());
+        }
+        let config: Config = toml::Value::Table(merged).try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn merge_overrides_scalars_and_replaces_lists() {
+        let mut base: toml::Table =
+            toml::from_str("latency = \"low\"\nlimiter = true\ndevices = [\"a\", \"b\"]").unwrap();
+        merge(
+            &mut base,
+            toml::from_str("limiter = false\ndevices = [\"c\"]").unwrap(),
+        );
+        let expected: toml::Table =
+            toml::from_str("latency = \"low\"\nlimiter = false\ndevices = [\"c\"]").unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn merge_merges_nested_tables() {
+        let mut base: toml::Table = toml::from_str(
+            "[buttons.BTN_LEFT]\nvolume = 0.5\npan = -1.0\n[buttons.BTN_RIGHT]\nvolume = 0.2",
+        )
+        .unwrap();
+        merge(
+            &mut base,
+            toml::from_str("[buttons.BTN_LEFT]\nvolume = 0.8\n[buttons.BTN_SIDE]\npan = 1.0")
+                .unwrap(),
+        );
+        let expected: toml::Table = toml::from_str(
+            "[buttons.BTN_LEFT]\nvolume = 0.8\npan = -1.0\n[buttons.BTN_RIGHT]\nvolume = 0.2\n\
+             [buttons.BTN_SIDE]\npan = 1.0",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn merge_replaces_alternative_settings() {
+        let config = merged(&["volume = 0.5", "volume_db = -6.0"]).unwrap();
+        assert_eq!(config.volume, None);
+        assert_eq!(config.volume_db, Some(-6.0));
+        let config = merged(&["volume_db = -6.0", "volume = 0.5"]).unwrap();
+        assert_eq!(config.volume(), 0.5);
+
+        let config = merged(&["audio = \"click.wav\"", "audio_inline = \"UklGRg==\""]).unwrap();
+        assert_eq!(config.audio, None);
+        assert_eq!(config.audio_inline(), Some("UklGRg=="));
+
+        let config = merged(&[
+            "[buttons.BTN_LEFT]\naudio = \"a.wav\"",
+            "[buttons.BTN_LEFT]\npress = \"b.wav\"",
+        ])
+        .unwrap();
+        let (_, button) = config.buttons().unwrap().next().unwrap();
+        assert_eq!(button.audio_path(), Some(Path::new("b.wav")));
+
+        // Both alternatives in the same file are still rejected.
+        assert!(merged(&["volume = 0.5", "volume = 0.2\nvolume_db = -6.0"]).is_err());
+    }
+
+    #[test]
+    fn merge_alternatives_are_per_table() {
+        let mut base: toml::Table = toml::from_str(
+            "audio = \"a.wav\"\n[buttons.BTN_LEFT]\naudio = \"b.wav\"\n\
+             [devices.volume]\naudio = \"c.wav\"",
+        )
+        .unwrap();
+        merge(
+            &mut base,
+            toml::from_str(
+                "audio_inline = \"UklGRg==\"\n[buttons.BTN_RIGHT]\npress = \"d.wav\"\n\
+                 [devices.volume_db]\naudio = \"e.wav\"",
+            )
+            .unwrap(),
+        );
+        // Only the top-level `audio` is replaced. Button tables don't have `audio_inline`, and
+        // device names are not settings.
+        let expected: toml::Table = toml::from_str(
+            "audio_inline = \"UklGRg==\"\n[buttons.BTN_LEFT]\naudio = \"b.wav\"\n\
+             [buttons.BTN_RIGHT]\npress = \"d.wav\"\n\
+             [devices.volume]\naudio = \"c.wav\"\n[devices.volume_db]\naudio = \"e.wav\"",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+
+        // `press` in a button doesn't replace the top-level `audio`.
+        let config =
+            merged(&["audio = \"a.wav\"", "[buttons.BTN_LEFT]\npress = \"b.wav\""]).unwrap();
+        assert_eq!(config.audio, Some(PathBuf::from("a.wav")));
+    }
+
+    #[test]
+    fn volume_db() {
+        let config: Config = toml::from_str("volume_db = -6.0").unwrap();
+        config.validate().unwrap();
+        assert!(
+            (config.volume() - 0.501).abs() < 0.001,
+            "{}",
+            config.volume()
+        );
+        let config: Config = toml::from_str("volume_db = 0.0").unwrap();
+        assert_eq!(config.volume(), 1.0);
+        let config: Config = toml::from_str("volume_db = 20.0").unwrap();
+        assert!((config.volume() - 10.0).abs() < 1e-4, "{}", config.volume());
+        let config: Config = toml::from_str("volume = 0.3").unwrap();
+        assert_eq!(config.volume(), 0.3);
+    }
+
+    #[test]
+    fn volume_and_volume_db_are_exclusive() {
+        let config: Config = toml::from_str("volume = 0.5\nvolume_db = -6.0").unwrap();
+        let e = config.validate().unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("only one of `volume` and `volume_db`"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn watchdog_interval() {
+        let config: Config = toml::from_str("watchdog_ms = 10000").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.watchdog(), Some(Duration::from_secs(10)));
+        assert!(config.watchdog_reopen());
+        let config: Config = toml::from_str("watchdog_ms = 0").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.watchdog(), None);
+        assert_eq!(Config::default().watchdog(), None);
+        let config: Config = toml::from_str("watchdog_ms = 50").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn touch_can_be_bound_explicitly() {
+        let keys = button_keys("buttons = [\"BTN_LEFT\", \"BTN_TOUCH\"]");
+        assert_eq!(keys, [evdev::Key::BTN_LEFT, evdev::Key::BTN_TOUCH]);
     }
 }
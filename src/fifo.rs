@@ -0,0 +1,113 @@
+//! Plays sounds when their names are written to a named pipe, for scripts that want to click.
+//!
+//! Every line written to the FIFO plays a sound: an empty line plays the default sound, and a key
+//! name (like `BTN_LEFT` or `KEY_ENTER`) plays the sound that pressing that key would play.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::Path,
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::stat::Mode,
+    unistd::mkfifo,
+};
+
+use crate::{
+    config,
+    log::{info, warn},
+    Control,
+};
+
+/// How often to check whether the FIFO was deleted or replaced while nothing is written to it.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before trying again after the FIFO couldn't be opened.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Spawns a thread that creates the FIFO at `path` if it doesn't exist, and sends
+/// `Control::PlayTest` or `Control::PlayKey` to `control_sender` for every line written to it.
+///
+/// If the FIFO is deleted or replaced, the one at `path` is opened (or created) again.
+pub fn spawn(path: &Path, control_sender: Sender<Control>) {
+    let path = path.to_path_buf();
+    thread::spawn(move || loop {
+        if let Err(e) = run(&path, &control_sender) {
+            warn!("failed to read trigger FIFO '{}': {e:#}", path.display());
+            thread::sleep(RETRY_DELAY);
+        }
+    });
+}
+
+/// Opens the FIFO at `path`, and handles the lines written to it until it is deleted or replaced.
+fn run(path: &Path, control_sender: &Sender<Control>) -> anyhow::Result<()> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_fifo() => {}
+        Ok(_) => bail!("it exists, but isn't a FIFO"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+            info!("created trigger FIFO '{}'", path.display());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    // Opening it for writing too keeps it from reaching end-of-file whenever a writer closes it,
+    // and makes opening it not block until the first writer appears.
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let meta = file.metadata()?;
+    let mut pending = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, RECHECK_INTERVAL.as_millis() as i32) {
+            Ok(0) => {
+                if !is_same_file(path, &meta) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        let n = file.read(&mut buf)?;
+        pending.extend_from_slice(&buf[..n]);
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line = pending.drain(..=end).collect::<Vec<_>>();
+            let name = String::from_utf8_lossy(&line);
+            let name = name.trim();
+            let control = if name.is_empty() {
+                Control::PlayTest
+            } else {
+                match config::parse_key(name) {
+                    Some(key) => Control::PlayKey(key),
+                    None => {
+                        warn!("unknown key '{name}' written to '{}'", path.display());
+                        continue;
+                    }
+                }
+            };
+            control_sender.send(control).ok();
+        }
+    }
+}
+
+/// Returns whether `path` still refers to the file with the metadata `meta`.
+fn is_same_file(path: &Path, meta: &fs::Metadata) -> bool {
+    match fs::metadata(path) {
+        Ok(now) => now.dev() == meta.dev() && now.ino() == meta.ino(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,821 @@
+//! A decoder for FLAC files.
+//!
+//! A FLAC file starts with `fLaC` and a few metadata blocks, the first of which (`STREAMINFO`)
+//! describes the format. It is followed by frames, which each hold a block of samples for every
+//! channel. Each channel's samples are stored as a "subframe", either verbatim or as the residual
+//! of a linear predictor, which is Rice coded.
+
+use anyhow::{bail, Context};
+
+use crate::sound::Sound;
+
+const TRUNCATED: &str = "FLAC file is truncated";
+
+/// The metadata block type of `STREAMINFO`.
+const STREAMINFO: u8 = 0;
+
+/// Decodes a FLAC file.
+pub fn decode(data: &[u8]) -> anyhow::Result<Sound> {
+    let Some(mut rest) = data.strip_prefix(b"fLaC") else {
+        bail!("not a FLAC file");
+    };
+
+    let mut info = None;
+    loop {
+        let [header, a, b, c, ..] = *rest else {
+            bail!(TRUNCATED);
+        };
+        let len = u32::from_be_bytes([0, a, b, c]) as usize;
+        let body = rest.get(4..4 + len).context(TRUNCATED)?;
+        if header & 0x7F == STREAMINFO {
+            info = Some(StreamInfo::parse(body)?);
+        }
+        rest = &rest[4 + len..];
+        // The top bit marks the last metadata block.
+        if header & 0x80 != 0 {
+            break;
+        }
+    }
+    let info = info.context("FLAC file has no `STREAMINFO` block")?;
+
+    let mut channels = vec![Vec::new(); usize::from(info.channels)];
+    let mut reader = BitReader::new(rest);
+    // Anything after the last frame (like an ID3v1 tag) doesn't start with a frame sync code.
+    while reader.peek_sync() {
+        decode_frame(&mut reader, &info, &mut channels)?;
+    }
+
+    let mut frames = channels[0].len();
+    if info.total_samples != 0 {
+        frames = frames.min(info.total_samples as usize);
+    }
+    let max = 2f32.powi(info.bits_per_sample as i32 - 1);
+    let mut samples = Vec::with_capacity(frames * channels.len());
+    for i in 0..frames {
+        samples.extend(channels.iter().map(|channel| channel[i] as f32 / max));
+    }
+
+    Ok(Sound {
+        channels: info.channels,
+        sample_rate: info.sample_rate,
+        samples,
+    })
+}
+
+/// The contents of the `STREAMINFO` metadata block that are needed for decoding.
+struct StreamInfo {
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: u32,
+    /// The number of frames (samples per channel) in the file, or 0 if unknown.
+    total_samples: u64,
+}
+
+impl StreamInfo {
+    fn parse(body: &[u8]) -> anyhow::Result<Self> {
+        if body.len() < 18 {
+            bail!("FLAC `STREAMINFO` block is too short");
+        }
+        // Skip the block and frame size limits.
+        let mut reader = BitReader::new(&body[10..]);
+        let sample_rate = reader.bits(20)?;
+        let channels = reader.bits(3)? as u16 + 1;
+        let bits_per_sample = reader.bits(5)? + 1;
+        let total_samples = u64::from(reader.bits(4)?) << 32 | u64::from(reader.bits(32)?);
+        if sample_rate == 0 {
+            bail!("FLAC file has an invalid sample rate of 0");
+        }
+        if bits_per_sample < 4 {
+            bail!("{bits_per_sample}-bit FLAC files are not supported");
+        }
+        Ok(Self {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            total_samples,
+        })
+    }
+}
+
+/// How the channels of a frame are stored.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ChannelAssignment {
+    /// Every channel is stored as is.
+    Independent,
+    /// The left channel, and the difference between the left and right channels.
+    LeftSide,
+    /// The difference between the left and right channels, and the right channel.
+    SideRight,
+    /// The average of the left and right channels, and their difference.
+    MidSide,
+}
+
+/// Decodes the frame at the reader's position, appending its samples to `channels`.
+fn decode_frame(
+    reader: &mut BitReader,
+    info: &StreamInfo,
+    channels: &mut [Vec<i64>],
+) -> anyhow::Result<()> {
+    let start = reader.byte_pos();
+
+    // The sync code, a reserved bit, and the blocking strategy, which were checked already.
+    reader.bits(16)?;
+    let block_size_code = reader.bits(4)?;
+    let sample_rate_code = reader.bits(4)?;
+    let channel_code = reader.bits(4)?;
+    let sample_size_code = reader.bits(3)?;
+    reader.bits(1)?;
+
+    // The frame or sample number, coded like UTF-8, which isn't needed for decoding everything.
+    let first = reader.bits(8)?;
+    let extra = match (first as u8).leading_ones() {
+        0 => 0,
+        n @ 2..=7 => n - 1,
+        _ => bail!("FLAC frame has an invalid frame number"),
+    };
+    for _ in 0..extra {
+        if reader.bits(8)? & 0xC0 != 0x80 {
+            bail!("FLAC frame has an invalid frame number");
+        }
+    }
+
+    let block_size = match block_size_code {
+        0 => bail!("FLAC frame has a reserved block size"),
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.bits(8)? as usize + 1,
+        7 => reader.bits(16)? as usize + 1,
+        _ => 256 << (block_size_code - 8),
+    };
+    match sample_rate_code {
+        12 => {
+            reader.bits(8)?;
+        }
+        13 | 14 => {
+            reader.bits(16)?;
+        }
+        15 => bail!("FLAC frame has an invalid sample rate"),
+        // The sample rate of the stream is used for all frames.
+        _ => {}
+    }
+    let bits_per_sample = match sample_size_code {
+        0 => info.bits_per_sample,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => bail!("FLAC frame has a reserved sample size"),
+    };
+    if bits_per_sample != info.bits_per_sample {
+        bail!("FLAC files with a varying sample size are not supported");
+    }
+    let (assignment, frame_channels) = match channel_code {
+        0..=7 => (ChannelAssignment::Independent, channel_code as usize + 1),
+        8 => (ChannelAssignment::LeftSide, 2),
+        9 => (ChannelAssignment::SideRight, 2),
+        10 => (ChannelAssignment::MidSide, 2),
+        _ => bail!("FLAC frame has a reserved channel assignment"),
+    };
+    if frame_channels != channels.len() {
+        bail!("FLAC files with a varying number of channels are not supported");
+    }
+
+    let header_crc = reader.bits(8)? as u8;
+    if crc8(&reader.data[start..reader.byte_pos() - 1]) != header_crc {
+        bail!("FLAC frame header has a bad checksum");
+    }
+
+    let mut block = Vec::with_capacity(frame_channels);
+    for channel in 0..frame_channels {
+        // The side channel needs an extra bit for the difference.
+        let is_side = matches!(
+            (assignment, channel),
+            (ChannelAssignment::LeftSide | ChannelAssignment::MidSide, 1)
+                | (ChannelAssignment::SideRight, 0)
+        );
+        let bits = bits_per_sample + u32::from(is_side);
+        block.push(decode_subframe(reader, block_size, bits)?);
+    }
+
+    reader.align();
+    let frame_crc = reader.bits(16)? as u16;
+    if crc16(&reader.data[start..reader.byte_pos() - 2]) != frame_crc {
+        bail!("FLAC frame has a bad checksum");
+    }
+
+    if let [a, b] = &mut block[..] {
+        for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+            (*a, *b) = match assignment {
+                ChannelAssignment::Independent => (*a, *b),
+                ChannelAssignment::LeftSide => (*a, *a - *b),
+                ChannelAssignment::SideRight => (*a + *b, *b),
+                ChannelAssignment::MidSide => {
+                    // The lowest bit of the sum was dropped from the mid channel, but it's the
+                    // same as that of the difference.
+                    let mid = *a << 1 | (*b & 1);
+                    ((mid + *b) >> 1, (mid - *b) >> 1)
+                }
+            };
+        }
+    }
+    for (channel, samples) in channels.iter_mut().zip(block) {
+        channel.extend(samples);
+    }
+    Ok(())
+}
+
+/// Decodes the `block_size` samples of one channel, which have `bits` bits each.
+fn decode_subframe(
+    reader: &mut BitReader,
+    block_size: usize,
+    bits: u32,
+) -> anyhow::Result<Vec<i64>> {
+    if reader.bits(1)? != 0 {
+        bail!("FLAC subframe has an invalid header");
+    }
+    let kind = reader.bits(6)?;
+    // Samples whose lowest bits are all 0 are stored without them.
+    let wasted = if reader.bits(1)? == 1 {
+        reader.unary()? + 1
+    } else {
+        0
+    };
+    if wasted >= bits {
+        bail!("FLAC subframe has more wasted bits than bits per sample");
+    }
+    let bits = bits - wasted;
+
+    let mut samples = match kind {
+        0b000000 => vec![reader.signed(bits)?; block_size],
+        0b000001 => (0..block_size)
+            .map(|_| reader.signed(bits))
+            .collect::<anyhow::Result<_>>()?,
+        0b001000..=0b001100 => {
+            let order = (kind & 0b111) as usize;
+            let mut samples = warm_up(reader, block_size, order, bits)?;
+            decode_residual(reader, block_size, order, &mut samples)?;
+            for i in order..block_size {
+                let s = &samples[i - order..i];
+                samples[i] += match order {
+                    0 => 0,
+                    1 => s[0],
+                    2 => 2 * s[1] - s[0],
+                    3 => 3 * s[2] - 3 * s[1] + s[0],
+                    _ => 4 * s[3] - 6 * s[2] + 4 * s[1] - s[0],
+                };
+            }
+            samples
+        }
+        0b100000.. => {
+            let order = (kind & 0b11111) as usize + 1;
+            let mut samples = warm_up(reader, block_size, order, bits)?;
+            let precision = reader.bits(4)? + 1;
+            if precision == 16 {
+                bail!("FLAC subframe has an invalid coefficient precision");
+            }
+            let shift = reader.signed(5)?;
+            if shift < 0 {
+                bail!("FLAC subframe has a negative prediction shift");
+            }
+            let coefficients = (0..order)
+                .map(|_| reader.signed(precision))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            decode_residual(reader, block_size, order, &mut samples)?;
+            for i in order..block_size {
+                // The first coefficient applies to the most recent sample.
+                let prediction: i64 = coefficients
+                    .iter()
+                    .zip(samples[i - order..i].iter().rev())
+                    .map(|(c, s)| c * s)
+                    .sum();
+                samples[i] += prediction >> shift;
+            }
+            samples
+        }
+        _ => bail!("FLAC subframe has a reserved type"),
+    };
+
+    if wasted > 0 {
+        for sample in &mut samples {
+            *sample <<= wasted;
+        }
+    }
+    Ok(samples)
+}
+
+/// Reads the `order` unpredicted samples that start a predicted subframe, and returns a block of
+/// samples that starts with them.
+fn warm_up(
+    reader: &mut BitReader,
+    block_size: usize,
+    order: usize,
+    bits: u32,
+) -> anyhow::Result<Vec<i64>> {
+    if order > block_size {
+        bail!("FLAC subframe has a predictor order larger than its block");
+    }
+    let mut samples = vec![0; block_size];
+    for sample in &mut samples[..order] {
+        *sample = reader.signed(bits)?;
+    }
+    Ok(samples)
+}
+
+/// Reads the Rice coded residual of a predicted subframe into `samples[order..]`.
+fn decode_residual(
+    reader: &mut BitReader,
+    block_size: usize,
+    order: usize,
+    samples: &mut [i64],
+) -> anyhow::Result<()> {
+    let param_bits = match reader.bits(2)? {
+        0 => 4,
+        1 => 5,
+        _ => bail!("FLAC subframe has a reserved residual coding method"),
+    };
+    let escape = (1 << param_bits) - 1;
+    let partition_order = reader.bits(4)?;
+    let partitions = 1 << partition_order;
+    let partition_len = block_size >> partition_order;
+    if partition_len << partition_order != block_size || partition_len < order {
+        bail!("FLAC subframe has an invalid residual partition order");
+    }
+
+    let mut i = order;
+    for partition in 0..partitions {
+        let end = (partition + 1) * partition_len;
+        let param = reader.bits(param_bits)?;
+        if param == escape {
+            let bits = reader.bits(5)?;
+            for sample in &mut samples[i..end] {
+                *sample = reader.signed(bits)?;
+            }
+        } else {
+            for sample in &mut samples[i..end] {
+                let value = u64::from(reader.unary()?) << param | u64::from(reader.bits(param)?);
+                // The residual is zigzag encoded, so that small magnitudes are small numbers.
+                *sample = (value >> 1) as i64 ^ -((value & 1) as i64);
+            }
+        }
+        i = end;
+    }
+    Ok(())
+}
+
+/// Reads big-endian bit fields from a byte slice.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// The position of the next bit to read, counted from the start of `data`.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Returns the position of the byte containing the next bit.
+    fn byte_pos(&self) -> usize {
+        self.pos / 8
+    }
+
+    /// Returns whether a frame sync code starts at the next byte.
+    fn peek_sync(&self) -> bool {
+        match self.data.get(self.byte_pos()..) {
+            Some([0xFF, b, ..]) => b & 0xFE == 0xF8,
+            _ => false,
+        }
+    }
+
+    fn bit(&mut self) -> anyhow::Result<u32> {
+        let byte = *self.data.get(self.pos / 8).context(TRUNCATED)?;
+        let bit = byte >> (7 - self.pos % 8) & 1;
+        self.pos += 1;
+        Ok(u32::from(bit))
+    }
+
+    /// Reads an unsigned number of up to 32 bits.
+    fn bits(&mut self, count: u32) -> anyhow::Result<u32> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = value << 1 | u64::from(self.bit()?);
+        }
+        Ok(value as u32)
+    }
+
+    /// Reads a two's complement number of up to 33 bits.
+    fn signed(&mut self, count: u32) -> anyhow::Result<i64> {
+        let mut value = 0i64;
+        for _ in 0..count {
+            value = value << 1 | i64::from(self.bit()?);
+        }
+        if count > 0 && value >> (count - 1) == 1 {
+            value -= 1 << count;
+        }
+        Ok(value)
+    }
+
+    /// Reads a unary number, as the count of 0 bits before the next 1 bit.
+    fn unary(&mut self) -> anyhow::Result<u32> {
+        let mut count = 0;
+        while self.bit()? == 0 {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Skips to the start of the next byte.
+    fn align(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+}
+
+/// The CRC-8 that protects frame headers, with the polynomial `x^8 + x^2 + x + 1`.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The CRC-16 that protects whole frames, with the polynomial `x^16 + x^15 + x^2 + 1`.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes big-endian bit fields, for building test files.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn push(&mut self, value: i64, count: u32) {
+            for i in (0..count).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                let bit = (value >> i & 1) as u8;
+                *self.data.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn unary(&mut self, count: u64) {
+            for _ in 0..count {
+                self.push(0, 1);
+            }
+            self.push(1, 1);
+        }
+
+        fn align(&mut self) {
+            self.bits = self.bits.next_multiple_of(8);
+        }
+    }
+
+    /// A subframe to encode.
+    enum Subframe<'a> {
+        Constant(i64),
+        Verbatim(&'a [i64]),
+        /// A fixed predictor subframe of the given order, with a single Rice partition.
+        Fixed(u32, &'a [i64]),
+        /// An LPC subframe with the given coefficients (of 8-bit precision) and shift.
+        Lpc(&'a [i64], i64, &'a [i64]),
+    }
+
+    fn rice(w: &mut BitWriter, residual: &[i64]) {
+        const PARAM: u32 = 3;
+        // Coding method 0, partition order 0.
+        w.push(0, 2);
+        w.push(0, 4);
+        w.push(PARAM.into(), 4);
+        for &r in residual {
+            let zigzag = ((r << 1) ^ (r >> 63)) as u64;
+            w.unary(zigzag >> PARAM);
+            w.push((zigzag & 0b111) as i64, PARAM);
+        }
+    }
+
+    fn subframe(w: &mut BitWriter, subframe: &Subframe, bits: u32, wasted: u32) {
+        let kind = match subframe {
+            Subframe::Constant(_) => 0,
+            Subframe::Verbatim(_) => 1,
+            Subframe::Fixed(order, _) => 0b001000 | i64::from(*order),
+            Subframe::Lpc(coefs, ..) => 0b100000 | (coefs.len() as i64 - 1),
+        };
+        w.push(0, 1);
+        w.push(kind, 6);
+        if wasted > 0 {
+            w.push(1, 1);
+            w.unary(u64::from(wasted) - 1);
+        } else {
+            w.push(0, 1);
+        }
+        let bits = bits - wasted;
+        let stored = |samples: &[i64]| samples.iter().map(|s| s >> wasted).collect::<Vec<_>>();
+        match subframe {
+            Subframe::Constant(value) => w.push(value >> wasted, bits),
+            Subframe::Verbatim(samples) => {
+                for s in stored(samples) {
+                    w.push(s, bits);
+                }
+            }
+            Subframe::Fixed(order, samples) => {
+                let s = stored(samples);
+                let order = *order as usize;
+                for &warm in &s[..order] {
+                    w.push(warm, bits);
+                }
+                let residual = (order..s.len())
+                    .map(|i| {
+                        s[i] - match order {
+                            0 => 0,
+                            1 => s[i - 1],
+                            2 => 2 * s[i - 1] - s[i - 2],
+                            3 => 3 * s[i - 1] - 3 * s[i - 2] + s[i - 3],
+                            _ => 4 * s[i - 1] - 6 * s[i - 2] + 4 * s[i - 3] - s[i - 4],
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                rice(w, &residual);
+            }
+            Subframe::Lpc(coefs, shift, samples) => {
+                let s = stored(samples);
+                let order = coefs.len();
+                for &warm in &s[..order] {
+                    w.push(warm, bits);
+                }
+                w.push(8 - 1, 4);
+                w.push(*shift, 5);
+                for &c in *coefs {
+                    w.push(c, 8);
+                }
+                let residual = (order..s.len())
+                    .map(|i| {
+                        let prediction: i64 = (0..order).map(|j| coefs[j] * s[i - 1 - j]).sum();
+                        s[i] - (prediction >> shift)
+                    })
+                    .collect::<Vec<_>>();
+                rice(w, &residual);
+            }
+        }
+    }
+
+    /// Encodes a frame of `block_size` samples per channel, with subframes of `bits` bits each
+    /// (plus one for side channels, which must be marked by `side`).
+    fn frame(
+        out: &mut Vec<u8>,
+        channel_code: i64,
+        block_size: usize,
+        bits: u32,
+        subframes: &[(Subframe, bool, u32)],
+    ) {
+        let mut w = BitWriter::default();
+        w.push(0xFFF8, 16);
+        // A 16-bit block size at the end of the header, and everything else from `STREAMINFO`.
+        w.push(7, 4);
+        w.push(0, 4);
+        w.push(channel_code, 4);
+        w.push(0, 3);
+        w.push(0, 1);
+        w.push(0, 8);
+        w.push(block_size as i64 - 1, 16);
+        let crc = crc8(&w.data);
+        w.push(crc.into(), 8);
+        for (sub, side, wasted) in subframes {
+            subframe(&mut w, sub, bits + u32::from(*side), *wasted);
+        }
+        w.align();
+        let crc = crc16(&w.data);
+        w.push(crc.into(), 16);
+        out.extend(w.data);
+    }
+
+    fn stream(channels: u16, bits: u32, total_samples: u64) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.data.extend(b"fLaC");
+        w.bits = 32;
+        // The last metadata block, of type `STREAMINFO`.
+        w.push(0x80, 8);
+        w.push(34, 24);
+        // The block and frame size limits aren't used by the decoder.
+        w.push(0, 16 + 16);
+        w.push(0, 24 + 24);
+        w.push(44100, 20);
+        w.push(i64::from(channels) - 1, 3);
+        w.push(i64::from(bits) - 1, 5);
+        w.push(total_samples as i64, 36);
+        w.push(0, 64);
+        w.push(0, 64);
+        w.data
+    }
+
+    #[test]
+    fn normalizes_24_bit_samples() {
+        let samples = [0x7F_FFFF, -0x80_0000, 0x40_0000, 0, -1];
+        let mut data = stream(1, 24, 0);
+        frame(
+            &mut data,
+            0,
+            5,
+            24,
+            &[(Subframe::Verbatim(&samples), false, 0)],
+        );
+        let sound = decode(&data).unwrap();
+        assert_eq!(sound.channels, 1);
+        assert_eq!(sound.sample_rate, 44100);
+        let step = 2f32.powi(-23);
+        assert_eq!(sound.samples, [1.0 - step, -1.0, 0.5, 0.0, -step]);
+    }
+
+    #[test]
+    fn normalizes_16_bit_samples() {
+        let samples = [i64::from(i16::MAX), i64::from(i16::MIN), 0x4000];
+        let mut data = stream(1, 16, 0);
+        frame(
+            &mut data,
+            0,
+            3,
+            16,
+            &[(Subframe::Verbatim(&samples), false, 0)],
+        );
+        let sound = decode(&data).unwrap();
+        assert_eq!(sound.samples, [1.0 - 2f32.powi(-15), -1.0, 0.5]);
+    }
+
+    #[test]
+    fn decodes_predicted_subframes() {
+        let samples = (0..32)
+            .map(|i| ((i as f64 * 0.4).sin() * 20000.0) as i64)
+            .collect::<Vec<_>>();
+        for sub in [
+            Subframe::Fixed(0, &samples),
+            Subframe::Fixed(1, &samples),
+            Subframe::Fixed(2, &samples),
+            Subframe::Fixed(3, &samples),
+            Subframe::Fixed(4, &samples),
+            Subframe::Lpc(&[115, -60], 6, &samples),
+        ] {
+            let mut data = stream(1, 16, 0);
+            frame(&mut data, 0, samples.len(), 16, &[(sub, false, 0)]);
+            let sound = decode(&data).unwrap();
+            let decoded = sound
+                .samples
+                .iter()
+                .map(|s| (s * 32768.0) as i64)
+                .collect::<Vec<_>>();
+            assert_eq!(decoded, samples);
+        }
+    }
+
+    #[test]
+    fn decodes_constant_subframes_and_wasted_bits() {
+        let samples = [0x100, -0x300, 0x7F00];
+        let mut data = stream(2, 16, 0);
+        frame(
+            &mut data,
+            1,
+            3,
+            16,
+            &[
+                (Subframe::Constant(-0x4000), false, 0),
+                (Subframe::Verbatim(&samples), false, 8),
+            ],
+        );
+        let sound = decode(&data).unwrap();
+        let decoded = sound
+            .samples
+            .iter()
+            .map(|s| (s * 32768.0) as i64)
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, [-0x4000, 0x100, -0x4000, -0x300, -0x4000, 0x7F00]);
+    }
+
+    #[test]
+    fn decodes_stereo_decorrelation() {
+        let left = [1000, -32768, 32767, 5];
+        let right = [-1000, 32767, -32768, 6];
+        let side = left
+            .iter()
+            .zip(&right)
+            .map(|(l, r)| l - r)
+            .collect::<Vec<_>>();
+        let mid = left
+            .iter()
+            .zip(&right)
+            .map(|(l, r)| (l + r) >> 1)
+            .collect::<Vec<_>>();
+        let interleaved = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(l, r)| [*l, *r])
+            .collect::<Vec<_>>();
+        let cases = [
+            (
+                8,
+                [
+                    (Subframe::Verbatim(&left), false, 0),
+                    (Subframe::Verbatim(&side), true, 0),
+                ],
+            ),
+            (
+                9,
+                [
+                    (Subframe::Verbatim(&side), true, 0),
+                    (Subframe::Verbatim(&right), false, 0),
+                ],
+            ),
+            (
+                10,
+                [
+                    (Subframe::Verbatim(&mid), false, 0),
+                    (Subframe::Verbatim(&side), true, 0),
+                ],
+            ),
+        ];
+        for (code, subframes) in cases {
+            let mut data = stream(2, 16, 0);
+            frame(&mut data, code, left.len(), 16, &subframes);
+            let sound = decode(&data).unwrap();
+            let decoded = sound
+                .samples
+                .iter()
+                .map(|s| (s * 32768.0) as i64)
+                .collect::<Vec<_>>();
+            assert_eq!(decoded, interleaved, "channel assignment {code}");
+        }
+    }
+
+    #[test]
+    fn decodes_multiple_frames_up_to_total_samples() {
+        let mut data = stream(1, 8, 5);
+        frame(
+            &mut data,
+            0,
+            3,
+            8,
+            &[(Subframe::Verbatim(&[1, 2, 3]), false, 0)],
+        );
+        frame(
+            &mut data,
+            0,
+            3,
+            8,
+            &[(Subframe::Verbatim(&[4, 5, 6]), false, 0)],
+        );
+        // Trailing data that isn't a frame is ignored.
+        data.extend(b"TAG");
+        let sound = decode(&data).unwrap();
+        let decoded = sound
+            .samples
+            .iter()
+            .map(|s| (s * 128.0) as i64)
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn rejects_corrupted_frames() {
+        let mut data = stream(1, 16, 0);
+        frame(
+            &mut data,
+            0,
+            2,
+            16,
+            &[(Subframe::Verbatim(&[1, 2]), false, 0)],
+        );
+        let last = data.len() - 3;
+        data[last] ^= 1;
+        let error = decode(&data).err().unwrap();
+        assert_eq!(error.to_string(), "FLAC frame has a bad checksum");
+
+        data.truncate(data.len() - 4);
+        assert!(decode(&data).is_err());
+    }
+}
@@ -0,0 +1,1736 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use evdev::{Device, EventType, InputEvent, InputEventKind, Key, RelativeAxisType};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::epoll::{
+        epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+    },
+    unistd,
+};
+
+use crate::{
+    config::{self, Config},
+    log::{debug, error, info, warn},
+    mixer::{Activity, Play, Volume},
+    profile,
+    random::Rng,
+    sound::SoundSet,
+    Control,
+};
+
+/// How much the volume hotkeys change the volume per press.
+const VOLUME_STEP: f32 = 0.1;
+
+/// The highest volume that can be reached with the volume hotkeys.
+const MAX_VOLUME: f32 = 2.0;
+
+/// The kind of button event that caused a sound to be played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    Press,
+    Release,
+    /// The button has been held down for [`Bindings::hold`].
+    Hold,
+    /// The button is being held down. This sound loops until the button is released.
+    Loop,
+}
+
+/// The sounds bound to a button.
+pub struct ButtonBinding {
+    /// The sounds to play for each trigger. There's always a press sound; buttons without a
+    /// release sound of their own use [`Bindings::release`].
+    pub sounds: HashMap<Trigger, SoundSet>,
+    /// Keys that must be held down (on any open device) for the button to play a sound.
+    pub modifiers: Vec<Key>,
+    /// Overrides [`Bindings::cooldown`] for this button.
+    pub cooldown: Option<Duration>,
+    /// The stereo position of the button's sounds, from -1.0 (left) to 1.0 (right). This is only
+    /// applied when they are converted to the output format.
+    pub pan: f32,
+    /// The volume of [`Bindings::release`] when it's played for this button, which is the
+    /// button's `volume` times its `release_volume`. Like `pan`, this is only applied when the
+    /// sounds are converted to the output format.
+    pub release_volume: f32,
+}
+
+/// Maps button and scroll wheel events to the sounds they play.
+pub struct Bindings {
+    pub buttons: HashMap<Key, ButtonBinding>,
+    /// Button bindings for devices with their own, by device name. These replace `buttons` for
+    /// that device.
+    pub device_buttons: HashMap<String, HashMap<Key, ButtonBinding>>,
+    pub release: Option<SoundSet>,
+    /// Sound played for keyboard keys that aren't in `buttons` (see [`is_keyboard_key`]).
+    pub default_key: Option<SoundSet>,
+    /// Sound played instead of the press sound for the second press of a double click.
+    pub double: Option<SoundSet>,
+    /// The longest time between two presses that counts as a double click.
+    pub double_click: Duration,
+    /// Sound played instead of the press sound for the first press after `idle_threshold`.
+    pub first: Option<SoundSet>,
+    /// How long no button has to be pressed for the next press to play the `first` sound.
+    pub idle_threshold: Duration,
+    /// How long a button has to be held down to play its hold sound.
+    pub hold: Duration,
+    /// Whether press sounds are played on press, on release, or both.
+    pub trigger: config::Trigger,
+    /// Sounds played for each notch scrolled on a scroll wheel axis.
+    pub scroll: Vec<(RelativeAxisType, SoundSet)>,
+    /// Keys that adjust the volume instead of playing a sound.
+    pub volume_up: Option<Key>,
+    pub volume_down: Option<Key>,
+    /// How long the end of each sound is faded out for.
+    pub fade: Duration,
+    /// How sounds are converted to the output sample rate.
+    pub resample_quality: config::ResampleQuality,
+    /// Events that happen within this duration of the last accepted one for the same button are
+    /// ignored.
+    pub cooldown: Duration,
+    /// The maximum relative deviation of the playback speed from 1.0.
+    pub pitch_variation: f32,
+    /// Whether key repeat events play the press sound again.
+    pub repeat: bool,
+    /// Whether no sounds are played while the session is locked.
+    pub mute_when_locked: bool,
+    /// How long a device may go without a heartbeat before the watchdog complains, if at all.
+    pub watchdog: Option<Duration>,
+    /// Whether the watchdog reopens devices that stopped responding.
+    pub watchdog_reopen: bool,
+}
+
+impl Bindings {
+    /// Returns the button bindings for the device called `device`.
+    fn buttons(&self, device: &str) -> &HashMap<Key, ButtonBinding> {
+        self.device_buttons.get(device).unwrap_or(&self.buttons)
+    }
+
+    /// Returns the sound to play when `trigger` happens on `key` of `device`, if any.
+    ///
+    /// `held` tells whether a key is currently held down, which is checked for the button's
+    /// modifiers.
+    pub fn sound(
+        &self,
+        device: &str,
+        key: Key,
+        trigger: Trigger,
+        held: impl Fn(Key) -> bool,
+    ) -> Option<&SoundSet> {
+        let Some(button) = self.buttons(device).get(&key) else {
+            let default_key = self.default_key.as_ref().filter(|_| is_keyboard_key(key))?;
+            return self.triggered(trigger, Some(default_key), None);
+        };
+        if !button.modifiers.iter().all(|modifier| held(*modifier)) {
+            return None;
+        }
+        match trigger {
+            Trigger::Hold | Trigger::Loop => button.sounds.get(&trigger),
+            _ => self.triggered(
+                trigger,
+                button.sounds.get(&Trigger::Press),
+                button.sounds.get(&Trigger::Release),
+            ),
+        }
+    }
+
+    /// Picks between a `press` and `release` sound for a press or release, according to the
+    /// `trigger` setting.
+    ///
+    /// Release sounds fall back to the global release sound, and then to `press` if it should
+    /// also be played on release.
+    fn triggered<'a>(
+        &'a self,
+        trigger: Trigger,
+        press: Option<&'a SoundSet>,
+        release: Option<&'a SoundSet>,
+    ) -> Option<&'a SoundSet> {
+        match (trigger, self.trigger) {
+            (Trigger::Press, config::Trigger::Release) => None,
+            (Trigger::Press, _) => press,
+            (Trigger::Release, config::Trigger::Press) => release.or(self.release.as_ref()),
+            (Trigger::Release, _) => release.or(self.release.as_ref()).or(press),
+            (Trigger::Hold | Trigger::Loop, _) => None,
+        }
+    }
+
+    /// Returns the cooldown of `key` on `device`.
+    pub fn cooldown(&self, device: &str, key: Key) -> Duration {
+        self.buttons(device)
+            .get(&key)
+            .and_then(|button| button.cooldown)
+            .unwrap_or(self.cooldown)
+    }
+
+    /// Returns whether `key` of `device` is bound to anything.
+    ///
+    /// Modifiers of any device's buttons count as bound, since they may be held down on a
+    /// different device (like a keyboard) than the button itself.
+    pub fn binds_key(&self, device: &str, key: Key) -> bool {
+        self.buttons(device).contains_key(&key)
+            || (self.default_key.is_some() && is_keyboard_key(key))
+            || self.volume_step(key).is_some()
+            || self.is_modifier(key)
+    }
+
+    /// Returns whether `key` is a modifier of any button.
+    fn is_modifier(&self, key: Key) -> bool {
+        self.device_buttons
+            .values()
+            .chain([&self.buttons])
+            .flat_map(|buttons| buttons.values())
+            .any(|button| button.modifiers.contains(&key))
+    }
+
+    /// Returns how much pressing `key` changes the volume, if it is a volume hotkey.
+    pub fn volume_step(&self, key: Key) -> Option<f32> {
+        if Some(key) == self.volume_up {
+            Some(VOLUME_STEP)
+        } else if Some(key) == self.volume_down {
+            Some(-VOLUME_STEP)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the sound to play for each notch scrolled on `axis`, if any.
+    pub fn scroll_sound(&self, axis: RelativeAxisType) -> Option<&SoundSet> {
+        self.scroll
+            .iter()
+            .find(|(bound, _)| *bound == axis)
+            .map(|(_, sounds)| sounds)
+    }
+
+    /// Converts all sounds to the given channel count and sample rate.
+    pub fn convert(&self, channels: u16, sample_rate: u32) -> Bindings {
+        let convert_with_fade = |sounds: &SoundSet, fade| {
+            sounds.map(|clip| clip.convert(channels, sample_rate, fade, self.resample_quality))
+        };
+        let convert = |sounds: &SoundSet| convert_with_fade(sounds, self.fade);
+        let convert_buttons = |buttons: &HashMap<Key, ButtonBinding>| {
+            buttons
+                .iter()
+                .map(|(key, button)| {
+                    let mut sounds = button.sounds.clone();
+                    // Panned or louder/quieter buttons need their own copy of the global release
+                    // sound.
+                    let own_release = button.pan != 0.0 || button.release_volume != 1.0;
+                    if let (Some(release), true) = (&self.release, own_release) {
+                        sounds.entry(Trigger::Release).or_insert_with(|| {
+                            release.map(|clip| clip.scale(button.release_volume))
+                        });
+                    }
+                    let pan = |sounds: SoundSet| {
+                        if button.pan == 0.0 {
+                            return sounds;
+                        }
+                        sounds.map(|clip| clip.pan(button.pan))
+                    };
+                    let button = ButtonBinding {
+                        sounds: sounds
+                            .iter()
+                            .map(|(trigger, sounds)| match trigger {
+                                // Fading out the end would make the loop dip at every repeat.
+                                Trigger::Loop => {
+                                    (*trigger, pan(convert_with_fade(sounds, Duration::ZERO)))
+                                }
+                                _ => (*trigger, pan(convert(sounds))),
+                            })
+                            .collect(),
+                        modifiers: button.modifiers.clone(),
+                        cooldown: button.cooldown,
+                        pan: button.pan,
+                        release_volume: button.release_volume,
+                    };
+                    (*key, button)
+                })
+                .collect()
+        };
+        let panned = self
+            .buttons
+            .values()
+            .chain(
+                self.device_buttons
+                    .values()
+                    .flat_map(|buttons| buttons.values()),
+            )
+            .any(|button| button.pan != 0.0);
+        if panned && channels < 2 {
+            warn!("`pan` has no effect, since the audio output is mono");
+        }
+        Bindings {
+            buttons: convert_buttons(&self.buttons),
+            device_buttons: self
+                .device_buttons
+                .iter()
+                .map(|(name, buttons)| (name.clone(), convert_buttons(buttons)))
+                .collect(),
+            release: self.release.as_ref().map(convert),
+            default_key: self.default_key.as_ref().map(convert),
+            double: self.double.as_ref().map(convert),
+            double_click: self.double_click,
+            first: self.first.as_ref().map(convert),
+            idle_threshold: self.idle_threshold,
+            hold: self.hold,
+            trigger: self.trigger,
+            scroll: self
+                .scroll
+                .iter()
+                .map(|(axis, sounds)| (*axis, convert(sounds)))
+                .collect(),
+            volume_up: self.volume_up,
+            volume_down: self.volume_down,
+            fade: self.fade,
+            resample_quality: self.resample_quality,
+            cooldown: self.cooldown,
+            pitch_variation: self.pitch_variation,
+            repeat: self.repeat,
+            mute_when_locked: self.mute_when_locked,
+            watchdog: self.watchdog,
+            watchdog_reopen: self.watchdog_reopen,
+        }
+    }
+}
+
+/// The set of input devices that are being listened to.
+///
+/// All devices are read from a single thread, which waits for events on any of them with epoll.
+pub struct Inputs {
+    shared: Arc<Shared>,
+}
+
+/// State shared between [`Inputs`] and the event loop thread.
+struct Shared {
+    /// The epoll instance all open devices are registered with.
+    epoll: RawFd,
+    devices: Mutex<HashMap<PathBuf, OpenDevice>>,
+    bindings: RwLock<Bindings>,
+    enabled: Arc<AtomicBool>,
+    /// Whether the session is locked, which is only kept up to date with `mute_when_locked`.
+    locked: Arc<AtomicBool>,
+    volume: Arc<Volume>,
+    activity: Arc<Activity>,
+    play_sender: Sender<Play>,
+    control_sender: Sender<Control>,
+    /// The timestamp of the last press that played a sound, on any device.
+    last_click: Mutex<Option<SystemTime>>,
+    /// When the event loop started. Heartbeats are in milliseconds since then.
+    started: Instant,
+    /// When the event loop last woke up.
+    woke: AtomicU64,
+    /// Whether the event loop is handling events, rather than waiting for them.
+    busy: AtomicBool,
+    /// The heartbeats of the open devices, for the watchdog. This is separate from `devices`,
+    /// which stays locked while the event loop is busy.
+    heartbeats: Mutex<HashMap<PathBuf, Weak<AtomicU64>>>,
+    /// Whether the watchdog thread is running.
+    watchdog_running: Mutex<bool>,
+}
+
+/// An open input device, as shown in the tray menu.
+#[cfg(feature = "tray")]
+#[derive(Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// Whether the device plays sounds. This is separate from, and only checked in addition to,
+    /// the global `enabled` flag.
+    pub enabled: Arc<AtomicBool>,
+}
+
+/// An input device that is being listened to.
+struct OpenDevice {
+    device: Device,
+    /// The device name, which selects per-device bindings.
+    name: String,
+    /// Whether this device plays sounds, which can be toggled from the tray menu.
+    enabled: Arc<AtomicBool>,
+    /// Whether we hold an exclusive grab on the device.
+    grabbed: bool,
+    /// When the device last responded to the event loop.
+    heartbeat: Arc<AtomicU64>,
+    /// The timing of the device's clicks.
+    clicks: Clicks,
+    /// The keys that are currently held down, for checking modifiers.
+    held: HashSet<Key>,
+    /// When the buttons that are held down and have a hold sound should play it.
+    hold_deadlines: HashMap<Key, Instant>,
+    /// Stops the looping sounds of the buttons that are held down.
+    loops: HashMap<Key, Arc<AtomicBool>>,
+    /// Scroll distance on high-resolution scroll axes that doesn't add up to a full notch yet.
+    scroll_remainders: HashMap<u16, i32>,
+    /// The high-resolution scroll axes the device supports.
+    hi_res_axes: Vec<RelativeAxisType>,
+}
+
+impl Inputs {
+    /// Creates an empty set of inputs and spawns the thread reading from them.
+    ///
+    /// Opened devices will send the sounds bound to their events to `play_sender`, unless
+    /// `enabled` is `false` (or `locked` is `true`, with `mute_when_locked`), and adjust `volume`
+    /// when a volume hotkey is pressed. Sounds are counted in `activity`, and `control_sender` is woken up
+    /// for each of them and notified when a device is closed.
+    pub fn new(
+        bindings: Bindings,
+        enabled: Arc<AtomicBool>,
+        locked: Arc<AtomicBool>,
+        volume: Arc<Volume>,
+        play_sender: Sender<Play>,
+        activity: Arc<Activity>,
+        control_sender: Sender<Control>,
+    ) -> anyhow::Result<Self> {
+        let shared = Arc::new(Shared {
+            epoll: epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?,
+            devices: Mutex::new(HashMap::new()),
+            bindings: RwLock::new(bindings),
+            enabled,
+            locked,
+            volume,
+            activity,
+            play_sender,
+            control_sender,
+            last_click: Mutex::new(None),
+            started: Instant::now(),
+            woke: AtomicU64::new(0),
+            busy: AtomicBool::new(false),
+            heartbeats: Mutex::new(HashMap::new()),
+            watchdog_running: Mutex::new(false),
+        });
+
+        let thread_shared = shared.clone();
+        thread::spawn(move || thread_shared.run());
+        shared.start_watchdog();
+
+        Ok(Self { shared })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.devices.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.devices.lock().unwrap().is_empty()
+    }
+
+    /// Returns the open devices, sorted by name.
+    #[cfg(feature = "tray")]
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        let devices = self.shared.devices.lock().unwrap();
+        let mut infos = devices
+            .values()
+            .map(|open| DeviceInfo {
+                name: open.name.clone(),
+                enabled: open.enabled.clone(),
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    /// Plays the sound that pressing `key` plays, on devices without their own bindings.
+    ///
+    /// Modifiers aren't checked, and nothing is played while sounds are disabled.
+    pub fn play_key(&self, key: Key) {
+        let shared = &self.shared;
+        let bindings = shared.bindings.read().unwrap();
+        if !shared.is_enabled(&bindings, &AtomicBool::new(true)) {
+            return;
+        }
+        // With `trigger = "release"`, the sound is bound to the release instead.
+        let sounds = bindings
+            .sound("", key, Trigger::Press, |_| true)
+            .or_else(|| bindings.sound("", key, Trigger::Release, |_| true));
+        match sounds {
+            Some(sounds) => shared.play(sounds, &bindings, &mut Rng::new()),
+            None => warn!("no sound is bound to {key:?}"),
+        }
+    }
+
+    pub fn set_bindings(&mut self, bindings: Bindings) {
+        *self.shared.bindings.write().unwrap() = bindings;
+        self.shared.start_watchdog();
+    }
+
+    /// Closes the device at `path` and opens it again, if it still matches the `config`.
+    pub fn reopen(&mut self, config: &Config, path: &Path) {
+        if self.shared.devices.lock().unwrap().remove(path).is_some() {
+            info!("reopening input device {}", path.display());
+            self.scan(config);
+        }
+    }
+
+    /// Opens all devices that match the `config` and aren't open yet, and closes open devices
+    /// that no longer match.
+    pub fn scan(&mut self, config: &Config) {
+        for (path, device) in evdev::enumerate() {
+            let matches = self.matches(config, &device);
+            let mut devices = self.shared.devices.lock().unwrap();
+            match (devices.get_mut(&path), matches) {
+                (Some(open), true) => open.set_grab(&path, config.grab()),
+                (Some(_), false) => {
+                    info!("closing input device {}", path.display());
+                    // Closing the device also removes it from the epoll set.
+                    devices.remove(&path);
+                }
+                (None, true) => {
+                    if let Err(e) = self.shared.open(&mut devices, &path, device, config.grab()) {
+                        error!("failed to open {}: {e}", path.display());
+                    }
+                }
+                (None, false) => {}
+            }
+        }
+    }
+
+    fn matches(&self, config: &Config, device: &Device) -> bool {
+        let bindings = self.shared.bindings.read().unwrap();
+        let name = device.name().unwrap_or_default();
+        let has_keys = device.supported_events().contains(EventType::KEY)
+            && device
+                .supported_keys()
+                .is_some_and(|keys| keys.iter().any(|key| bindings.binds_key(name, key)));
+        let has_axes = device.supported_events().contains(EventType::RELATIVE)
+            && device
+                .supported_relative_axes()
+                .is_some_and(|axes| bindings.scroll.iter().any(|(axis, _)| axes.contains(*axis)));
+        if !has_keys && !has_axes {
+            return false;
+        }
+
+        // Ignored devices are never opened, even if they are also listed in `devices` or `ids`.
+        let id = device.input_id();
+        if config
+            .ignored_devices()
+            .any(|name| Some(name) == device.name())
+            || config
+                .ignored_ids()
+                .any(|(vendor, product)| vendor == id.vendor() && product == id.product())
+        {
+            return false;
+        }
+
+        // A device matches if either its name or its ID is listed.
+        let by_name = config
+            .devices()
+            .map(|mut devs| devs.any(|name| Some(name) == device.name()));
+        let by_id = config.ids().map(|mut ids| {
+            ids.any(|(vendor, product)| vendor == id.vendor() && product == id.product())
+        });
+        match (by_name, by_id) {
+            (None, None) => true,
+            _ => by_name == Some(true) || by_id == Some(true),
+        }
+    }
+}
+
+impl OpenDevice {
+    fn new(device: Device) -> Self {
+        let hi_res_axes = device
+            .supported_relative_axes()
+            .map(|axes| axes.iter().filter(|axis| is_hi_res(*axis)).collect())
+            .unwrap_or_default();
+        Self {
+            name: device.name().unwrap_or_default().to_string(),
+            enabled: Arc::new(AtomicBool::new(true)),
+            device,
+            grabbed: false,
+            heartbeat: Arc::new(AtomicU64::new(0)),
+            clicks: Clicks::default(),
+            held: HashSet::new(),
+            hold_deadlines: HashMap::new(),
+            loops: HashMap::new(),
+            scroll_remainders: HashMap::new(),
+            hi_res_axes,
+        }
+    }
+
+    /// Grabs or releases the device, depending on `grab`.
+    fn set_grab(&mut self, path: &Path, grab: bool) {
+        if grab == self.grabbed {
+            return;
+        }
+        let res = if grab {
+            self.device.grab()
+        } else {
+            self.device.ungrab()
+        };
+        match res {
+            Ok(()) => self.grabbed = grab,
+            Err(e) => error!("failed to change grab of {}: {e}", path.display()),
+        }
+    }
+}
+
+impl Drop for OpenDevice {
+    fn drop(&mut self) {
+        if self.grabbed {
+            // This fails if the device is already gone, which releases the grab anyways.
+            self.device.ungrab().ok();
+        }
+        // The buttons won't be released anymore.
+        for stop in self.loops.values() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// What kind of click an event sounds as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Click {
+    Single,
+    /// The second press of a double click.
+    Double,
+    /// The first press after no button was pressed for a while.
+    First,
+}
+
+/// Tracks the timing of the clicks on a device, for the cooldown, double clicks and first clicks
+/// after idle.
+#[derive(Default)]
+struct Clicks {
+    /// The timestamps of the last accepted events, used to apply the cooldown.
+    last_triggered: HashMap<(Key, Trigger), SystemTime>,
+    /// The timestamps of presses that could be the first half of a double click.
+    last_press: HashMap<Key, SystemTime>,
+    /// The kind of click of the keys that are held down, which their release plays in release
+    /// mode.
+    pressed: HashMap<Key, Click>,
+}
+
+impl Clicks {
+    /// Records the event with `value` of `key` at `time`, and returns the kind of click it sounds
+    /// as, or `None` if it is within the key's cooldown.
+    ///
+    /// This has to see presses even if only releases play sounds. `last_click` is the time of the
+    /// last press on any device.
+    fn event(
+        &mut self,
+        bindings: &Bindings,
+        device: &str,
+        key: Key,
+        value: i32,
+        time: SystemTime,
+        last_click: &mut Option<SystemTime>,
+    ) -> Option<Click> {
+        let trigger = if value == 0 {
+            Trigger::Release
+        } else {
+            Trigger::Press
+        };
+        if let Some(last) = self.last_triggered.get(&(key, trigger)) {
+            let cooldown = bindings.cooldown(device, key);
+            if time.duration_since(*last).unwrap_or_default() < cooldown {
+                return None;
+            }
+        }
+        self.last_triggered.insert((key, trigger), time);
+
+        match value {
+            1 => {
+                // Key repeats don't count as clicks. After a double click, the next press starts
+                // over instead of forming another double click with the second press.
+                let mut click = Click::Single;
+                if bindings.double.is_some() {
+                    match self.last_press.remove(&key) {
+                        Some(last)
+                            if time.duration_since(last).unwrap_or_default()
+                                < bindings.double_click =>
+                        {
+                            click = Click::Double;
+                        }
+                        _ => {
+                            self.last_press.insert(key, time);
+                        }
+                    }
+                }
+                let idle = last_click.replace(time).is_none_or(|last| {
+                    time.duration_since(last).unwrap_or_default() >= bindings.idle_threshold
+                });
+                if bindings.first.is_some() && idle {
+                    click = Click::First;
+                }
+                self.pressed.insert(key, click);
+                Some(click)
+            }
+            0 => {
+                let click = self.pressed.remove(&key).unwrap_or(Click::Single);
+                if bindings.trigger == config::Trigger::Release {
+                    Some(click)
+                } else {
+                    Some(Click::Single)
+                }
+            }
+            _ => Some(Click::Single),
+        }
+    }
+}
+
+impl Shared {
+    /// Starts listening to the input `device` at `path`, and adds it to the open `devices`.
+    ///
+    /// The device is grabbed if `grab` is set.
+    fn open(
+        &self,
+        devices: &mut HashMap<PathBuf, OpenDevice>,
+        path: &Path,
+        device: Device,
+        grab: bool,
+    ) -> nix::Result<()> {
+        self.register(&device)?;
+        info!(
+            "opening input device {}: {}",
+            path.display(),
+            device.name().unwrap_or("<unnamed>"),
+        );
+        let mut open = OpenDevice::new(device);
+        open.set_grab(path, grab);
+        open.heartbeat.store(self.now_ms(), Ordering::Relaxed);
+        self.heartbeats
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), Arc::downgrade(&open.heartbeat));
+        devices.insert(path.to_path_buf(), open);
+        Ok(())
+    }
+
+    /// Makes `device` non-blocking and adds it to the epoll set.
+    fn register(&self, device: &Device) -> nix::Result<()> {
+        let fd = device.as_raw_fd();
+        // A device might get closed and replaced by another one with the same fd between
+        // `epoll_wait` returning and us reading from it, so reads must never block.
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
+        epoll_ctl(self.epoll, EpollOp::EpollCtlAdd, fd, &mut event)
+    }
+
+    /// Runs the event loop, dispatching events from all open devices.
+    ///
+    /// All devices share this one thread, and are only ever read without blocking, so a
+    /// misbehaving device can't stall the others. Read errors close the device (and report it to
+    /// the main thread), and it's opened again if it reappears. Devices that stop responding without
+    /// an error are left to the watchdog, which the loop feeds with heartbeats.
+    fn run(&self) {
+        let mut rng = Rng::new();
+        let mut events = [EpollEvent::empty(); 16];
+        loop {
+            self.busy.store(false, Ordering::Relaxed);
+            let n = match epoll_wait(self.epoll, &mut events, self.timeout()) {
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    error!("failed to wait for input events: {e}; exiting");
+                    process::exit(1);
+                }
+            };
+            self.woke.store(self.now_ms(), Ordering::Relaxed);
+            self.busy.store(true, Ordering::Relaxed);
+
+            let mut devices = self.devices.lock().unwrap();
+            for event in &events[..n] {
+                let fd = event.data() as RawFd;
+                // Modifiers may be held on a different device than the button.
+                let held_elsewhere = devices
+                    .values()
+                    .filter(|open| open.device.as_raw_fd() != fd)
+                    .flat_map(|open| open.held.iter().copied())
+                    .collect::<HashSet<_>>();
+                let Some((path, device)) = devices
+                    .iter_mut()
+                    .find(|(_, open)| open.device.as_raw_fd() == fd)
+                else {
+                    // The device was closed in the meantime.
+                    continue;
+                };
+
+                let res =
+                    profile::INPUT.time(|| self.dispatch(path, device, &held_elsewhere, &mut rng));
+                match res {
+                    Ok(()) => device.heartbeat.store(self.now_ms(), Ordering::Relaxed),
+                    Err(e) => {
+                        error!("{e}; closing {}", path.display());
+                        let path = path.clone();
+                        devices.remove(&path);
+                        // This fails once `main` is shutting down, and then nobody needs to know.
+                        self.control_sender.send(Control::DeviceClosed(path)).ok();
+                    }
+                }
+            }
+            self.play_holds(&mut devices, &mut rng);
+            self.probe(&devices);
+        }
+    }
+
+    /// Returns the time since the event loop started, in milliseconds.
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Renews the heartbeat of the idle `devices` that still respond, if the watchdog is on.
+    ///
+    /// Devices are only checked after going without a heartbeat for half the watchdog interval,
+    /// since they usually get one by sending events.
+    fn probe(&self, devices: &HashMap<PathBuf, OpenDevice>) {
+        let Some(interval) = self.bindings.read().unwrap().watchdog else {
+            return;
+        };
+        let now = self.now_ms();
+        for open in devices.values() {
+            let age = now.saturating_sub(open.heartbeat.load(Ordering::Relaxed));
+            // Reading the key state is a cheap ioctl, which fails once the device is broken.
+            if age >= interval.as_millis() as u64 / 2 && open.device.get_key_state().is_ok() {
+                open.heartbeat.store(now, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Starts the watchdog thread, if the bindings turn it on and it isn't running already.
+    fn start_watchdog(self: &Arc<Self>) {
+        let mut running = self.watchdog_running.lock().unwrap();
+        if *running || self.bindings.read().unwrap().watchdog.is_none() {
+            return;
+        }
+        *running = true;
+        let shared = self.clone();
+        thread::spawn(move || shared.watchdog());
+    }
+
+    /// Warns about open devices without a heartbeat for the watchdog interval, and has them
+    /// reopened with `watchdog_reopen`, until the bindings turn the watchdog off.
+    fn watchdog(&self) {
+        let started = self.now_ms();
+        let mut stalled = HashSet::new();
+        let mut stuck = false;
+        loop {
+            let (interval, reopen) = {
+                let mut running = self.watchdog_running.lock().unwrap();
+                let bindings = self.bindings.read().unwrap();
+                let Some(interval) = bindings.watchdog else {
+                    *running = false;
+                    return;
+                };
+                (interval, bindings.watchdog_reopen)
+            };
+            thread::sleep(interval / 2);
+
+            let now = self.now_ms();
+            let limit = interval.as_millis() as u64;
+            let woke = self.woke.load(Ordering::Relaxed);
+            let busy = now.saturating_sub(woke);
+            if self.busy.load(Ordering::Relaxed) && busy > limit {
+                // Reopening devices would wait for the event loop as well.
+                if !stuck {
+                    warn!(
+                        "the input thread has been busy for {:.1} s; clicks have stopped",
+                        busy as f32 / 1000.0,
+                    );
+                }
+                stuck = true;
+                continue;
+            }
+            if stuck {
+                info!("the input thread is running again");
+                stuck = false;
+            }
+            // If the watchdog was turned on by a reload, the event loop may still be waiting
+            // without a timeout, and hasn't started checking idle devices yet.
+            if woke < started {
+                continue;
+            }
+
+            let mut heartbeats = self.heartbeats.lock().unwrap();
+            heartbeats.retain(|_, heartbeat| heartbeat.strong_count() > 0);
+            stalled.retain(|path| heartbeats.contains_key(path));
+            for (path, heartbeat) in heartbeats.iter() {
+                let Some(heartbeat) = heartbeat.upgrade() else {
+                    continue;
+                };
+                let age = now.saturating_sub(heartbeat.load(Ordering::Relaxed));
+                if age <= limit {
+                    stalled.remove(path);
+                    continue;
+                }
+                // Unplugged devices are closed once reading from them fails.
+                if !path.exists() || !stalled.insert(path.clone()) {
+                    continue;
+                }
+                warn!(
+                    "input device {} hasn't responded for {:.1} s",
+                    path.display(),
+                    age as f32 / 1000.0,
+                );
+                if reopen {
+                    self.control_sender
+                        .send(Control::DeviceStalled(path.clone()))
+                        .ok();
+                }
+            }
+        }
+    }
+
+    /// Returns whether sounds should be played for events from a device with the given
+    /// `device_enabled` flag.
+    fn is_enabled(&self, bindings: &Bindings, device_enabled: &AtomicBool) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+            && device_enabled.load(Ordering::Relaxed)
+            && !(bindings.mute_when_locked && self.locked.load(Ordering::Relaxed))
+    }
+
+    /// Returns the epoll timeout in milliseconds until the next hold sound is due or idle devices
+    /// need to be checked for the watchdog, or -1 if neither is the case.
+    fn timeout(&self) -> isize {
+        let hold = self.hold_timeout();
+        match self.bindings.read().unwrap().watchdog {
+            Some(interval) => {
+                let probe = (interval / 2).as_millis() as isize;
+                if hold < 0 {
+                    probe
+                } else {
+                    hold.min(probe)
+                }
+            }
+            None => hold,
+        }
+    }
+
+    /// Returns the epoll timeout in milliseconds until the next hold sound is due, or -1 if no
+    /// button with a hold sound is held down.
+    fn hold_timeout(&self) -> isize {
+        let devices = self.devices.lock().unwrap();
+        let next = devices
+            .values()
+            .flat_map(|open| open.hold_deadlines.values())
+            .min();
+        match next {
+            // Round up, so that the deadline has passed when epoll returns.
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                timeout.as_micros().div_ceil(1000) as isize
+            }
+            None => -1,
+        }
+    }
+
+    /// Plays the hold sounds of all buttons that have been held down long enough.
+    fn play_holds(&self, devices: &mut HashMap<PathBuf, OpenDevice>, rng: &mut Rng) {
+        let bindings = self.bindings.read().unwrap();
+        let now = Instant::now();
+        for (path, open) in devices.iter_mut() {
+            let due = open
+                .hold_deadlines
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for key in due {
+                open.hold_deadlines.remove(&key);
+                if !self.is_enabled(&bindings, &open.enabled) {
+                    continue;
+                }
+                // Modifiers were checked when the button was pressed.
+                if let Some(sounds) = bindings.sound(&open.name, key, Trigger::Hold, |_| true) {
+                    debug!("{}: {key:?} {:?}", path.display(), Trigger::Hold);
+                    self.play(sounds, &bindings, rng);
+                }
+            }
+        }
+    }
+
+    /// Reads all pending events from the device at `path` and plays the sounds bound to them.
+    ///
+    /// `held_elsewhere` contains the keys held down on all other devices.
+    fn dispatch(
+        &self,
+        path: &Path,
+        open: &mut OpenDevice,
+        held_elsewhere: &HashSet<Key>,
+        rng: &mut Rng,
+    ) -> io::Result<()> {
+        let events = match open.device.fetch_events() {
+            Ok(events) => events,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let bindings = self.bindings.read().unwrap();
+        for event in events {
+            match event.kind() {
+                InputEventKind::Key(key) => {
+                    match event.value() {
+                        1 => {
+                            open.held.insert(key);
+                        }
+                        0 => {
+                            open.held.remove(&key);
+                            open.hold_deadlines.remove(&key);
+                            if let Some(stop) = open.loops.remove(&key) {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                        _ => {}
+                    }
+                    if let Some(step) = bindings.volume_step(key) {
+                        if key_trigger(event.value(), bindings.repeat) == Some(Trigger::Press) {
+                            let volume = (self.volume.get() + step).clamp(0.0, MAX_VOLUME);
+                            self.volume.set(volume);
+                            info!("volume set to {:.0}%", volume * 100.0);
+                            self.control_sender.send(Control::StateChanged).ok();
+                        }
+                        continue;
+                    }
+
+                    let enabled = self.is_enabled(&bindings, &open.enabled);
+                    let Some(trigger) = should_trigger(&event, &bindings, &open.name, enabled)
+                    else {
+                        continue;
+                    };
+                    let held = |key| open.held.contains(&key) || held_elsewhere.contains(&key);
+                    let sounds = bindings.sound(&open.name, key, trigger, held);
+                    let has_hold = bindings
+                        .sound(&open.name, key, Trigger::Hold, held)
+                        .is_some();
+                    // The hold sound is also played when only releases click.
+                    if has_hold && event.value() == 1 {
+                        open.hold_deadlines
+                            .insert(key, Instant::now() + bindings.hold);
+                    }
+                    let looped = bindings.sound(&open.name, key, Trigger::Loop, held);
+                    if let (Some(sounds), 1) = (looped, event.value()) {
+                        let stop = Arc::new(AtomicBool::new(false));
+                        if let Some(old) = open.loops.insert(key, stop.clone()) {
+                            old.store(true, Ordering::Relaxed);
+                        }
+                        debug!("{}: {key:?} {:?}", path.display(), Trigger::Loop);
+                        self.send(sounds.pick(rng).start_loop(stop));
+                    }
+
+                    // Use the kernel's timestamps, since events are read in batches.
+                    let time = event.timestamp();
+                    let value = event.value();
+                    let mut last_click = self.last_click.lock().unwrap();
+                    let click =
+                        open.clicks
+                            .event(&bindings, &open.name, key, value, time, &mut last_click);
+                    drop(last_click);
+                    let Some(click) = click else {
+                        continue;
+                    };
+                    let Some(mut sounds) = sounds else {
+                        continue;
+                    };
+                    match (click, &bindings.double, &bindings.first) {
+                        (Click::Double, Some(double), _) => {
+                            debug!("{}: {key:?} double click", path.display());
+                            sounds = double;
+                        }
+                        (Click::First, _, Some(first)) => {
+                            debug!("{}: {key:?} first click after idle", path.display());
+                            sounds = first;
+                        }
+                        _ => {}
+                    }
+
+                    debug!("{}: {key:?} {trigger:?}", path.display());
+                    self.play(sounds, &bindings, rng);
+                }
+                InputEventKind::RelAxis(axis) => {
+                    if !self.is_enabled(&bindings, &open.enabled) {
+                        continue;
+                    }
+                    let Some(sounds) = bindings.scroll_sound(axis) else {
+                        continue;
+                    };
+                    // Devices with a high-resolution wheel report every notch on both axes, so
+                    // only the high-resolution one plays when both are bound.
+                    let duplicate = hi_res_counterpart(axis).is_some_and(|hi_res| {
+                        open.hi_res_axes.contains(&hi_res)
+                            && bindings.scroll_sound(hi_res).is_some()
+                    });
+                    if duplicate {
+                        continue;
+                    }
+                    let notches = scroll_notches(&mut open.scroll_remainders, axis, event.value());
+                    if notches > 0 {
+                        debug!("{}: {axis:?} scrolled {notches} notch(es)", path.display());
+                    }
+                    for _ in 0..notches {
+                        self.play(sounds, &bindings, rng);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plays a random sound from `sounds`.
+    fn play(&self, sounds: &SoundSet, bindings: &Bindings, rng: &mut Rng) {
+        let variation = bindings.pitch_variation * (rng.next_f32() * 2.0 - 1.0);
+        self.send(sounds.pick(rng).start(1.0 + variation));
+    }
+
+    /// Sends `play` to the mixer, and wakes up the audio stream.
+    fn send(&self, play: Play) {
+        self.activity.start();
+        // These fail once `main` is shutting down, and then the sound doesn't matter anymore.
+        self.play_sender.send(play).ok();
+        self.control_sender.send(Control::Wake).ok();
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unistd::close(self.epoll).ok();
+    }
+}
+
+/// Returns what a key event with the given `value` triggers, if anything.
+///
+/// Key repeats (value 2), which are sent while a key is held down, count as presses if `repeat`
+/// is set.
+fn key_trigger(value: i32, repeat: bool) -> Option<Trigger> {
+    match value {
+        1 => Some(Trigger::Press),
+        0 => Some(Trigger::Release),
+        2 if repeat => Some(Trigger::Press),
+        _ => None,
+    }
+}
+
+/// Returns what the key `event` from the device called `device` triggers, if it can play a sound.
+///
+/// Nothing is triggered while sounds aren't `enabled`, or by keys that have no sound on the
+/// device. Volume hotkeys never play a sound, and are handled separately.
+fn should_trigger(
+    event: &InputEvent,
+    bindings: &Bindings,
+    device: &str,
+    enabled: bool,
+) -> Option<Trigger> {
+    let InputEventKind::Key(key) = event.kind() else {
+        return None;
+    };
+    let bound = bindings.buttons(device).contains_key(&key)
+        || (bindings.default_key.is_some() && is_keyboard_key(key));
+    if !enabled || !bound || bindings.volume_step(key).is_some() {
+        return None;
+    }
+    key_trigger(event.value(), bindings.repeat)
+}
+
+/// Returns whether `key` is a key on a keyboard, rather than a button on a mouse or a controller.
+///
+/// This covers the main range of keyboard keys, below `BTN_MISC` (which is also `BTN_0`).
+fn is_keyboard_key(key: Key) -> bool {
+    (1..Key::BTN_0.code()).contains(&key.code())
+}
+
+/// Returns whether `axis` reports scrolling in fractions of a notch.
+fn is_hi_res(axis: RelativeAxisType) -> bool {
+    axis == RelativeAxisType::REL_WHEEL_HI_RES || axis == RelativeAxisType::REL_HWHEEL_HI_RES
+}
+
+/// Returns the high-resolution axis that reports the same scrolling as the scroll wheel `axis`.
+fn hi_res_counterpart(axis: RelativeAxisType) -> Option<RelativeAxisType> {
+    match axis {
+        RelativeAxisType::REL_WHEEL => Some(RelativeAxisType::REL_WHEEL_HI_RES),
+        RelativeAxisType::REL_HWHEEL => Some(RelativeAxisType::REL_HWHEEL_HI_RES),
+        _ => None,
+    }
+}
+
+/// Returns the number of notches scrolled by an event on a scroll wheel `axis`.
+///
+/// `remainders` holds the partial notches scrolled so far on high-resolution axes.
+fn scroll_notches(remainders: &mut HashMap<u16, i32>, axis: RelativeAxisType, value: i32) -> u32 {
+    if axis == RelativeAxisType::REL_WHEEL || axis == RelativeAxisType::REL_HWHEEL {
+        return value.unsigned_abs();
+    }
+
+    // High-resolution axes report fractions of a notch, in units of 1/120.
+    let remainder = remainders.entry(axis.0).or_default();
+    if remainder.signum() == -value.signum() {
+        // Changing direction starts a new notch.
+        *remainder = 0;
+    }
+    *remainder += value;
+    let notches = *remainder / 120;
+    *remainder %= 120;
+    notches.unsigned_abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, sync::mpsc};
+
+    use evdev::{
+        uinput::{VirtualDevice, VirtualDeviceBuilder},
+        AttributeSet,
+    };
+
+    use super::*;
+    use crate::sound::constant;
+
+    fn button(sounds: HashMap<Trigger, SoundSet>, pan: f32, release_volume: f32) -> ButtonBinding {
+        ButtonBinding {
+            sounds,
+            modifiers: Vec::new(),
+            cooldown: None,
+            pan,
+            release_volume,
+        }
+    }
+
+    fn bindings(buttons: HashMap<Key, ButtonBinding>, release: Option<SoundSet>) -> Bindings {
+        Bindings {
+            buttons,
+            device_buttons: HashMap::new(),
+            release,
+            default_key: None,
+            double: None,
+            double_click: Duration::ZERO,
+            first: None,
+            idle_threshold: Duration::ZERO,
+            hold: Duration::ZERO,
+            trigger: config::Trigger::Press,
+            scroll: Vec::new(),
+            volume_up: None,
+            volume_down: None,
+            fade: Duration::ZERO,
+            resample_quality: config::ResampleQuality::Linear,
+            cooldown: Duration::ZERO,
+            pitch_variation: 0.0,
+            repeat: false,
+            mute_when_locked: false,
+            watchdog: None,
+            watchdog_reopen: true,
+        }
+    }
+
+    /// Returns the peak of the sound `key` plays on `trigger`.
+    fn peak(bindings: &Bindings, key: Key, trigger: Trigger) -> Option<f32> {
+        let sounds = bindings.sound("", key, trigger, |_| false)?;
+        Some(sounds.first().peak().unwrap())
+    }
+
+    #[test]
+    fn should_trigger_table() {
+        let mut bindings = bindings(
+            HashMap::from([
+                (Key::BTN_LEFT, button(HashMap::new(), 0.0, 1.0)),
+                (Key::KEY_VOLUMEUP, button(HashMap::new(), 0.0, 1.0)),
+            ]),
+            None,
+        );
+        bindings.volume_up = Some(Key::KEY_VOLUMEUP);
+        bindings.device_buttons.insert(
+            "keyboard".into(),
+            HashMap::from([(Key::KEY_A, button(HashMap::new(), 0.0, 1.0))]),
+        );
+        let key = |key: Key, value| InputEvent::new(EventType::KEY, key.code(), value);
+
+        let press = Some(Trigger::Press);
+        let release = Some(Trigger::Release);
+        #[rustfmt::skip]
+        let cases = [
+            // event, device, enabled, repeat, expected
+            (key(Key::BTN_LEFT, 1), "mouse", true, false, press),
+            (key(Key::BTN_LEFT, 0), "mouse", true, false, release),
+            (key(Key::BTN_LEFT, 2), "mouse", true, false, None),
+            (key(Key::BTN_LEFT, 2), "mouse", true, true, press),
+            (key(Key::BTN_LEFT, 3), "mouse", true, true, None),
+            // Disabled.
+            (key(Key::BTN_LEFT, 1), "mouse", false, false, None),
+            (key(Key::BTN_LEFT, 0), "mouse", false, false, None),
+            (key(Key::BTN_LEFT, 2), "mouse", false, true, None),
+            // Unbound keys.
+            (key(Key::BTN_RIGHT, 1), "mouse", true, false, None),
+            (key(Key::BTN_RIGHT, 0), "mouse", true, false, None),
+            (key(Key::KEY_B, 1), "mouse", true, false, None),
+            // Devices with their own buttons only play those.
+            (key(Key::KEY_A, 1), "keyboard", true, false, press),
+            (key(Key::KEY_A, 1), "mouse", true, false, None),
+            (key(Key::BTN_LEFT, 1), "keyboard", true, false, None),
+            // Volume hotkeys don't play sounds, even when they are bound.
+            (key(Key::KEY_VOLUMEUP, 1), "mouse", true, false, None),
+            // Not a key event at all.
+            (InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, 1), "mouse", true, false, None),
+        ];
+        for (event, device, enabled, repeat, expected) in cases {
+            bindings.repeat = repeat;
+            assert_eq!(
+                should_trigger(&event, &bindings, device, enabled),
+                expected,
+                "{:?} {} on {device}, enabled: {enabled}, repeat: {repeat}",
+                event.kind(),
+                event.value(),
+            );
+        }
+
+        // With a default key sound, all keyboard keys play it, but mouse buttons still need a
+        // binding.
+        bindings.repeat = false;
+        bindings.default_key = Some(constant(0.5));
+        assert_eq!(
+            should_trigger(&key(Key::KEY_B, 1), &bindings, "mouse", true),
+            press
+        );
+        assert_eq!(
+            should_trigger(&key(Key::KEY_B, 0), &bindings, "mouse", true),
+            release
+        );
+        assert_eq!(
+            should_trigger(&key(Key::BTN_RIGHT, 1), &bindings, "mouse", true),
+            None
+        );
+        assert_eq!(
+            should_trigger(&key(Key::KEY_B, 1), &bindings, "mouse", false),
+            None
+        );
+    }
+
+    #[test]
+    fn trigger_modes_table() {
+        use config::Trigger::{Both, Press, Release};
+
+        let press = constant(0.1);
+        let release = constant(0.2);
+        #[rustfmt::skip]
+        let cases = [
+            // mode, edge, own release sound, global release sound, expected
+            (Press, Trigger::Press, true, true, Some("press")),
+            (Press, Trigger::Release, true, true, Some("release")),
+            (Press, Trigger::Release, false, true, Some("global")),
+            (Press, Trigger::Release, false, false, None),
+            (Release, Trigger::Press, true, true, None),
+            (Release, Trigger::Release, true, true, Some("release")),
+            (Release, Trigger::Release, false, true, Some("global")),
+            (Release, Trigger::Release, false, false, Some("press")),
+            (Both, Trigger::Press, true, true, Some("press")),
+            (Both, Trigger::Release, true, true, Some("release")),
+            (Both, Trigger::Release, false, true, Some("global")),
+            (Both, Trigger::Release, false, false, Some("press")),
+        ];
+        for (mode, edge, own, global, expected) in cases {
+            let mut bindings = bindings(HashMap::new(), global.then(|| constant(0.3)));
+            bindings.trigger = mode;
+            let own = own.then_some(&release);
+            let sounds = bindings.triggered(edge, Some(&press), own);
+            let got = [
+                ("press", Some(&press)),
+                ("release", own),
+                ("global", bindings.release.as_ref()),
+            ]
+            .into_iter()
+            .find(|(_, set)| sounds.zip(*set).is_some_and(|(a, b)| std::ptr::eq(a, b)))
+            .map(|(name, _)| name);
+            assert_eq!(
+                got,
+                expected,
+                "{mode:?} mode, {edge:?}, own release: {}, global release: {global}",
+                own.is_some(),
+            );
+        }
+    }
+
+    #[test]
+    fn cooldown_is_per_key() {
+        let mut right = button(HashMap::new(), 0.0, 1.0);
+        right.cooldown = Some(Duration::from_millis(200));
+        let mut bindings = bindings(
+            HashMap::from([
+                (Key::BTN_LEFT, button(HashMap::new(), 0.0, 1.0)),
+                (Key::BTN_RIGHT, right),
+            ]),
+            None,
+        );
+        bindings.cooldown = Duration::from_millis(50);
+        let mut clicks = Clicks::default();
+        let mut last_click = None;
+        let mut fires = |key, value, ms| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+            clicks
+                .event(&bindings, "mouse", key, value, time, &mut last_click)
+                .is_some()
+        };
+
+        // Different keys pressed in quick succession both click.
+        assert!(fires(Key::BTN_LEFT, 1, 0));
+        assert!(fires(Key::BTN_RIGHT, 1, 5));
+        // Bounces within the global cooldown don't.
+        assert!(fires(Key::BTN_LEFT, 0, 10));
+        assert!(!fires(Key::BTN_LEFT, 1, 20));
+        assert!(fires(Key::BTN_LEFT, 1, 60));
+        // The right button's own cooldown replaces the global one.
+        assert!(fires(Key::BTN_RIGHT, 0, 10));
+        assert!(!fires(Key::BTN_RIGHT, 1, 100));
+        assert!(fires(Key::BTN_RIGHT, 1, 210));
+    }
+
+    #[test]
+    fn release_mode_double_click() {
+        let mut bindings = bindings(
+            HashMap::from([(Key::BTN_LEFT, button(HashMap::new(), 0.0, 1.0))]),
+            None,
+        );
+        bindings.trigger = config::Trigger::Release;
+        bindings.double = Some(constant(0.5));
+        bindings.double_click = Duration::from_millis(300);
+        let mut clicks = Clicks::default();
+        let mut last_click = None;
+        let mut event = |value, ms| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+            clicks.event(
+                &bindings,
+                "mouse",
+                Key::BTN_LEFT,
+                value,
+                time,
+                &mut last_click,
+            )
+        };
+
+        // The press doesn't sound, but its release plays the double click sound.
+        assert_eq!(event(1, 0), Some(Click::Single));
+        assert_eq!(event(0, 50), Some(Click::Single));
+        assert_eq!(event(1, 100), Some(Click::Double));
+        assert_eq!(event(0, 150), Some(Click::Double));
+        // The next click starts over.
+        assert_eq!(event(1, 200), Some(Click::Single));
+        assert_eq!(event(0, 250), Some(Click::Single));
+        // Too slow.
+        assert_eq!(event(1, 1000), Some(Click::Single));
+        assert_eq!(event(0, 1050), Some(Click::Single));
+    }
+
+    #[test]
+    fn press_mode_release_is_single() {
+        let mut bindings = bindings(
+            HashMap::from([(Key::BTN_LEFT, button(HashMap::new(), 0.0, 1.0))]),
+            None,
+        );
+        bindings.double = Some(constant(0.5));
+        bindings.double_click = Duration::from_millis(300);
+        let mut clicks = Clicks::default();
+        let mut last_click = None;
+        let mut event = |value, ms| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+            clicks.event(
+                &bindings,
+                "mouse",
+                Key::BTN_LEFT,
+                value,
+                time,
+                &mut last_click,
+            )
+        };
+
+        assert_eq!(event(1, 0), Some(Click::Single));
+        assert_eq!(event(0, 50), Some(Click::Single));
+        assert_eq!(event(1, 100), Some(Click::Double));
+        // The release of the second press plays the usual release sound.
+        assert_eq!(event(0, 150), Some(Click::Single));
+    }
+
+    #[test]
+    fn release_mode_first_click() {
+        let mut bindings = bindings(
+            HashMap::from([(Key::BTN_LEFT, button(HashMap::new(), 0.0, 1.0))]),
+            None,
+        );
+        bindings.trigger = config::Trigger::Release;
+        bindings.first = Some(constant(0.5));
+        bindings.idle_threshold = Duration::from_secs(1);
+        let mut clicks = Clicks::default();
+        let mut last_click = None;
+        let mut event = |value, ms| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+            clicks.event(
+                &bindings,
+                "mouse",
+                Key::BTN_LEFT,
+                value,
+                time,
+                &mut last_click,
+            )
+        };
+
+        // The silent press still counts as activity, and its release plays the first sound.
+        assert_eq!(event(1, 0), Some(Click::First));
+        assert_eq!(event(0, 50), Some(Click::First));
+        assert_eq!(event(1, 500), Some(Click::Single));
+        assert_eq!(event(0, 550), Some(Click::Single));
+        assert_eq!(event(1, 2000), Some(Click::First));
+        assert_eq!(event(0, 2050), Some(Click::First));
+    }
+
+    /// Creates a virtual mouse with only a left button through uinput, and opens the event device
+    /// it creates. Returns `None` if uinput isn't accessible.
+    fn virtual_mouse() -> Option<(VirtualDevice, PathBuf, Device)> {
+        let builder = match VirtualDeviceBuilder::new() {
+            Ok(builder) => builder,
+            Err(e) => {
+                eprintln!("skipping test, since /dev/uinput is not accessible: {e}");
+                return None;
+            }
+        };
+        let keys = AttributeSet::from_iter([Key::BTN_LEFT]);
+        let mut mouse = builder
+            .name("clickd test mouse")
+            .with_keys(&keys)
+            .unwrap()
+            .build()
+            .unwrap();
+        // The device node may only show up a moment after the device was created.
+        for _ in 0..100 {
+            let path = mouse
+                .enumerate_dev_nodes_blocking()
+                .unwrap()
+                .filter_map(Result::ok)
+                .find(|path| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with("event")
+                });
+            if let Some(device) = path.as_ref().and_then(|path| Device::open(path).ok()) {
+                return Some((mouse, path.unwrap(), device));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the device node of the virtual mouse didn't appear");
+    }
+
+    #[test]
+    fn uinput_press_plays_sound() {
+        let Some((mut mouse, path, device)) = virtual_mouse() else {
+            return;
+        };
+        let sounds = HashMap::from([(Trigger::Press, constant(0.5))]);
+        let buttons = HashMap::from([(Key::BTN_LEFT, button(sounds, 0.0, 1.0))]);
+        let (play_sender, play_receiver) = mpsc::channel();
+        let (control_sender, control_receiver) = mpsc::channel();
+        let inputs = Inputs::new(
+            bindings(buttons, None),
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(Volume::new(1.0)),
+            play_sender,
+            Arc::new(Activity::default()),
+            control_sender,
+        )
+        .unwrap();
+        let shared = &inputs.shared;
+        shared
+            .open(&mut shared.devices.lock().unwrap(), &path, device, false)
+            .unwrap();
+
+        let left = |value| InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), value);
+        mouse.emit(&[left(1)]).unwrap();
+        let timeout = Duration::from_secs(5);
+        let play = play_receiver
+            .recv_timeout(timeout)
+            .expect("no sound was played");
+        assert!(matches!(play, Play::Memory { .. }));
+        assert!(matches!(
+            control_receiver.recv_timeout(timeout),
+            Ok(Control::Wake)
+        ));
+
+        // There's no release sound, and disabled sounds don't play at all.
+        mouse.emit(&[left(0)]).unwrap();
+        shared.enabled.store(false, Ordering::Relaxed);
+        mouse.emit(&[left(1)]).unwrap();
+        assert!(play_receiver
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+
+        // Close the device before the virtual mouse goes away, which the event loop would report
+        // to the closed `control_receiver`.
+        shared.devices.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn watchdog_reopens_stalled_devices() {
+        let mut watched = bindings(HashMap::new(), None);
+        watched.watchdog = Some(Duration::from_millis(200));
+        let (play_sender, _play_receiver) = mpsc::channel();
+        let (control_sender, control_receiver) = mpsc::channel();
+        let mut inputs = Inputs::new(
+            watched,
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(Volume::new(1.0)),
+            play_sender,
+            Arc::new(Activity::default()),
+            control_sender,
+        )
+        .unwrap();
+        let shared = &inputs.shared;
+
+        // Stand-ins for the heartbeats of open devices: one that stopped responding, one that
+        // keeps responding, and one that was unplugged.
+        let stalled = Arc::new(AtomicU64::new(0));
+        let responding = Arc::new(AtomicU64::new(0));
+        let unplugged = Arc::new(AtomicU64::new(0));
+        let plugged_in = env::temp_dir();
+        shared.heartbeats.lock().unwrap().extend([
+            (plugged_in.clone(), Arc::downgrade(&stalled)),
+            (PathBuf::from("/"), Arc::downgrade(&responding)),
+            (
+                plugged_in.join("clickd-unplugged"),
+                Arc::downgrade(&unplugged),
+            ),
+        ]);
+
+        let mut reopened = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            responding.store(shared.now_ms(), Ordering::Relaxed);
+            if let Ok(Control::DeviceStalled(path)) =
+                control_receiver.recv_timeout(Duration::from_millis(50))
+            {
+                reopened.push(path);
+            }
+        }
+        // Each stall is only reported once.
+        assert_eq!(reopened, [plugged_in]);
+
+        // Turning the watchdog off stops its thread.
+        inputs.set_bindings(bindings(HashMap::new(), None));
+        thread::sleep(Duration::from_millis(300));
+        assert!(!*inputs.shared.watchdog_running.lock().unwrap());
+    }
+
+    #[test]
+    fn global_release_sound_uses_button_volume() {
+        let press = || HashMap::from([(Trigger::Press, constant(0.5))]);
+        let buttons = HashMap::from([
+            (Key::BTN_LEFT, button(press(), 0.0, 1.0)),
+            (Key::BTN_RIGHT, button(press(), 0.0, 0.5)),
+            (Key::BTN_MIDDLE, button(press(), 0.5, 0.25)),
+        ]);
+        let bindings = bindings(buttons, Some(constant(0.8))).convert(2, 48000);
+        assert_eq!(peak(&bindings, Key::BTN_LEFT, Trigger::Release), Some(0.8));
+        assert_eq!(peak(&bindings, Key::BTN_RIGHT, Trigger::Release), Some(0.4));
+        // The pan attenuates one channel, but the louder one stays at the button's volume.
+        let middle = peak(&bindings, Key::BTN_MIDDLE, Trigger::Release).unwrap();
+        let (left, right) = crate::sound::pan_gains(0.5);
+        assert!((middle - 0.2 * left.max(right)).abs() < 1e-6, "{middle}");
+        // The global sound itself is unchanged.
+        assert_eq!(
+            bindings.release.as_ref().unwrap().first().peak().unwrap(),
+            0.8
+        );
+    }
+
+    #[test]
+    fn own_release_sound_is_not_rescaled() {
+        let sounds = HashMap::from([
+            (Trigger::Press, constant(0.5)),
+            (Trigger::Release, constant(0.3)),
+        ]);
+        let buttons = HashMap::from([(Key::BTN_LEFT, button(sounds, 0.0, 0.5))]);
+        let bindings = bindings(buttons, Some(constant(0.8))).convert(2, 48000);
+        // `release_volume` has already been applied to the button's own sounds when loading them.
+        assert_eq!(peak(&bindings, Key::BTN_LEFT, Trigger::Release), Some(0.3));
+        assert_eq!(peak(&bindings, Key::BTN_LEFT, Trigger::Press), Some(0.5));
+    }
+
+    #[test]
+    fn scroll_notches_low_res() {
+        let mut remainders = HashMap::new();
+        let wheel = RelativeAxisType::REL_WHEEL;
+        assert_eq!(scroll_notches(&mut remainders, wheel, 1), 1);
+        assert_eq!(scroll_notches(&mut remainders, wheel, -1), 1);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 3), 3);
+        assert_eq!(
+            scroll_notches(&mut remainders, RelativeAxisType::REL_HWHEEL, -2),
+            2
+        );
+        assert!(remainders.is_empty());
+    }
+
+    #[test]
+    fn scroll_notches_hi_res_accumulates() {
+        let mut remainders = HashMap::new();
+        let wheel = RelativeAxisType::REL_WHEEL_HI_RES;
+        // A full notch in one event.
+        assert_eq!(scroll_notches(&mut remainders, wheel, 120), 1);
+        // Partial notches add up, and the rest carries over.
+        assert_eq!(scroll_notches(&mut remainders, wheel, 40), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 40), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 60), 1);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 40), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 200), 2);
+        // Several notches in one event.
+        assert_eq!(scroll_notches(&mut remainders, wheel, 360), 3);
+    }
+
+    #[test]
+    fn scroll_notches_hi_res_sign() {
+        let mut remainders = HashMap::new();
+        let wheel = RelativeAxisType::REL_WHEEL_HI_RES;
+        assert_eq!(scroll_notches(&mut remainders, wheel, -60), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, -60), 1);
+        assert_eq!(scroll_notches(&mut remainders, wheel, -90), 0);
+        // Changing direction drops the partial notch in the old direction.
+        assert_eq!(scroll_notches(&mut remainders, wheel, 60), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 60), 1);
+        // Each axis accumulates on its own.
+        let hwheel = RelativeAxisType::REL_HWHEEL_HI_RES;
+        assert_eq!(scroll_notches(&mut remainders, wheel, 100), 0);
+        assert_eq!(scroll_notches(&mut remainders, hwheel, 100), 0);
+        assert_eq!(scroll_notches(&mut remainders, wheel, 20), 1);
+        assert_eq!(scroll_notches(&mut remainders, hwheel, -100), 0);
+    }
+
+    #[test]
+    fn touch_keys_are_not_keyboard_keys() {
+        // Otherwise `default_key_sound` would play whenever a finger touches a touchpad.
+        for key in [
+            Key::BTN_TOUCH,
+            Key::BTN_TOOL_FINGER,
+            Key::BTN_LEFT,
+            Key::BTN_0,
+        ] {
+            assert!(!is_keyboard_key(key), "{key:?}");
+        }
+        assert!(is_keyboard_key(Key::KEY_ESC));
+        assert!(is_keyboard_key(Key::KEY_A));
+    }
+
+    #[test]
+    fn hi_res_counterparts() {
+        assert_eq!(
+            hi_res_counterpart(RelativeAxisType::REL_WHEEL),
+            Some(RelativeAxisType::REL_WHEEL_HI_RES)
+        );
+        assert_eq!(
+            hi_res_counterpart(RelativeAxisType::REL_HWHEEL),
+            Some(RelativeAxisType::REL_HWHEEL_HI_RES)
+        );
+        assert_eq!(hi_res_counterpart(RelativeAxisType::REL_WHEEL_HI_RES), None);
+        assert!(is_hi_res(RelativeAxisType::REL_HWHEEL_HI_RES));
+        assert!(!is_hi_res(RelativeAxisType::REL_WHEEL));
+    }
+}
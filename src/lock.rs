@@ -0,0 +1,67 @@
+//! Ensures that only one instance of `clickd` runs at a time.
+//!
+//! Two instances would both play a sound for every click, and fight over grabbed devices.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    unistd::{getpid, getuid},
+};
+
+/// An exclusive lock on the lock file, held until this is dropped.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock, or fails if another instance is already holding it.
+    pub fn acquire() -> anyhow::Result<Self> {
+        let path = runtime_file("lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Truncating would erase the PID of the instance holding the lock.
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file '{}'", path.display()))?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                // The file contains the PID of the instance that holds the lock.
+                let pid = fs::read_to_string(&path).unwrap_or_default();
+                match pid.trim() {
+                    "" => bail!("another instance of clickd is already running"),
+                    pid => bail!("another instance of clickd is already running (PID {pid})"),
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to lock '{}'", path.display()));
+            }
+        }
+
+        file.set_len(0)?;
+        writeln!(file, "{}", getpid())?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Returns the location of a runtime file like the lock file, `$XDG_RUNTIME_DIR/clickd.<ext>`.
+///
+/// If `$XDG_RUNTIME_DIR` isn't set, a per-user file in the temporary directory is used instead.
+pub fn runtime_file(ext: &str) -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join(format!("clickd.{ext}")),
+        _ => env::temp_dir().join(format!("clickd-{}.{ext}", getuid())),
+    }
+}
@@ -0,0 +1,132 @@
+//! Minimal leveled logging, configured via the `RUST_LOG` environment variable.
+//!
+//! Informational messages go to stdout, warnings and errors to stderr. When running under
+//! journald, each line is prefixed with its syslog priority (see `sd-daemon(3)`), so that the
+//! journal can filter messages by level.
+
+use std::{
+    env, fmt,
+    io::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Error => "error: ",
+            Level::Warn => "warning: ",
+            Level::Info => "",
+            Level::Debug => "debug: ",
+        }
+    }
+
+    fn syslog_priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+/// The most verbose level that is logged, or 0 if logging is off.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JOURNAL: AtomicBool = AtomicBool::new(false);
+
+/// Configures logging from the environment.
+///
+/// `RUST_LOG` may be set to `off`, `error`, `warn`, `info` (the default) or `debug`. For
+/// compatibility with other programs, `clickd=<level>` is also accepted, and directives for other
+/// targets are ignored.
+pub fn init() {
+    JOURNAL.store(env::var_os("JOURNAL_STREAM").is_some(), Ordering::Relaxed);
+
+    let Ok(filter) = env::var("RUST_LOG") else {
+        return;
+    };
+    let mut max_level = None;
+    for directive in filter.split(',').map(str::trim) {
+        let level = match directive.split_once('=') {
+            Some(("clickd", level)) => level,
+            Some(_) => continue,
+            None => directive,
+        };
+        match parse_max_level(level) {
+            Some(level) => max_level = Some(level),
+            None => {
+                log(
+                    Level::Warn,
+                    format_args!("ignoring invalid `RUST_LOG` directive '{directive}'"),
+                );
+            }
+        }
+    }
+    if let Some(level) = max_level {
+        MAX_LEVEL.store(level, Ordering::Relaxed);
+    }
+}
+
+/// Parses a level name into the value stored in [`MAX_LEVEL`].
+fn parse_max_level(s: &str) -> Option<u8> {
+    let level = match &*s.to_ascii_lowercase() {
+        "off" => return Some(0),
+        "error" => Level::Error,
+        "warn" | "warning" => Level::Warn,
+        "info" => Level::Info,
+        "debug" | "trace" => Level::Debug,
+        _ => return None,
+    };
+    Some(level as u8)
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, args: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+
+    let priority = if JOURNAL.load(Ordering::Relaxed) {
+        format!("<{}>", level.syslog_priority())
+    } else {
+        String::new()
+    };
+    let line = format!("{priority}{}{args}\n", level.prefix());
+    // There's nowhere to report failures to write the log to.
+    if level <= Level::Warn {
+        io::stderr().write_all(line.as_bytes()).ok();
+    } else {
+        io::stdout().write_all(line.as_bytes()).ok();
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! warn_ {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+// `warn` can't be defined directly, since it would be ambiguous with the built-in `#[warn]`
+// attribute.
+pub(crate) use {debug, error, info, warn_ as warn};
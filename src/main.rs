@@ -1,261 +1,1455 @@
+mod aiff;
+mod base64;
 mod config;
+mod fifo;
+mod flac;
+mod input;
+mod lock;
+mod log;
+mod mixer;
+#[cfg(feature = "tray")]
+mod notification;
+mod ogg;
+mod output;
+mod profile;
+mod random;
+mod ring;
+mod service;
+mod session;
+mod sound;
+mod status_file;
+mod stream;
+mod systemd;
+#[cfg(feature = "tray")]
 mod systray;
+mod vorbis;
+mod watch;
+mod xkb;
 
 use std::{
-    cmp, env, fs,
-    ops::Mul,
-    path::Path,
+    cell::RefCell,
+    cmp,
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
     process,
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::Context;
-use config::Config;
+use anyhow::{bail, Context};
+use config::{Button, Config, Latency};
 use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    StreamConfig,
+    traits::{DeviceTrait, HostTrait},
+    Host, StreamError,
 };
-use evdev::{EventType, InputEventKind, Key};
-use hound::WavReader;
+use evdev::{EventType, Key};
+use nix::sys::signal::{SigSet, Signal};
 
+#[cfg(feature = "tray")]
 use crate::systray::SystrayIcon;
+use crate::{
+    input::{Bindings, ButtonBinding, Inputs, Trigger},
+    lock::InstanceLock,
+    log::{debug, error, info, warn},
+    mixer::{Activity, Mixer, Play, SoftStart, Volume},
+    output::Output,
+    sound::{Clip, Sound, SoundSet},
+    status_file::StatusFile,
+    stream::StreamedSound,
+};
 
 static DEFAULT_WAV: &[u8] = include_bytes!("../assets/Windows Navigation Start.wav");
 
-#[derive(Clone)]
-struct Sound {
-    channels: u16,
-    sample_rate: u32,
-    samples: Vec<f32>,
+/// How long the output has to be silent before the audio stream is paused, with the default
+/// `latency = "balanced"`.
+///
+/// Keeping the stream open costs a bit of CPU even when nothing is playing, but pausing it after
+/// every click would be wasteful during bursts of clicks.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `idle_timeout_ms` that keeps the audio stream running forever.
+const NEVER_IDLE: u64 = u64::MAX;
+
+/// The peak level that sounds are normalized to, in dBFS.
+const NORMALIZE_PEAK_DB: f32 = -1.0;
+
+/// Sounds whose peak is below this level (-80 dBFS) are considered silent.
+const SILENCE: f32 = 1e-4;
+
+/// How long the first sound after the output stream starts is faded in for, with `soft_start`.
+const SOFT_START: Duration = Duration::from_millis(10);
+
+/// More playback errors than this within [`STREAM_ERROR_WINDOW`] mean that the stream is broken,
+/// even if the backend doesn't say that the device is gone.
+const MAX_STREAM_ERRORS: u32 = 5;
+
+/// The time window that [`MAX_STREAM_ERRORS`] applies to.
+const STREAM_ERROR_WINDOW: Duration = Duration::from_secs(1);
+
+/// How long to wait before the first attempt to reopen a lost audio device.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest time to wait between attempts to reopen a lost audio device.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often to check whether the default output device changed, while it is used.
+const DEFAULT_DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many frames the render thread mixes at once.
+const RENDER_FRAMES: usize = 128;
+
+/// How many samples the ring buffer between the render thread and the audio callback holds. This
+/// limits the device buffer size that can be kept filled.
+const RING_CAPACITY: usize = 1 << 15;
+
+/// How often `--play` checks whether the sound has finished.
+const PLAY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long `--play` waits after the sound has finished, for the output buffer to be played.
+const PLAY_DRAIN_TIME: Duration = Duration::from_millis(200);
+
+/// The options [`parse_args`] understands, with or without their arguments.
+const OPTIONS: &[&str] = &[
+    "--play",
+    "--profile",
+    "--list-devices",
+    "--help",
+    "-h",
+    "--version",
+    "-V",
+];
+
+const USAGE: &str = "\
+usage: clickd [<config.toml>...]
+       clickd --play [<config.toml>...]
+       clickd --profile <seconds> [<config.toml>...]
+       clickd --list-devices
+       clickd --help
+       clickd --version";
+
+const HELP: &str = "\
+Plays a click sound on every mouse button press.
+
+If no <config.toml> is given, $XDG_CONFIG_HOME/clickd/config.toml is used if it exists.
+Multiple config files are merged in order, with settings in later files taking precedence.
+
+options:
+  --play           play the configured sound once and exit
+  --profile        run for the given number of seconds, then print where time was spent
+  --list-devices   print the available audio output and input devices
+  --help           print this help
+  --version        print the version of clickd
+
+configuration keys (see config.example.toml for details):
+  devices          input devices to listen to, optionally with their own settings
+  ids              vendor:product IDs of input devices to listen to (default: all)
+  ignore_devices   names or vendor:product IDs of input devices to never listen to
+  audio            WAV file, or directory of WAV files, to play on button presses
+  audio_inline     base64-encoded WAV file to use instead of audio
+  audio_release    WAV file, or directory of WAV files, to play on button releases
+  release_volume   volume of audio_release, relative to volume (default: 1.0)
+  trigger          when to play button sounds: press, release or both (default: press)
+  audio_double     WAV file, or directory of WAV files, to play on double clicks
+  double_click_ms  longest time between the two presses of a double click (default: 0)
+  audio_first      WAV file, or directory of WAV files, to play on the first press after idle
+  idle_threshold_ms  time without presses after which audio_first plays (default: 0)
+  hold_ms          how long a button is held down before its hold sound plays (default: 500)
+  volume           playback volume, where 1.0 is the original volume (default: 1.0)
+  volume_db        playback volume in decibels, as an alternative to volume
+  buttons          list of buttons to click on, a table of per-button settings, all-mouse or all
+  keys             table of per-key settings, added to buttons
+  keysyms          like keys, but by the symbol the keyboard layout puts on each key
+  keyboard_layout  XKB layout that keysyms are looked up in, like de (default: XKB default)
+  default_key_sound  WAV file, or directory of WAV files, to play for all other keyboard keys
+  scroll           table of per-axis settings for scroll wheel clicks
+  volume_up        key that increases the volume
+  volume_down      key that decreases the volume
+  audio_host       audio backend to use, like ALSA or JACK (default: ALSA)
+  output_device    name of the audio output device to use, or a list of them
+  cooldown_ms      minimum time between two sounds from the same button
+  fade_ms          length of the fade at the end of each sound (default: 5)
+  resample_quality how sounds are resampled: linear or sinc (default: linear)
+  stream_threshold_ms  length above which sounds are streamed from disk
+  normalize        whether to scale every sound to the same peak level (default: false)
+  sound_weights    table of how often each file in a sound directory plays (default: 1)
+  pitch_variation  amount by which to randomly vary the pitch (default: 0.0)
+  tray             whether to show a tray icon (default: true)
+  tray_toggle_on   what toggles clicking in the tray: activate or menu-only (default: activate)
+  icon_enabled     PNG file to show in the tray while clicking is enabled
+  icon_disabled    PNG file to show in the tray while clicking is disabled
+  notifications    whether to show a notification when toggling (default: false)
+  status_file      true, or a path, to keep a JSON file with the current state (default: false)
+  trigger_fifo     path of a FIFO that plays a sound for every line written to it
+  buffer_frames    size of the audio buffer; smaller buffers reduce latency
+  limiter          whether to soft-clip overlapping sounds (default: true)
+  max_voices       how many sounds may play at the same time (default: 16)
+  latency          when to pause the audio stream: low, balanced or power-save (default: balanced)
+  soft_start       whether to fade in the first sound after the audio stream starts (default: true)
+  grab             whether to grab input devices exclusively (default: false)
+  repeat           whether to also click on key repeat events (default: false)
+  startup_sound    whether to click once after starting up (default: false)
+  mute_when_locked  whether to stay silent while the screen is locked (default: false)
+  watchdog_ms      warn when an input device stops responding for this long (default: off)
+  watchdog_reopen  whether the watchdog reopens devices that stopped responding (default: true)";
+
+/// The command-line arguments that configure the daemon.
+struct Args {
+    /// The config files that were passed, which may be none.
+    configs: Vec<PathBuf>,
+    /// How long to run for with `--profile`.
+    profile: Option<Duration>,
 }
 
-impl Sound {
-    fn new(wav: &[u8]) -> anyhow::Result<Self> {
-        let mut decoder = WavReader::new(wav)?;
-        let spec = decoder.spec();
-        let channels = spec.channels;
-        let sample_rate = spec.sample_rate;
-        let samples = match spec.sample_format {
-            hound::SampleFormat::Float => {
-                decoder.samples::<f32>().collect::<Result<Vec<_>, _>>()?
+/// Handles command-line flags, and returns the arguments that configure the daemon.
+fn parse_args() -> anyhow::Result<Args> {
+    let args = env::args_os().skip(1).collect::<Vec<_>>();
+    let paths = |args: &[OsString]| args.iter().map(PathBuf::from).collect::<Vec<_>>();
+    match &*args {
+        [flag, seconds, rest @ ..] if flag == "--profile" => {
+            let seconds = seconds
+                .to_str()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|s| s.is_finite() && *s > 0.0);
+            let Some(seconds) = seconds else {
+                eprintln!("invalid duration '{}'", args[1].to_string_lossy());
+                eprintln!("{USAGE}");
+                process::exit(1);
+            };
+            Ok(Args {
+                configs: paths(rest),
+                profile: Some(Duration::from_secs_f64(seconds)),
+            })
+        }
+        [flag] if flag == "--list-devices" => {
+            list_devices()?;
+            process::exit(0);
+        }
+        [flag, configs @ ..] if flag == "--play" => {
+            play(&paths(configs))?;
+            process::exit(0);
+        }
+        [flag] if flag == "--help" || flag == "-h" => {
+            println!("{USAGE}\n\n{HELP}");
+            process::exit(0);
+        }
+        [flag] if flag == "--version" || flag == "-V" => {
+            println!("clickd {}", env!("CARGO_PKG_VERSION"));
+            process::exit(0);
+        }
+        configs => {
+            if let Some(flag) = configs
+                .iter()
+                .find(|arg| arg.to_string_lossy().starts_with('-'))
+            {
+                // Known options with the wrong number of args only print the usage.
+                if !OPTIONS.iter().any(|option| flag == *option) {
+                    eprintln!("unknown option '{}'", flag.to_string_lossy());
+                }
+                eprintln!("{USAGE}");
+                process::exit(1);
             }
-            hound::SampleFormat::Int => {
-                let max = (1 << spec.bits_per_sample) as f32 * 0.5;
-                decoder
-                    .samples::<i32>()
-                    .map(|res| res.map(|i| i as f32 / max))
-                    .collect::<Result<Vec<_>, _>>()?
+            Ok(Args {
+                configs: paths(configs),
+                profile: None,
+            })
+        }
+    }
+}
+
+/// Plays the default sound of the configuration in `config_paths` once, and waits for it to
+/// finish.
+///
+/// This goes through the same decoding and output path as button presses, but doesn't need any
+/// input device.
+fn play(config_paths: &[PathBuf]) -> anyhow::Result<()> {
+    let config = load_config(config_paths)?;
+    let (control_sender, control_receiver) = mpsc::channel();
+    let mut audio = Audio::new(&config, control_sender)?;
+    audio.open()?;
+    audio.play_test()?;
+    while !audio.playback.activity.is_idle() {
+        if let Ok(Control::OutputLost) = control_receiver.recv_timeout(PLAY_POLL_INTERVAL) {
+            bail!("audio device failed while playing");
+        }
+    }
+    // The end of the sound may still be in the output buffer.
+    thread::sleep(PLAY_DRAIN_TIME);
+    Ok(())
+}
+
+/// Loads and merges the config files at `paths`, or the one in the default location if no paths
+/// are given.
+///
+/// If there's no config file in the default location, the default configuration is used.
+fn load_config(paths: &[PathBuf]) -> anyhow::Result<Config> {
+    let mut config: Config = match &*config_files(paths) {
+        [] => {
+            info!("no configuration file found; using the default configuration");
+            Config::default()
+        }
+        [path] => {
+            info!("loading configuration file '{}'", path.display());
+            let config = fs::read_to_string(path).with_context(|| path.display().to_string())?;
+            toml::from_str(&config)
+                .with_context(|| format!("invalid configuration file '{}'", path.display()))?
+        }
+        paths => {
+            let mut merged = toml::Table::new();
+            for path in paths {
+                info!("loading configuration file '{}'", path.display());
+                let config =
+                    fs::read_to_string(path).with_context(|| path.display().to_string())?;
+                // Parse each file on its own first, so that errors point at the right file.
+                let invalid = || format!("invalid configuration file '{}'", path.display());
+                toml::from_str::<Config>(&config).with_context(invalid)?;
+                config::merge(&mut merged, toml::from_str(&config).with_context(invalid)?);
             }
-        };
+            toml::Value::Table(merged)
+                .try_into()
+                .context("invalid merged configuration")?
+        }
+    };
 
-        Ok(Sound {
-            channels,
-            sample_rate,
-            samples,
-        })
+    config.resolve_keysyms()?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Returns the config files that [`load_config`] would load, in order.
+fn config_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    if !paths.is_empty() {
+        return paths.to_vec();
+    }
+    default_config_path()
+        .filter(|path| path.exists())
+        .into_iter()
+        .collect()
+}
+
+/// Returns the default config file location, `$XDG_CONFIG_HOME/clickd/config.toml`.
+fn default_config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("clickd").join("config.toml"))
+}
+
+/// Prints the audio output devices of all available hosts, and all input devices with keys or
+/// buttons.
+fn list_devices() -> anyhow::Result<()> {
+    let default_host = cpal::default_host().id();
+    for id in cpal::available_hosts() {
+        let default = if id == default_host { ", default" } else { "" };
+        println!("audio output devices ({} host{default}):", id.name());
+        match cpal::host_from_id(id) {
+            Ok(host) => list_output_devices(&host)?,
+            Err(e) => println!("  unavailable: {e}"),
+        }
     }
+
+    println!("input devices:");
+    for (path, device) in evdev::enumerate() {
+        if device.supported_events().contains(EventType::KEY) {
+            let id = device.input_id();
+            println!(
+                "  {}: {} ({:04x}:{:04x})",
+                path.display(),
+                device.name().unwrap_or("<unnamed>"),
+                id.vendor(),
+                id.product(),
+            );
+        }
+    }
+
+    Ok(())
 }
 
-/// :)
-impl Mul<f32> for Sound {
-    type Output = Self;
+/// Prints the audio output devices of `host`, along with their index and default format.
+fn list_output_devices(host: &Host) -> anyhow::Result<()> {
+    let default = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    for (i, device) in host.output_devices()?.enumerate() {
+        let name = device.name()?;
+        // Many ALSA devices can't be queried while another program uses them.
+        let format = match device.default_output_config() {
+            Ok(config) => format!(
+                "{} channel(s), {} Hz",
+                config.channels(),
+                config.sample_rate().0,
+            ),
+            Err(_) => "format unknown".into(),
+        };
+        let default = if Some(&name) == default.as_ref() {
+            ", default"
+        } else {
+            ""
+        };
+        println!("  #{i}: {name} ({format}{default})");
+    }
 
-    fn mul(self, rhs: f32) -> Self::Output {
+    Ok(())
+}
+
+/// Settings that affect how every sound file is loaded.
+struct LoadOptions<'a> {
+    /// Sounds longer than this are streamed from disk instead of being decoded into memory.
+    stream: Option<Duration>,
+    /// Whether to scale sounds so that their peak is at [`NORMALIZE_PEAK_DB`].
+    normalize: bool,
+    /// The `sound_weights` setting.
+    weights: &'a HashMap<String, f32>,
+    /// The names in `weights` that a file in a sound directory had.
+    matched_weights: &'a RefCell<HashSet<String>>,
+}
+
+impl<'a> LoadOptions<'a> {
+    fn new(config: &'a Config, matched_weights: &'a RefCell<HashSet<String>>) -> Self {
         Self {
-            channels: self.channels,
-            sample_rate: self.sample_rate,
-            samples: self.samples.into_iter().map(|f| f * rhs).collect(),
+            stream: config.stream_threshold(),
+            normalize: config.normalize(),
+            weights: config.sound_weights(),
+            matched_weights,
+        }
+    }
+
+    /// Applies the options that don't depend on how `clip` was loaded.
+    fn apply(&self, clip: Clip, name: &dyn fmt::Display) -> anyhow::Result<Clip> {
+        if !self.normalize {
+            return Ok(clip);
         }
+        let peak = clip.peak()?;
+        if peak < SILENCE {
+            warn!("not normalizing {name}, since it is silent");
+            return Ok(clip);
+        }
+        Ok(clip.scale(10f32.powf(NORMALIZE_PEAK_DB / 20.0) / peak))
     }
 }
 
-fn load_config() -> anyhow::Result<Config> {
-    let args = env::args_os().skip(1).collect::<Vec<_>>();
-    let config: Config = match &*args {
-        [] => Config::default(),
-        [config] => {
-            let config =
-                fs::read_to_string(config).with_context(|| config.to_string_lossy().to_string())?;
-            toml::from_str(&config)?
-        }
-        _ => {
-            // Incorrect number of args.
-            eprintln!("usage: clickd [<config.toml>]");
-            process::exit(1);
+/// Loads the sound file at `path`.
+fn load_sound(path: &Path, options: &LoadOptions) -> anyhow::Result<Clip> {
+    let name = format!("'{}'", path.display());
+    if let Some(threshold) = options.stream {
+        // If this fails, the file is not a WAV file or broken, which is reported properly below.
+        if let Ok(sound) = StreamedSound::open(path) {
+            if sound.duration() > threshold {
+                info!("streaming {name} from disk");
+                return options.apply(Clip::Streamed(Arc::new(sound)), &name);
+            }
         }
-    };
+    }
 
-    Ok(config)
+    let data = fs::read(path).with_context(|| path.display().to_string())?;
+    let sound = Sound::new(&data).with_context(|| format!("failed to decode {name}"))?;
+    options.apply(sound.into(), &name)
 }
 
-fn load_sound(path: &Path) -> anyhow::Result<Sound> {
-    let wav = fs::read(path).with_context(|| path.display().to_string())?;
-    Sound::new(&wav)
+/// Loads the sound file at `path`, or every sound file in it if `path` is a directory.
+///
+/// The sounds in a directory are picked according to their `sound_weights`, and files with a
+/// weight of 0 aren't loaded at all.
+fn load_sounds(path: &Path, options: &LoadOptions) -> anyhow::Result<SoundSet> {
+    if !path.is_dir() {
+        return Ok(SoundSet::new(vec![load_sound(path, options)?]));
+    }
+
+    let mut paths = fs::read_dir(path)
+        .with_context(|| path.display().to_string())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let mut sounds = Vec::new();
+    for path in paths.iter().filter(|path| !path.is_dir()) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if options.weights.contains_key(&*name) {
+            options
+                .matched_weights
+                .borrow_mut()
+                .insert(name.to_string());
+        }
+        let weight = options.weights.get(&*name).copied().unwrap_or(1.0);
+        if weight == 0.0 {
+            debug!("skipping '{}', since its weight is 0", path.display());
+            continue;
+        }
+        match load_sound(path, options) {
+            Ok(sound) => sounds.push((sound, weight)),
+            Err(e) => warn!("skipping {e:#}"),
+        }
+    }
+    if sounds.is_empty() {
+        bail!("no usable sound files found in '{}'", path.display());
+    }
+
+    info!("loaded {} sounds from '{}'", sounds.len(), path.display());
+    Ok(SoundSet::weighted(sounds))
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum State {
-    /// Initial state.
+/// Requests handled by the main thread.
+pub enum Control {
+    /// Shut down `clickd`.
+    Quit,
+    /// Reload the configuration file.
+    Reload,
+    /// An input device failed and was closed.
+    DeviceClosed(PathBuf),
+    /// An input device stopped responding, and the watchdog wants it reopened.
+    DeviceStalled(PathBuf),
+    /// An input device may have been plugged in, so the devices need to be scanned again.
+    Rescan,
+    /// A sound was triggered, so the audio stream must be running.
+    Wake,
+    /// The audio stream has been silent for the idle timeout and may be paused.
     Idle,
-    /// Set by the input listener threads when a sound should be played.
-    Trigger,
-    Playing,
-    /// Set by the audio callback when the sound has finished.
-    Done,
+    /// The audio stream failed, probably because the device was unplugged.
+    OutputLost,
+    /// Try to reopen the audio stream after it was lost.
+    RetryOutput,
+    /// Check whether the default output device changed.
+    CheckDefaultDevice,
+    /// `enabled`, the volume or the number of playback errors changed in the background, so the
+    /// tray icon and the status file need to be updated.
+    StateChanged,
+    /// Play the default sound.
+    PlayTest,
+    /// Play the sound bound to pressing a key, as if it was pressed on any device.
+    PlayKey(Key),
+}
+
+/// The state shared between the mixer and the rest of `clickd`.
+struct Playback {
+    volume: Arc<Volume>,
+    /// The `limiter` setting.
+    limiter: Arc<AtomicBool>,
+    /// The `max_voices` setting.
+    max_voices: Arc<AtomicUsize>,
+    /// How long the output has to be silent before the stream is paused, in milliseconds, or
+    /// [`NEVER_IDLE`]. This is derived from the `latency` setting.
+    idle_timeout_ms: Arc<AtomicU64>,
+    soft_start: Arc<SoftStart>,
+    activity: Arc<Activity>,
+    play_sender: Sender<Play>,
+    play_receiver: Arc<Mutex<Receiver<Play>>>,
+    control_sender: Sender<Control>,
+    /// The number of playback errors that the stream recovered from, since startup.
+    stream_errors: Arc<AtomicU64>,
+}
+
+/// The audio output, which is reopened when the audio device goes away.
+struct Audio {
+    playback: Playback,
+    /// The `audio_host` setting.
+    host: Option<String>,
+    /// The audio host selected by `host`.
+    cpal_host: Host,
+    /// The `output_device` setting.
+    devices: Vec<String>,
+    /// The `buffer_frames` setting.
+    buffer_frames: Option<u32>,
+    /// The `soft_start` setting.
+    soft_start: bool,
+    /// The default sound, whose format is preferred for the output stream.
+    sound: Clip,
+    /// The bindings in their original format, which is converted to the output format.
+    bindings: Bindings,
+    /// The output stream, unless it was lost and couldn't be reopened yet.
+    output: Option<Output>,
+    retry_delay: Duration,
+    /// Whether a `Control::RetryOutput` is on its way.
+    retry_pending: bool,
+}
+
+impl Audio {
+    /// Loads the sounds of the `config`, without opening the output stream yet.
+    fn new(config: &Config, control_sender: Sender<Control>) -> anyhow::Result<Self> {
+        let (play_sender, play_receiver) = mpsc::channel();
+        let playback = Playback {
+            volume: Arc::new(Volume::new(config.volume())),
+            limiter: Arc::new(AtomicBool::new(config.limiter())),
+            max_voices: Arc::new(AtomicUsize::new(config.max_voices())),
+            idle_timeout_ms: Arc::new(AtomicU64::new(idle_timeout_ms(config.latency()))),
+            soft_start: Arc::new(SoftStart::default()),
+            activity: Arc::new(Activity::default()),
+            play_sender,
+            play_receiver: Arc::new(Mutex::new(play_receiver)),
+            control_sender,
+            stream_errors: Arc::new(AtomicU64::new(0)),
+        };
+
+        let (sounds, bindings) = load_bindings(config)?;
+        Ok(Self {
+            playback,
+            host: config.audio_host().map(str::to_string),
+            cpal_host: output::host(config.audio_host()),
+            devices: config.output_devices().to_vec(),
+            buffer_frames: config.buffer_frames(),
+            soft_start: config.soft_start(),
+            sound: sounds.first().clone(),
+            bindings,
+            output: None,
+            retry_delay: MIN_RETRY_DELAY,
+            retry_pending: false,
+        })
+    }
+
+    /// (Re)opens the output stream, and returns the bindings converted to its format.
+    fn open(&mut self) -> anyhow::Result<Bindings> {
+        // Close the old stream first, in case the device can't be opened twice.
+        self.output = None;
+        let output = open_output(
+            &self.cpal_host,
+            &self.devices,
+            self.buffer_frames,
+            &self.sound,
+            &self.playback,
+        )?;
+        let bindings = self.bindings.convert(output.channels, output.sample_rate);
+        self.output = Some(output);
+        self.retry_delay = MIN_RETRY_DELAY;
+        self.update_soft_start();
+        self.playback.soft_start.trigger();
+        Ok(bindings)
+    }
+
+    /// Sets the length of the soft start fade for the current output format.
+    fn update_soft_start(&self) {
+        let samples = match &self.output {
+            Some(output) if self.soft_start => {
+                let frames = SOFT_START.as_secs_f64() * f64::from(output.sample_rate);
+                frames as usize * usize::from(output.channels)
+            }
+            _ => 0,
+        };
+        self.playback.soft_start.set_length(samples);
+    }
+
+    /// Closes the failed output stream, and schedules an attempt to reopen it.
+    fn lost(&mut self) {
+        self.output = None;
+        // Sounds triggered while there was no stream would all play at once when it's reopened.
+        for _ in self.playback.play_receiver.lock().unwrap().try_iter() {
+            self.playback.activity.finish();
+        }
+        self.schedule_retry();
+    }
+
+    fn schedule_retry(&mut self) {
+        if self.retry_pending {
+            return;
+        }
+        self.retry_pending = true;
+        info!("reopening audio device in {:?}", self.retry_delay);
+        let control_sender = self.playback.control_sender.clone();
+        let delay = self.retry_delay;
+        thread::spawn(move || {
+            thread::sleep(delay);
+            control_sender.send(Control::RetryOutput).ok();
+        });
+        self.retry_delay = cmp::min(self.retry_delay * 2, MAX_RETRY_DELAY);
+    }
+
+    /// Tries to reopen a lost output stream, and returns the bindings converted to its format.
+    fn retry(&mut self) -> Option<Bindings> {
+        self.retry_pending = false;
+        if self.output.is_some() {
+            // Already reopened by a reload.
+            return None;
+        }
+        match self.open() {
+            Ok(bindings) => Some(bindings),
+            Err(e) => {
+                warn!("failed to reopen audio device: {e:#}");
+                self.schedule_retry();
+                None
+            }
+        }
+    }
+
+    /// Switches to the new default output device if it changed, and returns the bindings
+    /// converted to its format.
+    ///
+    /// This only happens while the default device is used because `output_device` isn't set.
+    fn check_default_device(&mut self) -> Option<Bindings> {
+        let output = self.output.as_ref()?;
+        if !output.is_default || !self.devices.is_empty() {
+            return None;
+        }
+        let device = output::default_device_name(&self.cpal_host)?;
+        if device == output.device {
+            return None;
+        }
+        info!(
+            "default audio device changed from '{}' to '{device}'; switching to it",
+            output.device,
+        );
+        match self.open() {
+            Ok(bindings) => Some(bindings),
+            Err(e) => {
+                warn!("failed to switch audio device: {e:#}");
+                self.lost();
+                None
+            }
+        }
+    }
+
+    /// Plays the default sound, if the output stream is open.
+    fn play_test(&mut self) -> anyhow::Result<()> {
+        let Some(output) = &self.output else {
+            return Ok(());
+        };
+        let clip = self.sound.convert(
+            output.channels,
+            output.sample_rate,
+            self.bindings.fade,
+            self.bindings.resample_quality,
+        );
+        self.playback.activity.start();
+        self.playback.play_sender.send(clip.start(1.0)).unwrap();
+        self.resume()
+    }
+
+    fn resume(&mut self) -> anyhow::Result<()> {
+        match &mut self.output {
+            Some(output) => {
+                if output.is_paused() {
+                    self.playback.soft_start.trigger();
+                }
+                output.resume()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Pauses the audio stream, unless a sound was triggered since the stream went silent.
+    fn pause_if_idle(&mut self) -> anyhow::Result<()> {
+        let (Some(output), true) = (&mut self.output, self.playback.activity.is_idle()) else {
+            return Ok(());
+        };
+        output.pause()?;
+        // A sound may have been triggered right before the stream was paused, and its
+        // `Control::Wake` already handled.
+        if !self.playback.activity.is_idle() {
+            output.resume()?;
+        }
+        Ok(())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let config = load_config()?;
-    let buttons = match config.buttons() {
-        Some(iter) => iter.collect::<Vec<_>>(),
-        None => vec![Key::BTN_LEFT],
+    log::init();
+
+    // Block SIGHUP before any threads are spawned, so that it's only ever received by the thread
+    // waiting for it.
+    let mut sighup = SigSet::empty();
+    sighup.add(Signal::SIGHUP);
+    sighup.thread_block()?;
+
+    let Args { configs, profile } = parse_args()?;
+    let lock = InstanceLock::acquire()?;
+    let mut config = load_config(&configs)?;
+
+    let (control_sender, control_receiver) = mpsc::channel();
+    let mut audio = Audio::new(&config, control_sender.clone())?;
+    let bindings = audio.open()?;
+
+    let enabled = Arc::new(AtomicBool::new(true));
+    #[cfg(feature = "tray")]
+    let systray = if config.tray() {
+        Some(SystrayIcon::new(
+            enabled.clone(),
+            audio.playback.volume.clone(),
+            config.notifications(),
+            config.tray_toggle_on(),
+            control_sender.clone(),
+            config.icon_enabled(),
+            config.icon_disabled(),
+        )?)
+    } else {
+        None
     };
 
-    let sound = match config.audio_path() {
-        Some(path) => {
-            println!("opening audio file '{}'", path.display());
-            load_sound(path)?
+    let locked = Arc::new(AtomicBool::new(false));
+    let mut inputs = Inputs::new(
+        bindings,
+        enabled.clone(),
+        locked.clone(),
+        audio.playback.volume.clone(),
+        audio.playback.play_sender.clone(),
+        audio.playback.activity.clone(),
+        control_sender.clone(),
+    )?;
+    inputs.scan(&config);
+
+    if inputs.is_empty() {
+        // The device might just not be connected yet.
+        warn!("no matching input device found; waiting for matching devices");
+    }
+    if config.startup_sound() {
+        if let Err(e) = audio.play_test() {
+            error!("failed to play startup sound: {e}");
         }
-        None => Sound::new(DEFAULT_WAV)?,
+    }
+    let mut status_file = config.status_file().map(|path| {
+        StatusFile::new(
+            &path,
+            enabled.clone(),
+            audio.playback.volume.clone(),
+            audio.playback.stream_errors.clone(),
+        )
+    });
+    service::spawn(enabled, control_sender.clone());
+    if let Some(path) = config.trigger_fifo() {
+        fifo::spawn(path, control_sender.clone());
+    }
+    if config.mute_when_locked() {
+        session::spawn(locked.clone());
+    }
+    for path in config_files(&configs) {
+        watch::spawn_config(&path, control_sender.clone());
+    }
+    watch::spawn_devices(control_sender.clone());
+    {
+        let control_sender = control_sender.clone();
+        thread::spawn(move || loop {
+            thread::sleep(DEFAULT_DEVICE_POLL_INTERVAL);
+            if control_sender.send(Control::CheckDefaultDevice).is_err() {
+                break;
+            }
+        });
+    }
+    #[cfg(feature = "tray")]
+    update_tray(&systray, &inputs);
+    update_status_file(&mut status_file, &inputs);
+    systemd::notify(&format!("READY=1\n{}", status(&inputs)));
+
+    let profile = match profile {
+        Some(duration) => {
+            info!("profiling for {:.1} s", duration.as_secs_f64());
+            let control_sender = control_sender.clone();
+            thread::spawn(move || {
+                thread::sleep(duration);
+                control_sender.send(Control::Quit).ok();
+            });
+            Some(profile::Profile::start()?)
+        }
+        None => None,
     };
-    let sound = sound * config.volume();
 
-    let signal = Arc::new((Mutex::new(State::Idle), Condvar::new()));
+    thread::spawn(move || loop {
+        match sighup.wait() {
+            Ok(_) => {
+                control_sender.send(Control::Reload).ok();
+            }
+            Err(e) => error!("failed to wait for SIGHUP: {e}"),
+        }
+    });
 
-    let host = cpal::default_host();
-    let Some(device) = host.default_output_device() else {
-        eprintln!("no default audio device found");
-        process::exit(1);
-    };
-    println!("using audio device: {}", device.name()?);
-    let mut offset = 0;
-    let output = device.build_output_stream::<f32, _, _>(
-        &StreamConfig {
-            channels: sound.channels,
-            buffer_size: cpal::BufferSize::Default,
-            sample_rate: cpal::SampleRate(sound.sample_rate),
-        },
-        {
-            let signal = signal.clone();
-            move |data, _| {
-                let mut guard = signal.0.lock().unwrap();
-                match *guard {
-                    State::Playing => {
-                        let len = cmp::min(data.len(), sound.samples.len() - offset);
-
-                        data.copy_from_slice(&sound.samples[offset..len]);
-                        data[len..].fill(0.0);
-
-                        offset += len;
-                        offset = offset.max(sound.samples.len());
-
-                        if offset == sound.samples.len() {
-                            offset = 0;
-                            *guard = State::Done;
-                            signal.1.notify_one();
+    // `clickd` runs until it is told to quit; losing all input devices only makes it wait for new
+    // ones. `control_sender` is held by the background threads, so this never ends on its own.
+    for control in control_receiver {
+        match control {
+            Control::Quit => break,
+            Control::Reload => {
+                info!("reloading configuration");
+                systemd::notify("RELOADING=1");
+                match reload(&configs, &mut audio, &mut inputs) {
+                    Ok(new) => {
+                        config = new;
+                        if config.mute_when_locked() {
+                            session::spawn(locked.clone());
                         }
                     }
-                    _ => data.fill(0.0),
+                    Err(e) => error!("failed to reload configuration: {e:#}; keeping the old one"),
                 }
+                #[cfg(feature = "tray")]
+                update_tray(&systray, &inputs);
+                update_status_file(&mut status_file, &inputs);
+                systemd::notify(&format!("READY=1\n{}", status(&inputs)));
             }
-        },
-        |error| {
-            eprintln!("playback error: {}; exiting.", error);
-            process::exit(1);
-        },
-        None,
-    )?;
-    output.play()?;
+            Control::DeviceStalled(path) => {
+                inputs.reopen(&config, &path);
+                #[cfg(feature = "tray")]
+                update_tray(&systray, &inputs);
+                update_status_file(&mut status_file, &inputs);
+            }
+            Control::DeviceClosed(_) => {
+                if inputs.is_empty() {
+                    warn!("all input devices have been closed; waiting for matching devices");
+                }
+                #[cfg(feature = "tray")]
+                update_tray(&systray, &inputs);
+                update_status_file(&mut status_file, &inputs);
+                systemd::notify(&status(&inputs));
+            }
+            Control::Rescan => {
+                let before = inputs.len();
+                inputs.scan(&config);
+                if inputs.len() != before {
+                    #[cfg(feature = "tray")]
+                    update_tray(&systray, &inputs);
+                    update_status_file(&mut status_file, &inputs);
+                    systemd::notify(&status(&inputs));
+                }
+            }
+            Control::Wake => {
+                if let Err(e) = audio.resume() {
+                    error!("failed to resume audio stream: {e}");
+                }
+            }
+            Control::Idle => {
+                if let Err(e) = audio.pause_if_idle() {
+                    error!("failed to pause audio stream: {e}");
+                }
+            }
+            Control::OutputLost => audio.lost(),
+            Control::StateChanged => {
+                #[cfg(feature = "tray")]
+                if let Some(tray) = &systray {
+                    tray.refresh(audio.playback.stream_errors.load(Ordering::Relaxed));
+                }
+                update_status_file(&mut status_file, &inputs);
+            }
+            Control::PlayTest => {
+                if let Err(e) = audio.play_test() {
+                    error!("failed to play sound: {e}");
+                }
+            }
+            Control::PlayKey(key) => inputs.play_key(key),
+            Control::RetryOutput => {
+                if let Some(bindings) = audio.retry() {
+                    info!("audio device reopened");
+                    inputs.set_bindings(bindings);
+                }
+            }
+            Control::CheckDefaultDevice => {
+                if let Some(bindings) = audio.check_default_device() {
+                    inputs.set_bindings(bindings);
+                }
+            }
+        }
+    }
 
-    let systray = if config.tray() {
-        Some(SystrayIcon::new()?)
-    } else {
-        None
+    // The input thread is blocked waiting for events, so it is left to be torn down with the
+    // process.
+    info!("shutting down");
+    systemd::notify("STOPPING=1");
+    drop(audio);
+    drop(status_file);
+    #[cfg(feature = "tray")]
+    if let Some(tray) = systray {
+        tray.shutdown();
+    }
+    // Only allow another instance to start once the devices and the tray icon are released.
+    drop(lock);
+
+    if let Some(profile) = profile {
+        profile.report()?;
+    }
+
+    Ok(())
+}
+
+/// Shows the currently open input devices in the tray icon, if there is one.
+#[cfg(feature = "tray")]
+fn update_tray(systray: &Option<SystrayIcon>, inputs: &Inputs) {
+    if let Some(tray) = systray {
+        tray.set_devices(inputs.devices());
+    }
+}
+
+/// Writes the current state to the status file, if there is one.
+fn update_status_file(status_file: &mut Option<StatusFile>, inputs: &Inputs) {
+    if let Some(status_file) = status_file {
+        status_file.update(inputs.len());
+    }
+}
+
+/// Returns the `STATUS=` line to report to the service manager.
+fn status(inputs: &Inputs) -> String {
+    format!("STATUS=monitoring {} input device(s)", inputs.len())
+}
+
+/// Loads the default sounds and the sounds for all button and scroll wheel bindings.
+///
+/// The sounds are left in their original format, which is later converted to the output format.
+fn load_bindings(config: &Config) -> anyhow::Result<(SoundSet, Bindings)> {
+    let matched_weights = RefCell::default();
+    let options = &LoadOptions::new(config, &matched_weights);
+    let sounds = match (config.audio_path(), config.audio_inline()) {
+        (Some(path), _) => {
+            info!("opening audio file '{}'", path.display());
+            load_sounds(path, options)?
+        }
+        (None, Some(inline)) => {
+            let data = base64::decode(inline).context("failed to decode `audio_inline`")?;
+            let sound = Sound::new(&data).context("failed to decode `audio_inline`")?;
+            SoundSet::new(vec![options.apply(sound.into(), &"the inline sound")?])
+        }
+        (None, None) => SoundSet::new(vec![
+            options.apply(Sound::new(DEFAULT_WAV)?.into(), &"the default sound")?
+        ]),
     };
 
-    let mut threads = Vec::new();
+    let buttons = load_button_bindings(config.buttons(), &sounds, options)?;
+    let mut device_buttons = HashMap::new();
+    for (name, device) in config.device_overrides() {
+        let device_sounds = match device.audio_path() {
+            Some(path) => {
+                info!("opening audio file '{}' for '{name}'", path.display());
+                load_sounds(path, options)?
+            }
+            None => sounds.clone(),
+        };
+        let buttons = match device.buttons() {
+            Some(buttons) => load_button_bindings(Some(buttons), &device_sounds, options)?,
+            None => load_button_bindings(config.buttons(), &device_sounds, options)?,
+        };
+        device_buttons.insert(name.to_string(), buttons);
+    }
 
-    for (path, mut device) in evdev::enumerate() {
-        if !device.supported_events().contains(EventType::KEY) {
-            continue;
+    let release = match config.audio_release_path() {
+        Some(path) => {
+            info!("opening release audio file '{}'", path.display());
+            let volume = config.release_volume();
+            Some(load_sounds(path, options)?.map(|clip| clip.scale(volume)))
         }
+        None => None,
+    };
 
-        let keys = device.supported_keys().unwrap();
-        if !buttons.iter().any(|key| keys.contains(*key)) {
-            continue;
+    let default_key = match config.default_key_sound_path() {
+        Some(path) => {
+            info!("opening default key audio file '{}'", path.display());
+            Some(load_sounds(path, options)?)
+        }
+        None => None,
+    };
+
+    let double = match config.audio_double_path() {
+        Some(path) if !config.double_click().is_zero() => {
+            info!("opening double click audio file '{}'", path.display());
+            Some(load_sounds(path, options)?)
         }
+        _ => None,
+    };
 
-        if let Some(mut devs) = config.devices() {
-            if !devs.any(|name| Some(name) == device.name()) {
-                continue;
-            }
+    let first = match config.audio_first_path() {
+        Some(path) if !config.idle_threshold().is_zero() => {
+            info!("opening first click audio file '{}'", path.display());
+            Some(load_sounds(path, options)?)
         }
+        _ => None,
+    };
 
-        println!(
-            "opening input device {}: {}",
-            path.display(),
-            device.name().unwrap(),
-        );
+    let mut scroll = Vec::new();
+    for (axis, settings) in config.scroll() {
+        let sounds = match settings.audio_path() {
+            Some(path) => {
+                info!("opening audio file '{}' for {:?}", path.display(), axis);
+                load_sounds(path, options)?
+            }
+            None => sounds.clone(),
+        };
+        let volume = settings.volume().unwrap_or(1.0) * settings.press_volume();
+        scroll.push((axis, sounds.map(|clip| clip.scale(volume))));
+    }
 
-        let signal = signal.clone();
-        let buttons = buttons.clone();
-        threads.push(thread::spawn(move || loop {
-            let events = match device.fetch_events() {
-                Ok(events) => events,
-                Err(e) => {
-                    eprintln!("ERROR: {e}; closing {}", path.display());
-                    return;
-                }
-            };
+    // Names are easy to get wrong, and then the weight silently does nothing.
+    let mut unmatched = config
+        .sound_weights()
+        .keys()
+        .filter(|name| !matched_weights.borrow().contains(*name))
+        .collect::<Vec<_>>();
+    unmatched.sort();
+    for name in unmatched {
+        warn!(
+            "`sound_weights` has a weight for '{name}', but no sound directory contains that file"
+        );
+    }
 
-            for event in events {
-                if event.value() != 1 {
-                    // Only react to key down events.
-                    continue;
-                }
+    Ok((
+        sounds,
+        Bindings {
+            buttons,
+            device_buttons,
+            release,
+            default_key,
+            double,
+            double_click: config.double_click(),
+            first,
+            idle_threshold: config.idle_threshold(),
+            hold: config.hold(),
+            trigger: config.trigger(),
+            scroll,
+            volume_up: config.volume_up(),
+            volume_down: config.volume_down(),
+            fade: config.fade(),
+            resample_quality: config.resample_quality(),
+            cooldown: config.cooldown(),
+            pitch_variation: config.pitch_variation(),
+            repeat: config.repeat(),
+            mute_when_locked: config.mute_when_locked(),
+            watchdog: config.watchdog(),
+            watchdog_reopen: config.watchdog_reopen(),
+        },
+    ))
+}
 
-                if let InputEventKind::Key(key) = event.kind() {
-                    if buttons.contains(&key) {
-                        let mut guard = signal.0.lock().unwrap();
-                        if *guard == State::Idle {
-                            *guard = State::Trigger;
-                        }
-                        signal.1.notify_one();
-                        drop(guard);
+/// Loads the sounds for `buttons`, or for `BTN_LEFT` if there's no `buttons` setting.
+///
+/// Buttons without their own audio file play `sounds` when pressed.
+fn load_button_bindings<'a>(
+    buttons: Option<impl Iterator<Item = (Key, &'a Button)>>,
+    sounds: &SoundSet,
+    options: &LoadOptions,
+) -> anyhow::Result<HashMap<Key, ButtonBinding>> {
+    let mut bindings = HashMap::new();
+    match buttons {
+        Some(buttons) => {
+            for (key, button) in buttons {
+                let mut button_sounds = HashMap::new();
+                let press = match button.audio_path() {
+                    Some(path) => {
+                        info!("opening audio file '{}' for {:?}", path.display(), key);
+                        load_sounds(path, options)?
                     }
+                    None => sounds.clone(),
+                };
+                button_sounds.insert(Trigger::Press, press);
+                if let Some(path) = button.release_path() {
+                    info!(
+                        "opening release audio file '{}' for {:?}",
+                        path.display(),
+                        key
+                    );
+                    button_sounds.insert(Trigger::Release, load_sounds(path, options)?);
+                }
+                if let Some(path) = button.hold_path() {
+                    info!("opening hold audio file '{}' for {:?}", path.display(), key);
+                    button_sounds.insert(Trigger::Hold, load_sounds(path, options)?);
+                }
+                if let Some(path) = button.loop_path() {
+                    info!("opening loop audio file '{}' for {:?}", path.display(), key);
+                    // Only sounds in memory can be looped.
+                    let options = &LoadOptions {
+                        stream: None,
+                        ..*options
+                    };
+                    button_sounds.insert(Trigger::Loop, load_sounds(path, options)?);
                 }
-            }
 
-            // rate-limit the polling loop a bit (although the persistent ALSA connection seems to
-            // use a bunch of CPU, not this)
-            thread::sleep(Duration::from_millis(50));
-        }));
+                let binding = ButtonBinding {
+                    sounds: button_sounds
+                        .into_iter()
+                        .map(|(trigger, sounds)| {
+                            let volume = trigger_volume(button, trigger);
+                            (trigger, sounds.map(|clip| clip.scale(volume)))
+                        })
+                        .collect(),
+                    modifiers: button.modifiers(),
+                    cooldown: button.cooldown(),
+                    pan: button.pan(),
+                    release_volume: trigger_volume(button, Trigger::Release),
+                };
+                bindings.insert(key, binding);
+            }
+        }
+        None => {
+            let binding = ButtonBinding {
+                sounds: HashMap::from([(Trigger::Press, sounds.clone())]),
+                modifiers: Vec::new(),
+                cooldown: None,
+                pan: 0.0,
+                release_volume: 1.0,
+            };
+            bindings.insert(Key::BTN_LEFT, binding);
+        }
     }
+    Ok(bindings)
+}
 
-    if threads.is_empty() {
-        eprintln!("no matching input device found!");
-        process::exit(1);
+/// Returns the volume of `button`'s sound for `trigger`, relative to the master volume.
+///
+/// The master volume is applied on top of this while mixing, so it can be changed at runtime.
+fn trigger_volume(button: &Button, trigger: Trigger) -> f32 {
+    button.volume().unwrap_or(1.0)
+        * match trigger {
+            Trigger::Press => button.press_volume(),
+            Trigger::Release => button.release_volume(),
+            Trigger::Hold => button.hold_volume(),
+            Trigger::Loop => button.loop_volume(),
+        }
+}
+
+/// Returns the idle timeout for the `latency` setting, in milliseconds.
+fn idle_timeout_ms(latency: Latency) -> u64 {
+    match latency {
+        Latency::Low => NEVER_IDLE,
+        Latency::Balanced => IDLE_TIMEOUT.as_millis() as u64,
+        Latency::PowerSave => 0,
     }
+}
 
-    loop {
-        let mut guard = signal.1.wait(signal.0.lock().unwrap()).unwrap();
-        match *guard {
-            State::Idle | State::Playing => {}
-            State::Trigger => {
-                if let Some(tray) = &systray {
-                    if !tray.service_enabled() {
-                        *guard = State::Idle;
-                        continue;
-                    }
+/// Opens the first of the output `devices` that works (or the default one), preferring the format
+/// of the default `sound`.
+///
+/// Sounds are mixed on a render thread, in chunks of [`RENDER_FRAMES`], into a ring buffer that
+/// the audio callback only copies from. This keeps voice management, the limiter and resampling
+/// off the real-time thread. Whenever the callback has read a buffer, the render thread refills
+/// the ring buffer to the largest buffer the callback asked for, plus one chunk, and goes back to
+/// sleep.
+///
+/// The latency budget: a click is mixed behind the samples that are already buffered, so it is
+/// delayed by up to one device buffer plus one chunk (about 8 ms for 256 frames at 48 kHz), on
+/// top of the device buffer itself. Mixing a chunk has to take less than the time the callback
+/// leaves until the next buffer, which `--profile` shows.
+fn open_output(
+    host: &Host,
+    devices: &[String],
+    buffer_frames: Option<u32>,
+    sound: &Clip,
+    playback: &Playback,
+) -> anyhow::Result<Output> {
+    let mut mixer = Mixer::new(
+        playback.volume.clone(),
+        playback.activity.clone(),
+        playback.limiter.clone(),
+        playback.max_voices.clone(),
+        playback.soft_start.clone(),
+    );
+    let play_receiver = playback.play_receiver.clone();
+    let idle_timeout_ms = playback.idle_timeout_ms.clone();
+    let control_sender = playback.control_sender.clone();
+    let error_sender = playback.control_sender.clone();
+    let stream_errors = playback.stream_errors.clone();
+    let mut lost = false;
+    // When the current window of `STREAM_ERROR_WINDOW` started, and the errors in it.
+    let mut error_window = (Instant::now(), 0);
+
+    let (mut producer, mut consumer) = ring::channel(RING_CAPACITY);
+    // The render thread learns the stream's channel count once it is open, and exits if it can't
+    // be opened.
+    let (channels_sender, channels_receiver) = mpsc::channel::<u16>();
+    let render = thread::spawn(move || {
+        let Ok(channels) = channels_receiver.recv() else {
+            return;
+        };
+        let mut chunk = vec![0.0; RENDER_FRAMES * channels as usize];
+        // When the mixer went silent, and whether `Control::Idle` has been sent since.
+        let mut silent_since = None;
+        let mut idle_sent = false;
+        while !producer.is_abandoned() {
+            for play in play_receiver.lock().unwrap().try_iter() {
+                mixer.play(play);
+            }
+            let target = (producer.demand() + chunk.len()).min(producer.capacity() - chunk.len());
+            while producer.len() < target {
+                profile::RENDER.time(|| mixer.render(&mut chunk));
+                producer.push(&chunk);
+
+                let timeout = idle_timeout_ms.load(Ordering::Relaxed);
+                if !mixer.is_silent() {
+                    silent_since = None;
+                    idle_sent = false;
+                } else if !idle_sent
+                    && timeout != NEVER_IDLE
+                    && silent_since.get_or_insert_with(Instant::now).elapsed()
+                        >= Duration::from_millis(timeout)
+                {
+                    // The main thread might already be gone during shutdown.
+                    control_sender.send(Control::Idle).ok();
+                    idle_sent = true;
                 }
+            }
+            // The callback wakes this thread up after reading, or when the stream is dropped.
+            thread::park();
+        }
+    });
+    consumer.wake(render.thread().clone());
 
-                *guard = State::Playing;
+    let output = Output::open(
+        host,
+        devices,
+        sound.channels(),
+        sound.sample_rate(),
+        buffer_frames,
+        move |data| {
+            profile::AUDIO_CALLBACK.time(|| {
+                // Before the render thread caught up, which should only happen right after the
+                // stream was opened, the rest is silent.
+                let count = consumer.pop(data);
+                data[count..].fill(0.0);
+            });
+        },
+        move |error| {
+            // The stream is rebuilt from scratch, so only report the first error once it's lost.
+            if lost {
+                return;
             }
-            State::Done => {
-                *guard = State::Idle;
+            let now = Instant::now();
+            if now.duration_since(error_window.0) > STREAM_ERROR_WINDOW {
+                error_window = (now, 0);
             }
+            error_window.1 += 1;
+
+            // Backends recover from some errors on their own (and cpal's ALSA backend doesn't
+            // even report underruns), so only a lost device or a burst of errors is fatal.
+            let fatal = matches!(error, StreamError::DeviceNotAvailable)
+                || error_window.1 > MAX_STREAM_ERRORS;
+            if fatal {
+                error!("playback error: {error}");
+                lost = true;
+                error_sender.send(Control::OutputLost).ok();
+            } else {
+                let count = stream_errors.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("playback error: {error}; continuing ({count} so far)");
+                error_sender.send(Control::StateChanged).ok();
+            }
+        },
+    )?;
+    channels_sender.send(output.channels).ok();
+    Ok(output)
+}
+
+/// Reloads the configuration files and applies them, returning the new configuration.
+///
+/// The output stream is only reopened if a different output device was configured, or if it was
+/// lost.
+fn reload(
+    config_paths: &[PathBuf],
+    audio: &mut Audio,
+    inputs: &mut Inputs,
+) -> anyhow::Result<Config> {
+    let config = load_config(config_paths)?;
+    let (sounds, bindings) = load_bindings(&config)?;
+    let host = config.audio_host().map(str::to_string);
+    let devices = config.output_devices().to_vec();
+    let reopen = host != audio.host
+        || devices != audio.devices
+        || config.buffer_frames() != audio.buffer_frames;
+
+    if host != audio.host {
+        audio.cpal_host = output::host(host.as_deref());
+    }
+    audio.host = host;
+    audio.devices = devices;
+    audio.buffer_frames = config.buffer_frames();
+    audio.sound = sounds.first().clone();
+    audio.bindings = bindings;
+    let bindings = match &audio.output {
+        Some(output) if !reopen => audio.bindings.convert(output.channels, output.sample_rate),
+        _ => match audio.open() {
+            Ok(bindings) => bindings,
+            Err(e) => {
+                // The old stream is gone, so keep trying to open the new device.
+                audio.lost();
+                return Err(e);
+            }
+        },
+    };
+
+    audio.playback.volume.set(config.volume());
+    audio
+        .playback
+        .limiter
+        .store(config.limiter(), Ordering::Relaxed);
+    audio
+        .playback
+        .max_voices
+        .store(config.max_voices(), Ordering::Relaxed);
+    audio
+        .playback
+        .idle_timeout_ms
+        .store(idle_timeout_ms(config.latency()), Ordering::Relaxed);
+    if config.latency() == Latency::Low {
+        // The stream may have been paused with the old setting.
+        if let Err(e) = audio.resume() {
+            error!("failed to resume audio stream: {e}");
         }
     }
+    audio.soft_start = config.soft_start();
+    audio.update_soft_start();
+    inputs.set_bindings(bindings);
+    inputs.scan(&config);
+    if inputs.is_empty() {
+        warn!("no matching input device found; waiting for matching devices");
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a config file called `name` in a temporary directory.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("clickd-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn unknown_settings_are_named() {
+        let path = config_file("typo.toml", "volumne = 0.5\n");
+        let Err(e) = load_config(std::slice::from_ref(&path)) else {
+            panic!("the typo was accepted");
+        };
+        let message = format!("{e:#}");
+        assert!(message.contains("unknown field `volumne`"), "{message}");
+        assert!(message.contains(&*path.to_string_lossy()), "{message}");
+
+        // With several files, the error names the file with the typo.
+        let good = config_file("good.toml", "volume = 0.5\n");
+        let bad = config_file("bad.toml", "[buttons.BTN_LEFT]\nvolumne = 0.5\n");
+        let Err(e) = load_config(&[good, bad.clone()]) else {
+            panic!("the typo was accepted");
+        };
+        let message = format!("{e:#}");
+        assert!(message.contains("unknown field `volumne`"), "{message}");
+        assert!(message.contains(&*bad.to_string_lossy()), "{message}");
+    }
+
+    #[test]
+    fn broken_sounds_are_named() {
+        // An Ogg file that ends in the middle of its first page.
+        let path = config_file("broken.ogg", "OggS\0\x02");
+        let config = Config::default();
+        let matched_weights = RefCell::default();
+        let Err(e) = load_sound(&path, &LoadOptions::new(&config, &matched_weights)) else {
+            panic!("the broken file was loaded");
+        };
+        let message = format!("{e:#}");
+        assert!(message.contains(&*path.to_string_lossy()), "{message}");
+        assert!(message.contains("Ogg file is truncated"), "{message}");
+    }
+
+    #[test]
+    fn trigger_volumes_combine() {
+        let button: Button = toml::from_str(
+            "volume = 0.5\npress_volume = 0.5\nrelease_volume = 2.0\nhold_volume = 0.0",
+        )
+        .unwrap();
+        assert_eq!(trigger_volume(&button, Trigger::Press), 0.25);
+        assert_eq!(trigger_volume(&button, Trigger::Release), 1.0);
+        assert_eq!(trigger_volume(&button, Trigger::Hold), 0.0);
+        assert_eq!(trigger_volume(&button, Trigger::Loop), 0.5);
+
+        // Without a `volume`, the per-trigger volumes apply on their own.
+        let button: Button = toml::from_str("press_volume = 0.8").unwrap();
+        assert_eq!(trigger_volume(&button, Trigger::Press), 0.8);
+        assert_eq!(trigger_volume(&button, Trigger::Release), 1.0);
+    }
 }
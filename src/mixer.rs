@@ -0,0 +1,524 @@
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{sound::Sound, stream::Stream};
+
+/// The level above which the limiter starts compressing the output.
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+/// How long a looping sound is faded out for once it is stopped.
+const LOOP_FADE: Duration = Duration::from_millis(50);
+
+/// A request to play a sound.
+pub enum Play {
+    Memory {
+        sound: Arc<Sound>,
+        /// Playback speed, which also shifts the pitch. `1.0` plays the sound unchanged.
+        speed: f32,
+    },
+    /// A sound that is being decoded from disk, already at the desired speed.
+    Stream(Stream),
+    /// A sound that repeats until `stop` is set, and then fades out.
+    Loop {
+        sound: Arc<Sound>,
+        stop: Arc<AtomicBool>,
+    },
+}
+
+/// A sound that is currently being played.
+enum Voice {
+    Memory {
+        sound: Arc<Sound>,
+        speed: f32,
+        /// Position of the next frame to output, which may fall between two frames of the sound.
+        pos: f64,
+    },
+    Stream(Stream),
+    Loop {
+        sound: Arc<Sound>,
+        stop: Arc<AtomicBool>,
+        pos: usize,
+        /// The frames left in the fade out, and its length, once `stop` was set.
+        fade: Option<(usize, usize)>,
+    },
+}
+
+impl Voice {
+    fn is_finished(&self) -> bool {
+        match self {
+            Voice::Memory { sound, pos, .. } => *pos as usize >= frames(sound),
+            Voice::Stream(stream) => stream.is_finished(),
+            Voice::Loop { fade, .. } => matches!(fade, Some((0, _))),
+        }
+    }
+
+    /// Adds the next samples of the voice to `data`, scaled by `volume`.
+    fn mix(&mut self, data: &mut [f32], volume: f32) {
+        let (sound, speed, pos) = match self {
+            Voice::Memory { sound, speed, pos } => (sound, speed, pos),
+            Voice::Stream(stream) => return stream.mix(data, volume),
+            Voice::Loop {
+                sound,
+                stop,
+                pos,
+                fade,
+            } => return mix_loop(sound, stop, pos, fade, data, volume),
+        };
+
+        let channels = usize::from(sound.channels);
+        let frames = frames(sound);
+        for out in data.chunks_exact_mut(channels) {
+            let index = *pos as usize;
+            if index >= frames {
+                break;
+            }
+
+            // Interpolate between neighboring frames when not playing at the original speed.
+            let next = cmp::min(index + 1, frames - 1);
+            let t = (*pos - index as f64) as f32;
+            let a = &sound.samples[index * channels..][..channels];
+            let b = &sound.samples[next * channels..][..channels];
+            for ((out, a), b) in out.iter_mut().zip(a).zip(b) {
+                *out += (a + (b - a) * t) * volume;
+            }
+
+            *pos += f64::from(*speed);
+        }
+    }
+}
+
+fn frames(sound: &Sound) -> usize {
+    sound.samples.len() / usize::from(sound.channels)
+}
+
+/// Adds the next samples of a looping `sound` to `data`, fading it out once `stop` is set.
+fn mix_loop(
+    sound: &Sound,
+    stop: &AtomicBool,
+    pos: &mut usize,
+    fade: &mut Option<(usize, usize)>,
+    data: &mut [f32],
+    volume: f32,
+) {
+    let channels = usize::from(sound.channels);
+    let frames = frames(sound);
+    if fade.is_none() && stop.load(Ordering::Relaxed) {
+        let len = (LOOP_FADE.as_secs_f64() * f64::from(sound.sample_rate)) as usize;
+        *fade = Some((len, len.max(1)));
+    }
+    if frames == 0 {
+        *fade = Some((0, 1));
+        return;
+    }
+
+    for out in data.chunks_exact_mut(channels) {
+        let gain = match fade {
+            Some((0, _)) => break,
+            Some((left, len)) => {
+                *left -= 1;
+                *left as f32 / *len as f32
+            }
+            None => 1.0,
+        };
+        let frame = &sound.samples[*pos * channels..][..channels];
+        for (out, sample) in out.iter_mut().zip(frame) {
+            *out += sample * volume * gain;
+        }
+        *pos = (*pos + 1) % frames;
+    }
+}
+
+/// A volume level that can be adjusted while sounds are playing.
+pub struct Volume(AtomicU32);
+
+impl Volume {
+    pub fn new(volume: f32) -> Self {
+        Self(AtomicU32::new(volume.to_bits()))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, volume: f32) {
+        self.0.store(volume.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Counts the sounds that have been triggered, but haven't finished playing yet.
+///
+/// This lets the output stream be paused while nothing is playing, without missing sounds that
+/// are about to be played.
+#[derive(Default)]
+pub struct Activity(AtomicUsize);
+
+impl Activity {
+    /// Must be called before a [`Play`] request is sent to the mixer.
+    pub fn start(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Must be called when a sound has finished playing, or its [`Play`] request was discarded.
+    pub fn finish(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Returns whether all triggered sounds have finished playing.
+    pub fn is_idle(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Fades in the first sound that is played after the output stream (re)starts.
+#[derive(Default)]
+pub struct SoftStart {
+    /// Whether the next sound should be faded in.
+    pending: AtomicBool,
+    /// The length of the fade, in samples (not frames). Zero disables it.
+    samples: AtomicUsize,
+}
+
+impl SoftStart {
+    /// Sets the length of the fade in samples, which is 0 to disable it.
+    pub fn set_length(&self, samples: usize) {
+        self.samples.store(samples, Ordering::Relaxed);
+    }
+
+    /// Fades in the next sound that is played.
+    pub fn trigger(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Mixes any number of overlapping sounds into the output stream.
+pub struct Mixer {
+    voices: Vec<Voice>,
+    volume: Arc<Volume>,
+    activity: Arc<Activity>,
+    limiter: Arc<AtomicBool>,
+    max_voices: Arc<AtomicUsize>,
+    soft_start: Arc<SoftStart>,
+    /// The position in the soft start fade, and its length, while it's in progress.
+    ramp: Option<(usize, usize)>,
+}
+
+impl Mixer {
+    /// Creates a mixer that scales its output by the (master) `volume`.
+    ///
+    /// `activity` is updated whenever a voice finishes playing or is dropped. While `limiter` is
+    /// `true`, loud output is compressed smoothly instead of being clipped. At most `max_voices`
+    /// sounds play at the same time. Whenever `soft_start` is triggered, the output fades in
+    /// when the next sound starts.
+    pub fn new(
+        volume: Arc<Volume>,
+        activity: Arc<Activity>,
+        limiter: Arc<AtomicBool>,
+        max_voices: Arc<AtomicUsize>,
+        soft_start: Arc<SoftStart>,
+    ) -> Self {
+        Self {
+            voices: Vec::new(),
+            volume,
+            activity,
+            limiter,
+            max_voices,
+            soft_start,
+            ramp: None,
+        }
+    }
+
+    /// Starts playing a sound on a new voice.
+    ///
+    /// If `max_voices` sounds are already playing, the oldest ones are stopped to make room.
+    pub fn play(&mut self, play: Play) {
+        if self.soft_start.pending.swap(false, Ordering::Relaxed) {
+            let samples = self.soft_start.samples.load(Ordering::Relaxed);
+            if samples > 0 {
+                self.ramp = Some((0, samples));
+            }
+        }
+
+        let max_voices = self.max_voices.load(Ordering::Relaxed).max(1);
+        if self.voices.len() >= max_voices {
+            let stolen = self.voices.len() + 1 - max_voices;
+            for _ in self.voices.drain(..stolen) {
+                self.activity.finish();
+            }
+        }
+        self.voices.push(match play {
+            Play::Memory { sound, speed } => Voice::Memory {
+                sound,
+                speed,
+                pos: 0.0,
+            },
+            Play::Stream(stream) => Voice::Stream(stream),
+            Play::Loop { sound, stop } => Voice::Loop {
+                sound,
+                stop,
+                pos: 0,
+                fade: None,
+            },
+        });
+    }
+
+    /// Returns whether no voices are currently playing.
+    pub fn is_silent(&self) -> bool {
+        self.voices.is_empty()
+    }
+
+    /// Fills `data` with the sum of all active voices, and removes voices that have finished.
+    ///
+    /// `data` must have the same number of channels as all played sounds.
+    pub fn render(&mut self, data: &mut [f32]) {
+        data.fill(0.0);
+
+        let volume = self.volume.get();
+        for voice in &mut self.voices {
+            voice.mix(data, volume);
+        }
+        self.voices.retain(|voice| {
+            let finished = voice.is_finished();
+            if finished {
+                self.activity.finish();
+            }
+            !finished
+        });
+
+        if let Some((pos, len)) = &mut self.ramp {
+            // The gain changes per sample, which is close enough to per frame for a short fade.
+            for out in data.iter_mut() {
+                if *pos == *len {
+                    break;
+                }
+                *out *= *pos as f32 / *len as f32;
+                *pos += 1;
+            }
+            if *pos == *len {
+                self.ramp = None;
+            }
+        }
+
+        // Summing voices can exceed the valid sample range, which would wrap around or distort
+        // badly depending on the backend.
+        if self.limiter.load(Ordering::Relaxed) {
+            for out in data {
+                *out = limit(*out);
+            }
+        } else {
+            for out in data {
+                *out = out.clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+/// Soft-clips a sample, so that its magnitude never exceeds 1.0.
+///
+/// Samples below [`LIMITER_THRESHOLD`] are unchanged. Above it, the remaining headroom is
+/// approached with a `tanh` curve, which joins the unchanged part without a kink. Very loud
+/// samples end up at exactly 1.0, since the curve gets closer to it than `f32` can resolve.
+fn limit(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= LIMITER_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    let limited =
+        LIMITER_THRESHOLD + headroom * ((magnitude - LIMITER_THRESHOLD) / headroom).tanh();
+    limited.copysign(sample)
+}
+
+impl Drop for Mixer {
+    fn drop(&mut self) {
+        // The voices will never finish playing when the stream is closed.
+        for _ in &self.voices {
+            self.activity.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::{constant, Clip};
+
+    fn mixer(limiter: bool) -> Mixer {
+        mixer_with(
+            Arc::new(Volume::new(1.0)),
+            limiter,
+            Arc::new(SoftStart::default()),
+        )
+    }
+
+    fn mixer_with(volume: Arc<Volume>, limiter: bool, soft_start: Arc<SoftStart>) -> Mixer {
+        Mixer::new(
+            volume,
+            Arc::new(Activity::default()),
+            Arc::new(AtomicBool::new(limiter)),
+            Arc::new(AtomicUsize::new(16)),
+            soft_start,
+        )
+    }
+
+    fn full_scale() -> Arc<Sound> {
+        Arc::new(Sound {
+            channels: 2,
+            sample_rate: 48000,
+            samples: [1.0, -1.0].repeat(64),
+        })
+    }
+
+    #[test]
+    fn overlapping_full_scale_voices_stay_in_range() {
+        for limiter in [true, false] {
+            let mut mixer = mixer(limiter);
+            for speed in [1.0, 1.0, 1.0, 0.5, 0.75, 1.0, 1.0, 1.0] {
+                mixer.play(Play::Memory {
+                    sound: full_scale(),
+                    speed,
+                });
+            }
+            let mut data = [0.0; 64];
+            mixer.render(&mut data);
+            assert!(
+                data.iter().all(|sample| sample.abs() <= 1.0),
+                "limiter: {limiter}, output: {data:?}"
+            );
+            // Eight voices at full scale are far above the threshold, so they end up at the top.
+            assert!(data.iter().all(|sample| sample.abs() > LIMITER_THRESHOLD));
+        }
+    }
+
+    #[test]
+    fn master_volume_scales_button_volume() {
+        let volume = Arc::new(Volume::new(0.5));
+        let mut mixer = mixer_with(volume.clone(), false, Arc::new(SoftStart::default()));
+        // A button with `volume = 0.5` and `press_volume = 0.5`.
+        let clip = Clip::from((*full_scale()).clone()).scale(0.25);
+        mixer.play(clip.start(1.0));
+        let mut data = [0.0; 8];
+        mixer.render(&mut data);
+        assert!(data.iter().all(|sample| sample.abs() == 0.125), "{data:?}");
+
+        // Changing the master volume applies to sounds that are already playing.
+        volume.set(2.0);
+        mixer.render(&mut data);
+        assert!(data.iter().all(|sample| sample.abs() == 0.5), "{data:?}");
+    }
+
+    /// Returns a stereo sound with `samples` samples that all differ, and stay below the limiter
+    /// threshold.
+    fn ramp(samples: usize) -> Arc<Sound> {
+        Arc::new(Sound {
+            channels: 2,
+            sample_rate: 48000,
+            samples: (0..samples)
+                .map(|i| i as f32 / samples as f32 * 0.5)
+                .collect(),
+        })
+    }
+
+    /// Renders `buffers` buffers of `len` samples each, and returns them joined together.
+    fn render_buffers(mixer: &mut Mixer, buffers: usize, len: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        let mut data = vec![0.0; len];
+        for _ in 0..buffers {
+            mixer.render(&mut data);
+            output.extend_from_slice(&data);
+        }
+        output
+    }
+
+    #[test]
+    fn short_sound_across_buffers() {
+        let sound = ramp(40);
+        let mut mixer = mixer(false);
+        mixer.play(Play::Memory {
+            sound: sound.clone(),
+            speed: 1.0,
+        });
+        // The sound ends in the middle of the third buffer.
+        let output = render_buffers(&mut mixer, 4, 16);
+        assert_eq!(output[..40], sound.samples[..]);
+        assert!(output[40..].iter().all(|&sample| sample == 0.0));
+        assert!(mixer.is_silent());
+    }
+
+    #[test]
+    fn long_sound_plays_to_the_end() {
+        // 3.5 buffers of 32 samples.
+        let sound = ramp(112);
+        let mut mixer = mixer(false);
+        mixer.play(Play::Memory {
+            sound: sound.clone(),
+            speed: 1.0,
+        });
+        let output = render_buffers(&mut mixer, 3, 32);
+        assert_eq!(output, sound.samples[..96]);
+        assert!(!mixer.is_silent());
+
+        let mut data = [1.0; 32];
+        mixer.render(&mut data);
+        assert_eq!(data[..16], sound.samples[96..]);
+        assert!(data[16..].iter().all(|&sample| sample == 0.0));
+        assert!(mixer.is_silent());
+    }
+
+    #[test]
+    fn soft_start_ramps_first_sound() {
+        let soft_start = Arc::new(SoftStart::default());
+        soft_start.set_length(8);
+        let mut mixer = mixer_with(Arc::new(Volume::new(1.0)), false, soft_start.clone());
+        let play = || constant(0.5).first().start(1.0);
+
+        // The stream was just opened.
+        soft_start.trigger();
+        mixer.play(play());
+        // The ramp continues across buffers.
+        let output = render_buffers(&mut mixer, 3, 4);
+        let expected = (0..12)
+            .map(|i| 0.5 * cmp::min(i, 8) as f32 / 8.0)
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+        render_buffers(&mut mixer, 13, 4);
+        assert!(mixer.is_silent());
+
+        // Later sounds play at full volume right away.
+        mixer.play(play());
+        assert_eq!(render_buffers(&mut mixer, 1, 4), [0.5; 4]);
+        render_buffers(&mut mixer, 15, 4);
+
+        // The stream was resumed after a pause.
+        soft_start.trigger();
+        mixer.play(play());
+        assert_eq!(render_buffers(&mut mixer, 2, 4), expected[..8]);
+
+        // Without a length, nothing is faded in.
+        render_buffers(&mut mixer, 14, 4);
+        soft_start.set_length(0);
+        soft_start.trigger();
+        mixer.play(play());
+        assert_eq!(render_buffers(&mut mixer, 1, 4), [0.5; 4]);
+    }
+
+    #[test]
+    fn limit_is_bounded_and_monotonic() {
+        let mut last = 0.0;
+        for i in 0..=10_000 {
+            let sample = i as f32 * 0.01;
+            let limited = limit(sample);
+            assert!(limited <= 1.0, "limit({sample}) = {limited}");
+            assert!(limited >= last, "limit({sample}) = {limited} < {last}");
+            assert_eq!(limit(-sample), -limited);
+            last = limited;
+        }
+        assert_eq!(limit(0.5), 0.5);
+        assert_eq!(limit(f32::MAX), 1.0);
+    }
+}
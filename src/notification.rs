@@ -0,0 +1,55 @@
+//! Desktop notifications, sent via the `org.freedesktop.Notifications` D-Bus service.
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::Duration,
+};
+
+use dbus::{arg::PropMap, blocking::Connection};
+
+use crate::log::warn;
+
+/// How long notifications stay on screen, in milliseconds.
+const EXPIRE_TIMEOUT: i32 = 2000;
+
+/// The ID of the last notification we've shown, so that it can be replaced by the next one
+/// instead of piling up.
+static LAST_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Shows a notification with the given `summary` in the background.
+///
+/// Failures are only logged, since there might not be a notification daemon running.
+pub fn show(summary: &str) {
+    let summary = summary.to_string();
+    thread::spawn(move || {
+        if let Err(e) = try_show(&summary) {
+            warn!("failed to show notification: {e}");
+        }
+    });
+}
+
+fn try_show(summary: &str) -> Result<(), dbus::Error> {
+    let conn = Connection::new_session()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        Duration::from_secs(5),
+    );
+    let (id,): (u32,) = proxy.method_call(
+        "org.freedesktop.Notifications",
+        "Notify",
+        (
+            "clickd",
+            LAST_ID.load(Ordering::Relaxed),
+            "",
+            summary,
+            "",
+            Vec::<String>::new(),
+            PropMap::new(),
+            EXPIRE_TIMEOUT,
+        ),
+    )?;
+    LAST_ID.store(id, Ordering::Relaxed);
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! Reading the packets of an Ogg file.
+//!
+//! An Ogg file is a sequence of pages, which each start with `OggS` and a header that lists the
+//! sizes of the segments in the page. A packet is split into segments of 255 bytes and a final
+//! shorter one, so a packet can span several pages. A file can interleave the pages of several
+//! logical streams, which are told apart by their serial number.
+
+use anyhow::{bail, Context};
+
+const TRUNCATED: &str = "Ogg file is truncated";
+
+const HEADER_LEN: usize = 27;
+
+/// The page continues a packet from the previous page.
+const CONTINUED: u8 = 0x01;
+/// The page is the first one of a logical stream.
+const BEGINNING: u8 = 0x02;
+/// The page is the last one of a logical stream.
+const END: u8 = 0x04;
+
+/// The packets of the first logical stream in an Ogg file.
+pub struct Stream {
+    pub packets: Vec<Vec<u8>>,
+    /// The granule position of the last page that has one. For Vorbis, this is the number of
+    /// frames in the stream.
+    pub granule: Option<u64>,
+}
+
+/// Splits the first logical stream in an Ogg file into packets.
+pub fn read(data: &[u8]) -> anyhow::Result<Stream> {
+    let mut rest = data;
+    let mut serial = None;
+    let mut page_number = 0;
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut granule = None;
+    while !rest.is_empty() {
+        let header = rest.get(..HEADER_LEN).context(TRUNCATED)?;
+        if &header[..4] != b"OggS" {
+            bail!("Ogg page {page_number} doesn't start with `OggS`");
+        }
+        if header[4] != 0 {
+            bail!("Ogg version {} is not supported", header[4]);
+        }
+        let flags = header[5];
+        let page_granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let checksum = u32::from_le_bytes(header[22..26].try_into().unwrap());
+        let lacing = rest
+            .get(HEADER_LEN..HEADER_LEN + usize::from(header[26]))
+            .context(TRUNCATED)?;
+        let body_start = HEADER_LEN + lacing.len();
+        let body_len = lacing.iter().map(|&len| usize::from(len)).sum::<usize>();
+        let page = rest.get(..body_start + body_len).context(TRUNCATED)?;
+        rest = &rest[page.len()..];
+
+        match serial {
+            None if flags & BEGINNING == 0 => bail!("Ogg file doesn't start a stream"),
+            None => serial = Some(page_serial),
+            // Pages of other streams (like a video track) are skipped.
+            Some(serial) if serial != page_serial => continue,
+            Some(_) => {}
+        }
+        // The checksum is computed with its own field set to 0.
+        let computed = crc(crc(crc(0, &page[..22]), &[0; 4]), &page[26..]);
+        if computed != checksum {
+            bail!("Ogg page {page_number} is corrupt (its checksum doesn't match)");
+        }
+        if (flags & CONTINUED != 0) == packet.is_empty() {
+            bail!("Ogg page {page_number} doesn't continue the previous page's packet");
+        }
+
+        let mut body = &page[body_start..];
+        for &len in lacing {
+            let (segment, next) = body.split_at(usize::from(len));
+            packet.extend_from_slice(segment);
+            body = next;
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+        // A granule position of -1 means that no packet ends on the page.
+        if page_granule != u64::MAX {
+            granule = Some(page_granule);
+        }
+        page_number += 1;
+        if flags & END != 0 {
+            break;
+        }
+    }
+    if !packet.is_empty() {
+        bail!(TRUNCATED);
+    }
+
+    Ok(Stream { packets, granule })
+}
+
+/// The lookup table of the CRC-32 that Ogg uses, with the polynomial 0x04C11DB7, no reflection,
+/// no initial value and no final XOR.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues computing the checksum `crc` over `data`.
+fn crc(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        crc << 8 ^ CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+/// Writes Ogg pages, for building test files.
+#[cfg(test)]
+pub struct Writer {
+    pub data: Vec<u8>,
+    serial: u32,
+    sequence: u32,
+    /// The most segments to put on a page, to test packets that span pages.
+    pub max_segments: usize,
+}
+
+#[cfg(test)]
+impl Writer {
+    pub fn new(serial: u32) -> Self {
+        Self {
+            data: Vec::new(),
+            serial,
+            sequence: 0,
+            max_segments: 255,
+        }
+    }
+
+    /// Writes `packets` on as many pages as needed. The last page gets the granule position
+    /// `granule` and, if `end` is set, ends the stream.
+    pub fn packets(&mut self, packets: &[Vec<u8>], granule: u64, end: bool) {
+        let mut segments = Vec::new();
+        for packet in packets {
+            let mut rest = &packet[..];
+            loop {
+                let len = rest.len().min(255);
+                segments.push(&rest[..len]);
+                rest = &rest[len..];
+                if len < 255 {
+                    break;
+                }
+            }
+        }
+        let mut continued = false;
+        let pages = segments.chunks(self.max_segments).collect::<Vec<_>>();
+        for (i, page) in pages.iter().enumerate() {
+            let last = i == pages.len() - 1;
+            let mut flags = 0;
+            if continued {
+                flags |= CONTINUED;
+            }
+            if self.sequence == 0 {
+                flags |= BEGINNING;
+            }
+            if last && end {
+                flags |= END;
+            }
+            let page_granule = if last { granule } else { u64::MAX };
+            let start = self.data.len();
+            self.data.extend_from_slice(b"OggS");
+            self.data.extend_from_slice(&[0, flags]);
+            self.data.extend_from_slice(&page_granule.to_le_bytes());
+            self.data.extend_from_slice(&self.serial.to_le_bytes());
+            self.data.extend_from_slice(&self.sequence.to_le_bytes());
+            self.data.extend_from_slice(&[0; 4]);
+            self.data.push(page.len() as u8);
+            self.data
+                .extend(page.iter().map(|segment| segment.len() as u8));
+            for segment in page.iter() {
+                self.data.extend_from_slice(segment);
+            }
+            let checksum = crc(0, &self.data[start..]);
+            self.data[start + 22..start + 26].copy_from_slice(&checksum.to_le_bytes());
+            self.sequence += 1;
+            continued = page.last().unwrap().len() == 255;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc(0, b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn packets_across_pages() {
+        let packets = vec![vec![1; 10], vec![2; 600], Vec::new(), vec![3; 255]];
+        let mut writer = Writer::new(7);
+        writer.max_segments = 2;
+        writer.packets(&packets[..1], 0, false);
+        writer.packets(&packets[1..], 1234, true);
+        // Pages after the end of the stream are ignored.
+        let mut other = Writer::new(7);
+        other.packets(&[vec![4; 3]], 0, true);
+        writer.data.extend_from_slice(&other.data);
+
+        let stream = read(&writer.data).unwrap();
+        assert_eq!(stream.packets, packets);
+        assert_eq!(stream.granule, Some(1234));
+    }
+
+    #[test]
+    fn other_streams_are_skipped() {
+        let mut first = Writer::new(1);
+        first.packets(&[vec![1; 3]], 0, false);
+        let mut second = Writer::new(2);
+        second.packets(&[vec![2; 3]], 0, false);
+        first.data.extend_from_slice(&second.data);
+        first.packets(&[vec![3; 3]], 5, true);
+
+        let stream = read(&first.data).unwrap();
+        assert_eq!(stream.packets, [vec![1; 3], vec![3; 3]]);
+    }
+
+    #[test]
+    fn broken_files() {
+        let mut writer = Writer::new(1);
+        writer.max_segments = 1;
+        writer.packets(&[vec![1; 300]], 0, true);
+        let data = writer.data;
+
+        let error = read(&data[..data.len() - 1]).err().unwrap();
+        assert_eq!(error.to_string(), TRUNCATED);
+        // Without the second page, the packet is never finished.
+        let first_page = HEADER_LEN + 1 + 255;
+        let error = read(&data[..first_page]).err().unwrap();
+        assert_eq!(error.to_string(), TRUNCATED);
+
+        let mut corrupt = data.clone();
+        corrupt[first_page + HEADER_LEN + 2] ^= 1;
+        let error = read(&corrupt).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Ogg page 1 is corrupt (its checksum doesn't match)"
+        );
+
+        let error = read(&data[first_page..]).err().unwrap();
+        assert_eq!(error.to_string(), "Ogg file doesn't start a stream");
+    }
+}
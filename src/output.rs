@@ -0,0 +1,430 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Device, FromSample, Host, SampleFormat, SizedSample, Stream, StreamConfig,
+    StreamError, SupportedBufferSize,
+};
+
+use crate::log::{info, warn};
+
+type Render = Arc<Mutex<dyn FnMut(&mut [f32]) + Send>>;
+type OnError = Arc<Mutex<dyn FnMut(StreamError) + Send>>;
+
+/// An audio output stream, along with the format it was opened with.
+pub struct Output {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// The name of the device the stream plays on.
+    pub device: String,
+    /// Whether the stream was opened on the default device, because none of the `devices` passed
+    /// to [`Output::open`] could be opened.
+    pub is_default: bool,
+    stream: Stream,
+    paused: bool,
+}
+
+impl Output {
+    /// Opens an output stream on the first of the `devices` of `host` that can be opened, or on the
+    /// default device if none of them can.
+    ///
+    /// `channels` and `sample_rate` are used for the stream if the device supports them, and so is
+    /// a buffer of `buffer_frames` frames, if given. `render` is called to fill each buffer of
+    /// interleaved `f32` samples (which are converted to the device's sample format), and
+    /// `on_error` when playback fails.
+    pub fn open(
+        host: &Host,
+        devices: &[String],
+        channels: u16,
+        sample_rate: u32,
+        buffer_frames: Option<u32>,
+        render: impl FnMut(&mut [f32]) + Send + 'static,
+        on_error: impl FnMut(StreamError) + Send + 'static,
+    ) -> anyhow::Result<Self> {
+        // Each attempt to open a device needs its own callbacks.
+        let render: Render = Arc::new(Mutex::new(render));
+        let on_error: OnError = Arc::new(Mutex::new(on_error));
+
+        for name in devices {
+            let res = find_output_device(host, name).and_then(|device| {
+                Self::open_device(
+                    device,
+                    channels,
+                    sample_rate,
+                    buffer_frames,
+                    render.clone(),
+                    on_error.clone(),
+                )
+            });
+            match res {
+                Ok(output) => return Ok(output),
+                Err(e) => warn!("{e:#}"),
+            }
+        }
+
+        let Some(device) = host.default_output_device() else {
+            bail!("no default audio device found");
+        };
+        let mut output = Self::open_device(
+            device,
+            channels,
+            sample_rate,
+            buffer_frames,
+            render,
+            on_error,
+        )?;
+        output.is_default = true;
+        Ok(output)
+    }
+
+    fn open_device(
+        device: Device,
+        channels: u16,
+        sample_rate: u32,
+        buffer_frames: Option<u32>,
+        render: Render,
+        on_error: OnError,
+    ) -> anyhow::Result<Self> {
+        let name = device.name()?;
+        let res = Self::build(
+            &device,
+            channels,
+            sample_rate,
+            buffer_frames,
+            render,
+            on_error,
+        );
+        match res {
+            Ok(mut output) => {
+                info!("using audio device: {name}");
+                output.device = name;
+                Ok(output)
+            }
+            Err(e) => Err(e.context(format!("failed to open audio device '{name}'"))),
+        }
+    }
+
+    fn build(
+        device: &Device,
+        channels: u16,
+        sample_rate: u32,
+        buffer_frames: Option<u32>,
+        render: Render,
+        on_error: OnError,
+    ) -> anyhow::Result<Self> {
+        let preferred = channels;
+        let channels = output_channels(device, preferred)?;
+        if channels != preferred {
+            info!(
+                "audio device does not support {} channel(s); converting to {} channel(s)",
+                preferred, channels,
+            );
+        }
+        let preferred = sample_rate;
+        let sample_rate = output_sample_rate(device, channels, preferred)?;
+        if sample_rate != preferred {
+            info!(
+                "audio device does not support {} Hz; resampling to {} Hz",
+                preferred, sample_rate,
+            );
+        }
+
+        let buffer_size = match buffer_frames {
+            Some(frames) => {
+                let frames = output_buffer_size(device, channels, sample_rate, frames)?;
+                info!(
+                    "using a buffer of {} frames, which adds {:.1} ms of latency",
+                    frames,
+                    f64::from(frames) * 1000.0 / f64::from(sample_rate),
+                );
+                BufferSize::Fixed(frames)
+            }
+            None => BufferSize::Default,
+        };
+
+        let sample_format = output_sample_format(device, channels, sample_rate)?;
+        if sample_format != SampleFormat::F32 {
+            info!("audio device does not support f32 samples; converting to {sample_format}");
+        }
+
+        let config = StreamConfig {
+            channels,
+            buffer_size,
+            sample_rate: cpal::SampleRate(sample_rate),
+        };
+        let stream = match sample_format {
+            SampleFormat::I8 => build_stream::<i8>(device, &config, render, on_error)?,
+            SampleFormat::I16 => build_stream::<i16>(device, &config, render, on_error)?,
+            SampleFormat::I32 => build_stream::<i32>(device, &config, render, on_error)?,
+            SampleFormat::I64 => build_stream::<i64>(device, &config, render, on_error)?,
+            SampleFormat::U8 => build_stream::<u8>(device, &config, render, on_error)?,
+            SampleFormat::U16 => build_stream::<u16>(device, &config, render, on_error)?,
+            SampleFormat::U32 => build_stream::<u32>(device, &config, render, on_error)?,
+            SampleFormat::U64 => build_stream::<u64>(device, &config, render, on_error)?,
+            SampleFormat::F32 => build_stream::<f32>(device, &config, render, on_error)?,
+            SampleFormat::F64 => build_stream::<f64>(device, &config, render, on_error)?,
+            format => bail!("unsupported sample format {format}"),
+        };
+        stream.play()?;
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            device: String::new(),
+            is_default: false,
+            stream,
+            paused: false,
+        })
+    }
+
+    /// Pauses the stream, which stops `render` from being called.
+    pub fn pause(&mut self) -> anyhow::Result<()> {
+        if !self.paused {
+            self.stream.pause()?;
+            self.paused = true;
+        }
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Resumes the stream if it was paused.
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        if self.paused {
+            self.stream.play()?;
+            self.paused = false;
+        }
+        Ok(())
+    }
+}
+
+/// How many frames are rendered at once when the device picks the buffer size.
+const DEFAULT_RENDER_FRAMES: usize = 1024;
+
+/// Builds an output stream with samples of type `T`.
+///
+/// `render` always produces `f32` samples, which are converted to `T` if necessary. It renders into
+/// a buffer of fixed size, so that the callback never allocates, and is called several times if the
+/// device asks for more samples than fit into it.
+fn build_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    render: Render,
+    on_error: OnError,
+) -> anyhow::Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let frames = match config.buffer_size {
+        BufferSize::Fixed(frames) => frames as usize,
+        BufferSize::Default => DEFAULT_RENDER_FRAMES,
+    };
+    let mut buffer = vec![0.0; frames * config.channels as usize];
+    let stream = device.build_output_stream::<T, _, _>(
+        config,
+        move |data, _| {
+            let mut render = render.lock().unwrap();
+            for data in data.chunks_mut(buffer.len()) {
+                let buffer = &mut buffer[..data.len()];
+                render(buffer);
+                for (out, sample) in data.iter_mut().zip(&*buffer) {
+                    *out = T::from_sample(*sample);
+                }
+            }
+        },
+        move |error| on_error.lock().unwrap()(error),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Returns the audio host called `name` (like `ALSA` or `JACK`), or the default one.
+///
+/// Hosts that aren't available fall back to the default host, with a warning.
+pub fn host(name: Option<&str>) -> Host {
+    let Some(name) = name else {
+        return cpal::default_host();
+    };
+    let available = cpal::available_hosts();
+    match available
+        .iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+    {
+        Some(&id) => match cpal::host_from_id(id) {
+            Ok(host) => return host,
+            Err(e) => warn!(
+                "failed to use audio host '{}': {e}; using the default host",
+                id.name()
+            ),
+        },
+        None => {
+            let names = available.iter().map(|id| id.name()).collect::<Vec<_>>();
+            warn!(
+                "audio host '{name}' is not available (available: {}); using the default host",
+                names.join(", "),
+            );
+        }
+    }
+    cpal::default_host()
+}
+
+/// Returns the name of the current default output device of `host`, if there is one.
+pub fn default_device_name(host: &Host) -> Option<String> {
+    host.default_output_device()?.name().ok()
+}
+
+/// Finds the output device called `name`.
+///
+/// If no device has exactly that name, a device whose name contains `name` is used instead.
+fn find_output_device(host: &Host, name: &str) -> anyhow::Result<Device> {
+    let mut devices = Vec::new();
+    for device in host.output_devices()? {
+        devices.push((device.name()?, device));
+    }
+
+    let exact = devices.iter().position(|(dev_name, _)| dev_name == name);
+    // `#2` selects the device with that index in `clickd --list-devices`.
+    let index = || {
+        let index = name.strip_prefix('#')?.parse().ok()?;
+        (index < devices.len()).then_some(index)
+    };
+    let partial = || {
+        let mut matches = devices
+            .iter()
+            .enumerate()
+            .filter(|(_, (dev_name, _))| dev_name.contains(name))
+            .map(|(i, _)| i);
+        let first = matches.next()?;
+        let others = matches.map(|i| &*devices[i].0).collect::<Vec<_>>();
+        if !others.is_empty() {
+            warn!(
+                "'{}' matches several audio output devices; using '{}' instead of '{}'",
+                name,
+                devices[first].0,
+                others.join("', '"),
+            );
+        }
+        Some(first)
+    };
+    match exact.or_else(index).or_else(partial) {
+        Some(i) => Ok(devices.swap_remove(i).1),
+        None => {
+            let names = devices
+                .iter()
+                .enumerate()
+                .map(|(i, (name, _))| format!("- #{i}: {name}"))
+                .collect::<Vec<_>>();
+            bail!(
+                "no audio output device matches '{}'; available devices:\n{}",
+                name,
+                names.join("\n"),
+            );
+        }
+    }
+}
+
+/// Picks the number of channels to open the output stream with.
+///
+/// This is `preferred` if the device supports it. Otherwise, it's the highest channel count below
+/// `preferred` that the device supports, so that sounds are down-mixed as little as possible, or
+/// the device's default channel count if there is none.
+fn output_channels(device: &Device, preferred: u16) -> anyhow::Result<u16> {
+    let supported = device
+        .supported_output_configs()?
+        .map(|config| config.channels())
+        .filter(|channels| *channels <= preferred)
+        .max();
+    match supported {
+        Some(channels) => Ok(channels),
+        None => Ok(device.default_output_config()?.channels()),
+    }
+}
+
+/// Picks the buffer size to open the output stream with.
+///
+/// This is `preferred`, clamped to the range of buffer sizes the device supports.
+fn output_buffer_size(
+    device: &Device,
+    channels: u16,
+    sample_rate: u32,
+    preferred: u32,
+) -> anyhow::Result<u32> {
+    let config = device.supported_output_configs()?.find(|config| {
+        config.channels() == channels
+            && (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate)
+    });
+    let Some(SupportedBufferSize::Range { min, max }) = config.as_ref().map(|c| c.buffer_size())
+    else {
+        // The device doesn't know, so try the preferred size.
+        return Ok(preferred);
+    };
+
+    let frames = preferred.clamp(*min, *max);
+    if frames != preferred {
+        warn!(
+            "audio device does not support a buffer of {} frames; using {} frames",
+            preferred, frames,
+        );
+    }
+    Ok(frames)
+}
+
+/// Picks the sample format to open the output stream with.
+///
+/// This is `f32` if the device supports it, and the format of the device's default config (or of
+/// any supported config) otherwise.
+fn output_sample_format(
+    device: &Device,
+    channels: u16,
+    sample_rate: u32,
+) -> anyhow::Result<SampleFormat> {
+    let formats = device
+        .supported_output_configs()?
+        .filter(|config| {
+            config.channels() == channels
+                && (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate)
+        })
+        .map(|config| config.sample_format())
+        .collect::<Vec<_>>();
+    if formats.is_empty() || formats.contains(&SampleFormat::F32) {
+        // If no config matches, let stream creation report the error.
+        return Ok(SampleFormat::F32);
+    }
+
+    let default = device.default_output_config()?.sample_format();
+    if formats.contains(&default) {
+        Ok(default)
+    } else {
+        Ok(formats[0])
+    }
+}
+
+/// Picks the sample rate to open the output stream with.
+///
+/// This is `preferred` if the device supports it, and the device's default sample rate otherwise.
+fn output_sample_rate(device: &Device, channels: u16, preferred: u32) -> anyhow::Result<u32> {
+    let configs = device
+        .supported_output_configs()?
+        .filter(|config| config.channels() == channels)
+        .collect::<Vec<_>>();
+    let supports = |rate: u32| {
+        configs
+            .iter()
+            .any(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&rate))
+    };
+
+    if configs.is_empty() || supports(preferred) {
+        // If no config matches the channel count, let stream creation report the error.
+        return Ok(preferred);
+    }
+
+    let default = device.default_output_config()?.sample_rate().0;
+    if supports(default) {
+        Ok(default)
+    } else {
+        Ok(configs[0].max_sample_rate().0)
+    }
+}
@@ -0,0 +1,106 @@
+//! Timing counters for `--profile`, which show where `clickd` spends its time.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use nix::time::{clock_gettime, ClockId};
+
+/// Whether the counters are running. Timing is skipped entirely otherwise.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Time spent in the audio output callback.
+pub static AUDIO_CALLBACK: Counter = Counter::new();
+
+/// Time spent mixing sounds on the render thread.
+pub static RENDER: Counter = Counter::new();
+
+/// Time spent reading and handling input events.
+pub static INPUT: Counter = Counter::new();
+
+/// Accumulates the time spent in some piece of code, and how often it ran.
+pub struct Counter {
+    nanos: AtomicU64,
+    calls: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self {
+            nanos: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `f`, adding the time it takes to the counter if profiling is enabled.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    fn report(&self, name: &str, elapsed: Duration) {
+        let total = Duration::from_nanos(self.nanos.load(Ordering::Relaxed));
+        let calls = self.calls.load(Ordering::Relaxed);
+        let average = u32::try_from(calls)
+            .ok()
+            .and_then(|calls| total.checked_div(calls))
+            .unwrap_or_default();
+        println!(
+            "  {name:<16} {:>10.3} ms ({:.3}%), {calls} calls, {:.1} µs per call",
+            total.as_secs_f64() * 1000.0,
+            percent(total, elapsed),
+            average.as_secs_f64() * 1_000_000.0,
+        );
+    }
+}
+
+/// Data collected while profiling.
+pub struct Profile {
+    start: Instant,
+    cpu_start: Duration,
+}
+
+impl Profile {
+    /// Starts the counters.
+    pub fn start() -> anyhow::Result<Self> {
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(Self {
+            start: Instant::now(),
+            cpu_start: cpu_time()?,
+        })
+    }
+
+    /// Prints how much time was spent where since the profile was started.
+    pub fn report(&self) -> anyhow::Result<()> {
+        let elapsed = self.start.elapsed();
+        let cpu = cpu_time()?.saturating_sub(self.cpu_start);
+        println!("profiled for {:.1} s:", elapsed.as_secs_f64());
+        println!(
+            "  {:<16} {:>10.3} ms ({:.3}%)",
+            "CPU time",
+            cpu.as_secs_f64() * 1000.0,
+            percent(cpu, elapsed),
+        );
+        AUDIO_CALLBACK.report("audio callback", elapsed);
+        RENDER.report("mixing", elapsed);
+        INPUT.report("input handling", elapsed);
+        Ok(())
+    }
+}
+
+/// Returns the CPU time used by all threads of the process so far.
+fn cpu_time() -> anyhow::Result<Duration> {
+    Ok(clock_gettime(ClockId::CLOCK_PROCESS_CPUTIME_ID)?.into())
+}
+
+fn percent(part: Duration, total: Duration) -> f64 {
+    part.as_secs_f64() / total.as_secs_f64() * 100.0
+}
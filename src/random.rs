@@ -0,0 +1,33 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// A small, non-cryptographic pseudo-random number generator (xorshift64*).
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates a generator with a random seed.
+    pub fn new() -> Self {
+        // `RandomState` is seeded randomly by the standard library.
+        Self::with_seed(RandomState::new().build_hasher().finish())
+    }
+
+    /// Creates a generator that always produces the same numbers for the same `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        // A state of 0 would only ever produce 0.
+        Self(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a random number in the range `[0.0, 1.0)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
@@ -0,0 +1,214 @@
+//! A lock-free ring buffer of samples, which the render thread fills and the audio callback
+//! drains.
+//!
+//! There is exactly one producer and one consumer. Samples are stored as the bits of `f32`s in
+//! atomics, so neither side ever waits for the other or allocates.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::Thread,
+};
+
+struct Shared {
+    samples: Box<[AtomicU32]>,
+    /// How many samples have been read in total. Only the consumer writes this.
+    read: AtomicUsize,
+    /// How many samples have been written in total. Only the producer writes this.
+    written: AtomicUsize,
+    /// The most samples the consumer asked for at once.
+    demand: AtomicUsize,
+    /// Whether the consumer was dropped.
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        written.wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+
+    fn slot(&self, index: usize) -> &AtomicU32 {
+        &self.samples[index & (self.samples.len() - 1)]
+    }
+}
+
+/// Writes samples into the ring buffer.
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+/// Reads samples from the ring buffer.
+pub struct Consumer {
+    shared: Arc<Shared>,
+    /// The producer's thread, which is woken up when samples have been read, and when the
+    /// consumer is dropped.
+    producer: Option<Thread>,
+}
+
+/// Creates a ring buffer that holds `capacity` samples, which must be a power of two.
+pub fn channel(capacity: usize) -> (Producer, Consumer) {
+    assert!(
+        capacity.is_power_of_two(),
+        "capacity must be a power of two"
+    );
+    let shared = Arc::new(Shared {
+        samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        read: AtomicUsize::new(0),
+        written: AtomicUsize::new(0),
+        demand: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+    });
+    let producer = Producer {
+        shared: shared.clone(),
+    };
+    let consumer = Consumer {
+        shared,
+        producer: None,
+    };
+    (producer, consumer)
+}
+
+impl Producer {
+    pub fn capacity(&self) -> usize {
+        self.shared.samples.len()
+    }
+
+    /// Returns how many samples are waiting to be read.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    /// Returns the most samples the consumer has asked for at once so far.
+    pub fn demand(&self) -> usize {
+        self.shared.demand.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the consumer is gone, so that nothing will be read anymore.
+    pub fn is_abandoned(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Writes as many of `samples` as fit, and returns how many that were.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let shared = &*self.shared;
+        let written = shared.written.load(Ordering::Relaxed);
+        let count = samples.len().min(self.capacity() - shared.len());
+        for (i, sample) in samples[..count].iter().enumerate() {
+            shared
+                .slot(written.wrapping_add(i))
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        shared
+            .written
+            .store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+impl Consumer {
+    /// Wakes up `thread` whenever samples have been read.
+    pub fn wake(&mut self, thread: Thread) {
+        self.producer = Some(thread);
+    }
+
+    /// Reads as many samples as are available into `out`, and returns how many that were.
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let shared = &*self.shared;
+        shared.demand.fetch_max(out.len(), Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Relaxed);
+        let count = out.len().min(shared.len());
+        for (i, out) in out[..count].iter_mut().enumerate() {
+            *out = f32::from_bits(shared.slot(read.wrapping_add(i)).load(Ordering::Relaxed));
+        }
+        shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+        if let Some(producer) = &self.producer {
+            producer.unpark();
+        }
+        count
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        if let Some(producer) = &self.producer {
+            producer.unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = channel(8);
+        let mut out = [0.0; 5];
+        for round in 0..10 {
+            let samples = [0, 1, 2, 3, 4].map(|i| (round * 5 + i) as f32);
+            assert_eq!(producer.push(&samples), 5);
+            assert_eq!(producer.len(), 5);
+            assert_eq!(consumer.pop(&mut out), 5);
+            assert_eq!(out, samples);
+        }
+        assert_eq!(producer.len(), 0);
+        assert_eq!(producer.demand(), 5);
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let (mut producer, mut consumer) = channel(4);
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.push(&[4.0, 5.0]), 1);
+        let mut out = [0.0; 6];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out[..4], [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(consumer.pop(&mut out), 0);
+        assert_eq!(producer.demand(), 6);
+    }
+
+    #[test]
+    fn dropping_the_consumer_wakes_the_producer() {
+        let (producer, mut consumer) = channel(4);
+        let thread = thread::spawn(move || {
+            while !producer.is_abandoned() {
+                thread::park();
+            }
+        });
+        consumer.wake(thread.thread().clone());
+        drop(consumer);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn across_threads() {
+        let (mut producer, mut consumer) = channel(64);
+        let samples = (0..10_000).map(|i| i as f32).collect::<Vec<_>>();
+        let expected = samples.clone();
+        let thread = thread::spawn(move || {
+            let mut rest = &samples[..];
+            while !rest.is_empty() {
+                let count = producer.push(&rest[..rest.len().min(7)]);
+                rest = &rest[count..];
+                thread::yield_now();
+            }
+        });
+        let mut received = Vec::new();
+        let mut out = [0.0; 13];
+        while received.len() < expected.len() {
+            let count = consumer.pop(&mut out);
+            received.extend_from_slice(&out[..count]);
+            thread::yield_now();
+        }
+        thread.join().unwrap();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,95 @@
+//! A D-Bus service that lets scripts and status bars control `clickd`.
+//!
+//! The service is published on the session bus as [`BUS_NAME`], with one object implementing:
+//!
+//! - `Enable()`, `Disable()` and `Toggle()`, which work like the tray icon.
+//! - `Play()`, which plays the default sound.
+//! - A read-only `Enabled` property.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use dbus::blocking::{stdintf::org_freedesktop_dbus::RequestNameReply, LocalConnection};
+use dbus_tree::{EmitsChangedSignal, Factory};
+
+use crate::{log::warn, Control};
+
+pub const BUS_NAME: &str = "io.github.SludgePhD.clickd";
+const PATH: &str = "/io/github/SludgePhD/clickd";
+const INTERFACE: &str = "io.github.SludgePhD.clickd";
+
+/// Spawns a thread that serves the D-Bus interface.
+///
+/// The methods change `enabled` and notify `control_sender` about the change, or ask it to play
+/// the default sound. Failures only disable the service, since a session bus isn't always
+/// available.
+pub fn spawn(enabled: Arc<AtomicBool>, control_sender: Sender<Control>) {
+    thread::spawn(move || {
+        if let Err(e) = run(enabled, control_sender) {
+            warn!("D-Bus service failed: {e}");
+        }
+    });
+}
+
+fn run(enabled: Arc<AtomicBool>, control_sender: Sender<Control>) -> anyhow::Result<()> {
+    let conn = LocalConnection::new_session()?;
+    match conn.request_name(BUS_NAME, false, false, true)? {
+        RequestNameReply::PrimaryOwner => {}
+        _ => anyhow::bail!("the bus name '{BUS_NAME}' is already taken"),
+    }
+
+    let f = Factory::new_fn::<()>();
+    let toggle = |name: &'static str, update: fn(&AtomicBool)| {
+        let enabled = enabled.clone();
+        let control_sender = control_sender.clone();
+        f.method(name, (), move |m| {
+            update(&enabled);
+            control_sender.send(Control::StateChanged).ok();
+            Ok(vec![m.msg.method_return()])
+        })
+    };
+    let play = {
+        let control_sender = control_sender.clone();
+        f.method("Play", (), move |m| {
+            control_sender.send(Control::PlayTest).ok();
+            Ok(vec![m.msg.method_return()])
+        })
+    };
+    let property = {
+        let enabled = enabled.clone();
+        f.property::<bool, _>("Enabled", ())
+            // The tray icon doesn't tell us when it toggles `enabled`.
+            .emits_changed(EmitsChangedSignal::False)
+            .on_get(move |iter, _| {
+                iter.append(enabled.load(Ordering::Relaxed));
+                Ok(())
+            })
+    };
+    let interface = f
+        .interface(INTERFACE, ())
+        .add_m(toggle("Enable", |enabled| {
+            enabled.store(true, Ordering::Relaxed)
+        }))
+        .add_m(toggle("Disable", |enabled| {
+            enabled.store(false, Ordering::Relaxed)
+        }))
+        .add_m(toggle("Toggle", |enabled| {
+            enabled.fetch_xor(true, Ordering::Relaxed);
+        }))
+        .add_m(play)
+        .add_p(property);
+    f.tree(())
+        .add(f.object_path(PATH, ()).introspectable().add(interface))
+        .start_receive(&conn);
+
+    loop {
+        conn.process(Duration::from_secs(60))?;
+    }
+}
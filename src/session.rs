@@ -0,0 +1,95 @@
+//! Watches whether the login session is locked or inactive, via `systemd-logind` on the system
+//! bus.
+//!
+//! This backs the `mute_when_locked` setting. The session is considered locked while its
+//! `LockedHint` property is set (which screen lockers are expected to do), or while it isn't the
+//! `Active` session of its seat, for example after switching to another user.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Once,
+    },
+    thread,
+    time::Duration,
+};
+
+use dbus::{
+    blocking::{
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        Connection, Proxy,
+    },
+    Message, Path,
+};
+
+use crate::log::{debug, warn};
+
+const LOGIND: &str = "org.freedesktop.login1";
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static SPAWNED: Once = Once::new();
+
+/// Spawns a thread that keeps `locked` up to date with the state of our login session.
+///
+/// Only the first call starts watching; the thread keeps running after that, even if
+/// `mute_when_locked` is turned off again. If logind can't be reached, `locked` stays `false`, so
+/// clicking keeps working.
+pub fn spawn(locked: Arc<AtomicBool>) {
+    SPAWNED.call_once(|| {
+        thread::spawn(move || {
+            if let Err(e) = run(&locked) {
+                locked.store(false, Ordering::Relaxed);
+                warn!("failed to watch the session lock state: {e}; clicking while locked");
+            }
+        });
+    });
+}
+
+fn run(locked: &Arc<AtomicBool>) -> Result<(), dbus::Error> {
+    let conn = Connection::new_system()?;
+    // `auto` is our own session, or the user's graphical session when running as a user service
+    // outside of any session.
+    let (session,): (Path<'static>,) = conn.with_proxy(LOGIND, MANAGER_PATH, TIMEOUT).method_call(
+        MANAGER_INTERFACE,
+        "GetSession",
+        ("auto",),
+    )?;
+    debug!("watching the lock state of session {session}");
+
+    let proxy = conn.with_proxy(LOGIND, session, TIMEOUT);
+    update(&proxy, locked)?;
+    let signal_locked = locked.clone();
+    proxy.match_signal(
+        move |changed: PropertiesPropertiesChanged, conn: &Connection, msg: &Message| {
+            if changed.interface_name == SESSION_INTERFACE {
+                let Some(path) = msg.path() else {
+                    return true;
+                };
+                let proxy = conn.with_proxy(LOGIND, path.into_static(), TIMEOUT);
+                if let Err(e) = update(&proxy, &signal_locked) {
+                    warn!("failed to read the session lock state: {e}");
+                }
+            }
+            true
+        },
+    )?;
+
+    loop {
+        conn.process(Duration::from_secs(60))?;
+    }
+}
+
+/// Reads the session's properties and stores whether it's locked in `locked`.
+fn update(proxy: &Proxy<'_, &Connection>, locked: &AtomicBool) -> Result<(), dbus::Error> {
+    let locked_hint: bool = proxy.get(SESSION_INTERFACE, "LockedHint")?;
+    let active: bool = proxy.get(SESSION_INTERFACE, "Active")?;
+    let now = locked_hint || !active;
+    if locked.swap(now, Ordering::Relaxed) != now {
+        debug!("session {}", if now { "locked" } else { "unlocked" });
+    }
+    Ok(())
+}
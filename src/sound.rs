@@ -0,0 +1,853 @@
+use std::{
+    cmp, fmt, io,
+    ops::Mul,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::bail;
+use hound::WavReader;
+
+use crate::{
+    aiff, config::ResampleQuality, flac, mixer::Play, random::Rng, stream::StreamedSound, vorbis,
+};
+
+/// The gain of the center and surround channels when down-mixing to stereo (-3 dB).
+const SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Returns the gains of the left and right channels for the stereo position `pan`, from -1.0
+/// (left) to 1.0 (right).
+///
+/// This follows the constant power pan law, scaled so that centered sounds keep their volume. A
+/// sound panned all the way to one side is 3 dB louder on that side.
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    let scale = std::f32::consts::SQRT_2;
+    (angle.cos() * scale, angle.sin() * scale)
+}
+
+/// The number of zero crossings of the sinc filter on each side of a sample, for
+/// [`Sound::resample_sinc`]. More make the filter sharper, but slower.
+const SINC_ZERO_CROSSINGS: f64 = 16.0;
+
+/// A fully decoded sound, stored as interleaved `f32` samples.
+#[derive(Clone)]
+pub struct Sound {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+impl Sound {
+    /// Decodes a sound file, detecting its format from the file contents.
+    pub fn new(data: &[u8]) -> anyhow::Result<Self> {
+        match Format::detect(data) {
+            Some(Format::Wav) => Self::decode_wav(data),
+            Some(Format::Aiff) => aiff::decode(data),
+            Some(Format::Flac) => flac::decode(data),
+            Some(Format::Ogg) => vorbis::decode(data),
+            None => bail!("unrecognized audio file format"),
+        }
+    }
+
+    fn decode_wav(wav: &[u8]) -> anyhow::Result<Self> {
+        // `hound` reads integer and float samples, also in `WAVE_FORMAT_EXTENSIBLE` files, but
+        // doesn't say which encoding it doesn't support.
+        let mut decoder = match WavReader::new(wav) {
+            Err(hound::Error::Unsupported) => match wav_format_tag(wav) {
+                Some(tag) => bail!(
+                    "WAV files encoded as {} are not supported",
+                    WavEncoding(tag)
+                ),
+                None => bail!("unsupported WAV file"),
+            },
+            res => res?,
+        };
+        let spec = decoder.spec();
+        let channels = spec.channels;
+        let sample_rate = spec.sample_rate;
+        let samples = wav_samples(&mut decoder).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Sound {
+            channels,
+            sample_rate,
+            samples,
+        })
+    }
+
+    /// Converts the sound to a different number of channels.
+    ///
+    /// Mono sounds are duplicated across all output channels, and down-mixing to mono averages all
+    /// channels. 5.1 and 7.1 surround sounds are folded into stereo with the usual coefficients.
+    /// Otherwise, existing channels are kept (or repeated, when up-mixing).
+    pub fn to_channels(&self, channels: u16) -> Sound {
+        if channels == self.channels {
+            return self.clone();
+        }
+
+        let from = usize::from(self.channels);
+        let to = usize::from(channels);
+        let mut samples = Vec::with_capacity(self.samples.len() / from * to);
+        for frame in self.samples.chunks_exact(from) {
+            convert_frame(frame, to, &mut samples);
+        }
+
+        Sound {
+            channels,
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+
+    /// Converts the sound to a different sample rate, using linear interpolation.
+    ///
+    /// The duration stays the same, so the number of frames scales with the sample rate.
+    pub fn resample(&self, sample_rate: u32) -> Sound {
+        let channels = usize::from(self.channels);
+        let frames = self.samples.len() / channels;
+        if sample_rate == self.sample_rate || frames == 0 {
+            return Sound {
+                sample_rate,
+                ..self.clone()
+            };
+        }
+
+        let ratio = f64::from(self.sample_rate) / f64::from(sample_rate);
+        let out_frames = (frames as f64 / ratio).round() as usize;
+        let mut samples = Vec::with_capacity(out_frames * channels);
+        for frame in 0..out_frames {
+            let pos = frame as f64 * ratio;
+            let index = cmp::min(pos as usize, frames - 1);
+            let next = cmp::min(index + 1, frames - 1);
+            let t = (pos - index as f64) as f32;
+            for ch in 0..channels {
+                let a = self.samples[index * channels + ch];
+                let b = self.samples[next * channels + ch];
+                samples.push(a + (b - a) * t);
+            }
+        }
+
+        Sound {
+            channels: self.channels,
+            sample_rate,
+            samples,
+        }
+    }
+
+    /// Converts the sound to a different sample rate, using a windowed sinc filter.
+    ///
+    /// This is much slower than [`Sound::resample`], but avoids aliasing. When the sample rate is
+    /// lowered, frequencies that the new rate can't represent are filtered out.
+    pub fn resample_sinc(&self, sample_rate: u32) -> Sound {
+        let channels = usize::from(self.channels);
+        let frames = self.samples.len() / channels;
+        if sample_rate == self.sample_rate || frames == 0 {
+            return Sound {
+                sample_rate,
+                ..self.clone()
+            };
+        }
+
+        let ratio = f64::from(self.sample_rate) / f64::from(sample_rate);
+        // The cutoff frequency, relative to the Nyquist frequency of the original sound.
+        let cutoff = (1.0 / ratio).min(1.0);
+        let half_width = SINC_ZERO_CROSSINGS / cutoff;
+        let out_frames = (frames as f64 / ratio).round() as usize;
+        let mut samples = Vec::with_capacity(out_frames * channels);
+        let mut out = vec![0.0; channels];
+        for frame in 0..out_frames {
+            let pos = frame as f64 * ratio;
+            let first = (pos - half_width).ceil().max(0.0) as usize;
+            let last = cmp::min((pos + half_width).floor() as usize, frames - 1);
+            out.fill(0.0);
+            for index in first..=last {
+                let x = index as f64 - pos;
+                let weight = (cutoff * sinc(cutoff * x) * blackman(x / half_width)) as f32;
+                let frame = &self.samples[index * channels..][..channels];
+                for (out, sample) in out.iter_mut().zip(frame) {
+                    *out += sample * weight;
+                }
+            }
+            samples.extend_from_slice(&out);
+        }
+
+        Sound {
+            channels: self.channels,
+            sample_rate,
+            samples,
+        }
+    }
+
+    /// Returns the highest absolute sample value.
+    pub fn peak(&self) -> f32 {
+        self.samples
+            .iter()
+            .fold(0.0, |peak, sample| f32::max(peak, sample.abs()))
+    }
+
+    /// Moves the sound to the stereo position `pan` (see [`pan_gains`]), by scaling its first
+    /// two channels. Mono sounds are returned unchanged.
+    pub fn pan(mut self, pan: f32) -> Sound {
+        let channels = usize::from(self.channels);
+        if channels < 2 {
+            return self;
+        }
+        let (left, right) = pan_gains(pan);
+        for frame in self.samples.chunks_exact_mut(channels) {
+            frame[0] *= left;
+            frame[1] *= right;
+        }
+        self
+    }
+
+    /// Linearly fades out the last `duration` of the sound, so that it doesn't end abruptly.
+    pub fn fade_out(mut self, duration: Duration) -> Sound {
+        let channels = usize::from(self.channels);
+        let frames = self.samples.len() / channels;
+        let fade = (duration.as_secs_f64() * f64::from(self.sample_rate)) as usize;
+        let fade = cmp::min(fade, frames);
+
+        let start = (frames - fade) * channels;
+        for (i, frame) in self.samples[start..].chunks_exact_mut(channels).enumerate() {
+            let gain = (fade - 1 - i) as f32 / fade as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+        self
+    }
+}
+
+/// Returns the format tag of a WAV file, which identifies how its samples are encoded.
+///
+/// For `WAVE_FORMAT_EXTENSIBLE` files, the tag embedded in the subformat GUID is returned.
+fn wav_format_tag(wav: &[u8]) -> Option<u16> {
+    const EXTENSIBLE: u16 = 0xfffe;
+
+    let mut chunks = wav.get(12..)?;
+    while chunks.len() >= 8 {
+        let (header, rest) = chunks.split_at(8);
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        if &header[..4] == b"fmt " {
+            let fmt = rest.get(..len)?;
+            let tag = u16::from_le_bytes(fmt.get(..2)?.try_into().unwrap());
+            if tag != EXTENSIBLE {
+                return Some(tag);
+            }
+            // The subformat GUID starts with the format tag it stands for.
+            return Some(u16::from_le_bytes(fmt.get(24..26)?.try_into().unwrap()));
+        }
+        // Chunks are padded to an even length.
+        chunks = rest.get(len + len % 2..)?;
+    }
+    None
+}
+
+/// A WAV format tag, displayed as the name of the encoding it stands for.
+struct WavEncoding(u16);
+
+impl fmt::Display for WavEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            0x0001 => "PCM",
+            0x0002 => "Microsoft ADPCM",
+            0x0003 => "IEEE float",
+            0x0006 => "A-law",
+            0x0007 => "µ-law",
+            0x0011 => "IMA ADPCM",
+            0x0031 => "GSM 6.10",
+            0x0050 => "MPEG",
+            0x0055 => "MP3",
+            _ => return write!(f, "format 0x{:04x}", self.0),
+        };
+        write!(f, "{name} (format 0x{:04x})", self.0)
+    }
+}
+
+/// Returns the samples of a WAV file as `f32`s.
+pub fn wav_samples<R: io::Read>(
+    decoder: &mut WavReader<R>,
+) -> Box<dyn Iterator<Item = hound::Result<f32>> + '_> {
+    let spec = decoder.spec();
+    match spec.sample_format {
+        hound::SampleFormat::Float => Box::new(decoder.samples::<f32>()),
+        hound::SampleFormat::Int => {
+            // Samples are signed and normalized to `[-1, 1)`. 8-bit WAVs are stored unsigned,
+            // but `hound` already shifts them to be centered around 0.
+            // (This also avoids overflowing an `i32` shift for 32-bit samples.)
+            let max = 2f32.powi(i32::from(spec.bits_per_sample) - 1);
+            Box::new(
+                decoder
+                    .samples::<i32>()
+                    .map(move |res| res.map(|i| i as f32 / max)),
+            )
+        }
+    }
+}
+
+/// The normalized sinc function, `sin(πx) / πx`.
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        return 1.0;
+    }
+    let x = x * std::f64::consts::PI;
+    x.sin() / x
+}
+
+/// The Blackman window, which goes from 0 at `x = -1` to 1 at `x = 0` and back to 0 at `x = 1`.
+fn blackman(x: f64) -> f64 {
+    let t = std::f64::consts::PI * (x + 1.0);
+    0.42 - 0.5 * t.cos() + 0.08 * (2.0 * t).cos()
+}
+
+/// Converts a single `frame` to `channels` channels, and appends it to `out`.
+///
+/// See [`Sound::to_channels`] for how channels are mapped.
+pub fn convert_frame(frame: &[f32], channels: usize, out: &mut Vec<f32>) {
+    let from = frame.len();
+    if channels == from {
+        out.extend_from_slice(frame);
+    } else if channels == 2 && (from == 6 || from == 8) {
+        // WAV files store surround channels in the order front left, front right, center, LFE,
+        // back left, back right, and (for 7.1) side left and side right. Center and surround
+        // channels are mixed in at -3 dB, and the LFE channel is dropped.
+        let (mut left, mut right) = (frame[0], frame[1]);
+        for (l, r) in [(2, 2), (4, 5), (6, 7)] {
+            if r < from {
+                left += frame[l] * SURROUND_GAIN;
+                right += frame[r] * SURROUND_GAIN;
+            }
+        }
+        out.extend([left, right]);
+    } else if channels == 1 {
+        out.push(frame.iter().sum::<f32>() / from as f32);
+    } else {
+        out.extend((0..channels).map(|ch| frame[ch % from]));
+    }
+}
+
+/// :)
+impl Mul<f32> for Sound {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            samples: self.samples.into_iter().map(|f| f * rhs).collect(),
+        }
+    }
+}
+
+/// A sound that can be played: either decoded into memory, or streamed from disk.
+#[derive(Clone)]
+pub enum Clip {
+    Memory(Arc<Sound>),
+    Streamed(Arc<StreamedSound>),
+}
+
+impl Clip {
+    pub fn channels(&self) -> u16 {
+        match self {
+            Clip::Memory(sound) => sound.channels,
+            Clip::Streamed(sound) => sound.channels(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Clip::Memory(sound) => sound.sample_rate,
+            Clip::Streamed(sound) => sound.sample_rate(),
+        }
+    }
+
+    /// Converts the clip to the given channel count and sample rate, and fades out its last
+    /// `fade`.
+    ///
+    /// Streamed clips are resampled while they play, which always uses linear interpolation.
+    pub fn convert(
+        &self,
+        channels: u16,
+        sample_rate: u32,
+        fade: Duration,
+        quality: ResampleQuality,
+    ) -> Clip {
+        match self {
+            Clip::Memory(sound) => {
+                let sound = sound.to_channels(channels);
+                let sound = match quality {
+                    ResampleQuality::Linear => sound.resample(sample_rate),
+                    ResampleQuality::Sinc => sound.resample_sinc(sample_rate),
+                };
+                Clip::Memory(Arc::new(sound.fade_out(fade)))
+            }
+            Clip::Streamed(sound) => {
+                Clip::Streamed(Arc::new(sound.convert(channels, sample_rate, fade)))
+            }
+        }
+    }
+
+    /// Moves the clip to the stereo position `pan`, which only affects stereo (or surround)
+    /// clips.
+    pub fn pan(&self, pan: f32) -> Clip {
+        match self {
+            Clip::Memory(sound) => Clip::Memory(Arc::new((**sound).clone().pan(pan))),
+            Clip::Streamed(sound) => Clip::Streamed(Arc::new(sound.pan(pan))),
+        }
+    }
+
+    /// Returns the highest absolute sample value, which requires decoding streamed clips.
+    pub fn peak(&self) -> anyhow::Result<f32> {
+        match self {
+            Clip::Memory(sound) => Ok(sound.peak()),
+            Clip::Streamed(sound) => sound.peak(),
+        }
+    }
+
+    /// Scales the volume of the clip by `volume`.
+    pub fn scale(&self, volume: f32) -> Clip {
+        match self {
+            Clip::Memory(sound) => Clip::Memory(Arc::new((**sound).clone() * volume)),
+            Clip::Streamed(sound) => Clip::Streamed(Arc::new(sound.scale(volume))),
+        }
+    }
+
+    /// Creates a request to play the clip at `speed`.
+    ///
+    /// Streamed clips start decoding right away.
+    pub fn start(&self, speed: f32) -> Play {
+        match self {
+            Clip::Memory(sound) => Play::Memory {
+                sound: sound.clone(),
+                speed,
+            },
+            Clip::Streamed(sound) => Play::Stream(sound.start(speed)),
+        }
+    }
+
+    /// Creates a request to play the clip over and over, until `stop` is set.
+    ///
+    /// Streamed clips can't be looped, so they are played once instead.
+    pub fn start_loop(&self, stop: Arc<AtomicBool>) -> Play {
+        match self {
+            Clip::Memory(sound) => Play::Loop {
+                sound: sound.clone(),
+                stop,
+            },
+            Clip::Streamed(sound) => Play::Stream(sound.start(1.0)),
+        }
+    }
+}
+
+impl From<Sound> for Clip {
+    fn from(sound: Sound) -> Self {
+        Clip::Memory(Arc::new(sound))
+    }
+}
+
+/// A non-empty set of sounds, one of which is picked at random whenever it is played.
+#[derive(Clone)]
+pub struct SoundSet {
+    sounds: Vec<Clip>,
+    /// The running totals of the sounds' weights, or `None` if all sounds are equally likely.
+    cumulative_weights: Option<Vec<f32>>,
+}
+
+impl SoundSet {
+    /// Creates a set from a list of sounds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sounds` is empty.
+    pub fn new(sounds: Vec<Clip>) -> Self {
+        assert!(!sounds.is_empty(), "`SoundSet` must not be empty");
+        Self {
+            sounds,
+            cumulative_weights: None,
+        }
+    }
+
+    /// Creates a set from a list of sounds and how often each is picked, relative to the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sounds` is empty, or if a weight is negative or the weights add up to 0.
+    pub fn weighted(sounds: Vec<(Clip, f32)>) -> Self {
+        assert!(!sounds.is_empty(), "`SoundSet` must not be empty");
+        if sounds.iter().all(|(_, weight)| *weight == sounds[0].1) {
+            return Self::new(sounds.into_iter().map(|(clip, _)| clip).collect());
+        }
+
+        let mut total = 0.0;
+        let (sounds, cumulative_weights) = sounds
+            .into_iter()
+            .map(|(clip, weight)| {
+                assert!(weight >= 0.0, "sound weights must not be negative");
+                total += weight;
+                (clip, total)
+            })
+            .unzip();
+        assert!(total > 0.0, "sound weights must not add up to 0");
+        Self {
+            sounds,
+            cumulative_weights: Some(cumulative_weights),
+        }
+    }
+
+    /// Returns the first sound in the set.
+    pub fn first(&self) -> &Clip {
+        &self.sounds[0]
+    }
+
+    /// Returns a random sound from the set, according to the weights of the sounds.
+    pub fn pick(&self, rng: &mut Rng) -> &Clip {
+        let i = match &self.cumulative_weights {
+            Some(cumulative) => {
+                let total = cumulative[cumulative.len() - 1];
+                let x = rng.next_f32() * total;
+                // Sounds with a weight of 0 have the same total as the one before them, so they
+                // are never picked.
+                let i = cumulative.partition_point(|&sum| sum <= x);
+                cmp::min(i, cumulative.len() - 1)
+            }
+            None => (rng.next_u64() % self.sounds.len() as u64) as usize,
+        };
+        &self.sounds[i]
+    }
+
+    /// Applies `f` to every sound in the set, keeping their weights.
+    pub fn map(&self, f: impl Fn(&Clip) -> Clip) -> Self {
+        Self {
+            sounds: self.sounds.iter().map(f).collect(),
+            cumulative_weights: self.cumulative_weights.clone(),
+        }
+    }
+}
+
+/// Audio file formats, as identified by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Wav,
+    Aiff,
+    Flac,
+    Ogg,
+}
+
+impl Format {
+    fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Self::Wav),
+            [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => {
+                Some(Self::Aiff)
+            }
+            [b'f', b'L', b'a', b'C', ..] => Some(Self::Flac),
+            [b'O', b'g', b'g', b'S', ..] => Some(Self::Ogg),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Wav => "WAV",
+            Self::Aiff => "AIFF",
+            Self::Flac => "FLAC",
+            Self::Ogg => "Ogg Vorbis",
+        })
+    }
+}
+
+/// Returns a set of one stereo sound at 48 kHz whose samples are all `level`, for tests.
+#[cfg(test)]
+pub fn constant(level: f32) -> SoundSet {
+    SoundSet::new(vec![Clip::from(Sound {
+        channels: 2,
+        sample_rate: 48000,
+        samples: vec![level; 64],
+    })])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use hound::{SampleFormat, WavSpec, WavWriter};
+
+    use super::*;
+
+    /// Writes a mono WAV file with the given integer `samples`.
+    fn int_wav(bits_per_sample: u16, samples: &[i32]) -> Vec<u8> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample,
+            sample_format: SampleFormat::Int,
+        };
+        let mut wav = Vec::new();
+        let mut writer = WavWriter::new(Cursor::new(&mut wav), spec).unwrap();
+        for &sample in samples {
+            if bits_per_sample == 8 {
+                writer.write_sample(sample as i8).unwrap();
+            } else {
+                writer.write_sample(sample).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        wav
+    }
+
+    #[test]
+    fn wav_samples_are_normalized() {
+        for bits in [8, 16, 24, 32] {
+            let max = (1i64 << (bits - 1)) - 1;
+            let min = -max - 1;
+            let samples = [
+                min as i32,
+                -(1 << (bits - 2)),
+                0,
+                1 << (bits - 2),
+                max as i32,
+            ];
+            let wav = int_wav(bits, &samples);
+            let mut decoder = WavReader::new(&wav[..]).unwrap();
+            let decoded = wav_samples(&mut decoder)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            // Full scale maps to ±1.0; the positive end is one step short of 1.0.
+            let step = 1.0 / (1i64 << (bits - 1)) as f32;
+            assert_eq!(decoded[0], -1.0, "{bits} bits");
+            assert_eq!(decoded[1], -0.5, "{bits} bits");
+            assert_eq!(decoded[2], 0.0, "{bits} bits");
+            assert_eq!(decoded[3], 0.5, "{bits} bits");
+            assert!(
+                (decoded[4] - (1.0 - step)).abs() <= f32::EPSILON,
+                "{bits} bits: {decoded:?}"
+            );
+        }
+    }
+
+    fn sound(channels: u16, sample_rate: u32, samples: Vec<f32>) -> Sound {
+        Sound {
+            channels,
+            sample_rate,
+            samples,
+        }
+    }
+
+    #[test]
+    fn resampled_length() {
+        for (from, to, frames, expected) in [
+            (44100, 48000, 441, 480),
+            (48000, 44100, 480, 441),
+            (8000, 48000, 100, 600),
+            (48000, 8000, 600, 100),
+            (48000, 48000, 123, 123),
+            (44100, 48000, 1, 1),
+            (44100, 48000, 0, 0),
+        ] {
+            let sound = sound(2, from, vec![0.5; frames * 2]);
+            for resampled in [sound.resample(to), sound.resample_sinc(to)] {
+                assert_eq!(resampled.sample_rate, to);
+                assert_eq!(resampled.channels, 2);
+                assert_eq!(resampled.samples.len(), expected * 2, "{from} -> {to} Hz");
+            }
+        }
+    }
+
+    #[test]
+    fn linear_resampling_keeps_constant_sounds() {
+        let resampled = sound(1, 44100, vec![0.25; 441]).resample(48000);
+        assert!(resampled.samples.iter().all(|&sample| sample == 0.25));
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        let mono = sound(1, 48000, vec![0.5, -0.25]);
+        let stereo = mono.to_channels(2);
+        assert_eq!(stereo.channels, 2);
+        assert_eq!(stereo.samples, [0.5, 0.5, -0.25, -0.25]);
+
+        let stereo = sound(2, 48000, vec![1.0, 0.0, 0.5, -0.5]);
+        let mono = stereo.to_channels(1);
+        assert_eq!(mono.channels, 1);
+        assert_eq!(mono.samples, [0.5, 0.0]);
+
+        // Up-mixing beyond stereo repeats the existing channels.
+        assert_eq!(
+            sound(2, 48000, vec![0.1, 0.2]).to_channels(4).samples,
+            [0.1, 0.2, 0.1, 0.2]
+        );
+    }
+
+    #[test]
+    fn surround_fold() {
+        let g = SURROUND_GAIN;
+        // Front left, front right, center, LFE, back left, back right.
+        let mut out = Vec::new();
+        convert_frame(&[0.1, 0.2, 0.3, 1.0, 0.4, 0.5], 2, &mut out);
+        let expected = [0.1 + 0.3 * g + 0.4 * g, 0.2 + 0.3 * g + 0.5 * g];
+        assert_close(&out, &expected);
+
+        // 7.1 adds side left and side right.
+        out.clear();
+        convert_frame(&[0.1, 0.2, 0.3, 1.0, 0.4, 0.5, 0.6, 0.7], 2, &mut out);
+        let expected = [0.1 + (0.3 + 0.4 + 0.6) * g, 0.2 + (0.3 + 0.5 + 0.7) * g];
+        assert_close(&out, &expected);
+
+        // Only the LFE channel is dropped entirely.
+        out.clear();
+        convert_frame(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0], 2, &mut out);
+        assert_eq!(out, [0.0, 0.0]);
+
+        // Down-mixing surround to mono averages all channels, including the LFE.
+        out.clear();
+        convert_frame(&[0.6, 0.0, 0.0, 0.6, 0.0, 0.0], 1, &mut out);
+        assert_close(&out, &[0.2]);
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    /// Picks from `sounds` many times, and returns how often each sound was picked, relative to
+    /// the number of picks. Each sound is identified by its level.
+    fn pick_frequencies(sounds: &SoundSet, levels: &[f32]) -> Vec<f32> {
+        const PICKS: usize = 100_000;
+        let mut rng = Rng::with_seed(12345);
+        let mut counts = vec![0; levels.len()];
+        for _ in 0..PICKS {
+            let level = sounds.pick(&mut rng).peak().unwrap();
+            counts[levels.iter().position(|&l| l == level).unwrap()] += 1;
+        }
+        counts
+            .iter()
+            .map(|&count| count as f32 / PICKS as f32)
+            .collect()
+    }
+
+    #[test]
+    fn weighted_picks_follow_weights() {
+        let levels = [0.1, 0.2, 0.3, 0.4];
+        let weights = [1.0, 3.0, 0.0, 6.0];
+        let sounds = SoundSet::weighted(
+            levels
+                .iter()
+                .zip(weights)
+                .map(|(&level, weight)| (constant(level).first().clone(), weight))
+                .collect(),
+        );
+        let frequencies = pick_frequencies(&sounds, &levels);
+        for (frequency, weight) in frequencies.iter().zip(weights) {
+            let expected = weight / 10.0;
+            assert!((frequency - expected).abs() < 0.01, "{frequencies:?}");
+        }
+        // A weight of 0 means never.
+        assert_eq!(frequencies[2], 0.0);
+    }
+
+    #[test]
+    fn unweighted_picks_are_uniform() {
+        let levels = [0.1, 0.2, 0.3];
+        let sounds = SoundSet::weighted(
+            levels
+                .iter()
+                .map(|&level| (constant(level).first().clone(), 2.0))
+                .collect(),
+        );
+        for frequency in pick_frequencies(&sounds, &levels) {
+            assert!((frequency - 1.0 / 3.0).abs() < 0.01, "{frequency}");
+        }
+    }
+
+    /// Builds a `WAVE_FORMAT_EXTENSIBLE` file whose subformat GUID stands for format `tag`.
+    fn extensible_wav(tag: u16, channels: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+        let sample_rate = 48000u32;
+        let block_align = channels * bits_per_sample / 8;
+        let mut fmt = Vec::new();
+        fmt.extend(0xfffeu16.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(sample_rate.to_le_bytes());
+        fmt.extend((sample_rate * u32::from(block_align)).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bits_per_sample.to_le_bytes());
+        // The size of the extension, the valid bits per sample and the channel mask.
+        fmt.extend(22u16.to_le_bytes());
+        fmt.extend(bits_per_sample.to_le_bytes());
+        fmt.extend(if channels == 2 { 3u32 } else { 4u32 }.to_le_bytes());
+        fmt.extend(tag.to_le_bytes());
+        fmt.extend([
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+        ]);
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend((fmt.len() as u32).to_le_bytes());
+        wav.extend(fmt);
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    #[test]
+    fn extensible_pcm_wav() {
+        let data = [i16::MIN, 16384, 0, i16::MAX]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        let sound = Sound::new(&extensible_wav(0x0001, 2, 16, &data)).unwrap();
+        assert_eq!(sound.channels, 2);
+        assert_eq!(sound.sample_rate, 48000);
+        assert_eq!(sound.samples[..3], [-1.0, 0.5, 0.0]);
+        assert_eq!(sound.samples[3], f32::from(i16::MAX) / 32768.0);
+    }
+
+    #[test]
+    fn extensible_float_wav() {
+        let data = [0.5f32, -0.25]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        let sound = Sound::new(&extensible_wav(0x0003, 1, 32, &data)).unwrap();
+        assert_eq!(sound.channels, 1);
+        assert_eq!(sound.samples, [0.5, -0.25]);
+    }
+
+    #[test]
+    fn extensible_unsupported_wav() {
+        let wav = extensible_wav(0x0006, 1, 8, &[0xd5, 0x55]);
+        assert_eq!(wav_format_tag(&wav), Some(0x0006));
+        let e = Sound::new(&wav).err().expect("A-law should be rejected");
+        assert_eq!(
+            e.to_string(),
+            "WAV files encoded as A-law (format 0x0006) are not supported"
+        );
+    }
+
+    #[test]
+    fn float_wav_samples_are_unchanged() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut wav = Vec::new();
+        let mut writer = WavWriter::new(Cursor::new(&mut wav), spec).unwrap();
+        for sample in [1.0f32, -1.0, 0.25, -0.75] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let sound = Sound::new(&wav).unwrap();
+        assert_eq!(sound.channels, 2);
+        assert_eq!(sound.sample_rate, 44100);
+        assert_eq!(sound.samples, [1.0, -1.0, 0.25, -0.75]);
+    }
+}
@@ -0,0 +1,78 @@
+//! A file that always contains the current state of `clickd` as JSON, for status bars that
+//! would rather watch a file than talk to the D-Bus service.
+//!
+//! The file holds a single object, like
+//! `{"enabled":true,"devices":2,"volume":0.5,"stream_errors":0}`. It is
+//! replaced atomically, so readers never see a partial update, and removed when `clickd` exits.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{log::warn, mixer::Volume};
+
+pub struct StatusFile {
+    path: PathBuf,
+    enabled: Arc<AtomicBool>,
+    volume: Arc<Volume>,
+    stream_errors: Arc<AtomicU64>,
+    /// The last contents written, to skip writes when nothing changed.
+    written: String,
+}
+
+impl StatusFile {
+    /// Creates a status file at `path` that reports `enabled`, `volume` and the number of
+    /// `stream_errors` playback recovered from.
+    ///
+    /// Nothing is written until [`StatusFile::update`] is called.
+    pub fn new(
+        path: &Path,
+        enabled: Arc<AtomicBool>,
+        volume: Arc<Volume>,
+        stream_errors: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            enabled,
+            volume,
+            stream_errors,
+            written: String::new(),
+        }
+    }
+
+    /// Writes the current state, with `devices` open input devices, if it has changed.
+    ///
+    /// Failures are only logged, since the file is just informational.
+    pub fn update(&mut self, devices: usize) {
+        let contents = format!(
+            "{{\"enabled\":{},\"devices\":{devices},\"volume\":{},\"stream_errors\":{}}}\n",
+            self.enabled.load(Ordering::Relaxed),
+            self.volume.get(),
+            self.stream_errors.load(Ordering::Relaxed),
+        );
+        if contents == self.written {
+            return;
+        }
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let res = fs::write(&tmp, &contents).and_then(|()| fs::rename(&tmp, &self.path));
+        match res {
+            Ok(()) => self.written = contents,
+            Err(e) => warn!("failed to write status file '{}': {e}", self.path.display()),
+        }
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        if !self.written.is_empty() {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
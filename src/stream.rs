@@ -0,0 +1,259 @@
+//! Playback of long sounds that are decoded from disk while they play, instead of being loaded
+//! into memory up front.
+//!
+//! Each playing stream has its own decoder thread, which converts the file to the output format
+//! and sends it to the mixer in chunks. The channel between them is bounded, so it acts as a
+//! ring buffer that keeps the decoder only a few chunks ahead of playback.
+
+use std::{
+    cmp, mem,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use hound::WavReader;
+
+use crate::{
+    log::error,
+    sound::{convert_frame, pan_gains, wav_samples},
+};
+
+/// The number of frames decoded at once.
+const CHUNK_FRAMES: usize = 2048;
+
+/// The number of decoded chunks that may be waiting to be played.
+const BUFFERED_CHUNKS: usize = 4;
+
+/// A WAV file that is streamed from disk whenever it is played.
+#[derive(Clone)]
+pub struct StreamedSound {
+    path: PathBuf,
+    /// The format of the file.
+    file_channels: u16,
+    file_sample_rate: u32,
+    frames: u32,
+    /// The format to convert the file to while streaming.
+    channels: u16,
+    sample_rate: u32,
+    gain: f32,
+    /// The stereo position, applied after converting to `channels`.
+    pan: f32,
+    fade: Duration,
+}
+
+impl StreamedSound {
+    /// Reads the header of the WAV file at `path`.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let decoder = WavReader::open(path)?;
+        let spec = decoder.spec();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file_channels: spec.channels,
+            file_sample_rate: spec.sample_rate,
+            frames: decoder.duration(),
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            gain: 1.0,
+            pan: 0.0,
+            fade: Duration::ZERO,
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns how long the sound plays for at its original speed.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.frames) / f64::from(self.file_sample_rate))
+    }
+
+    /// Makes the stream produce the given channel count and sample rate, with the last `fade` of
+    /// the sound faded out.
+    pub fn convert(&self, channels: u16, sample_rate: u32, fade: Duration) -> Self {
+        Self {
+            channels,
+            sample_rate,
+            fade,
+            ..self.clone()
+        }
+    }
+
+    /// Scales the volume of the sound by `gain`.
+    pub fn scale(&self, gain: f32) -> Self {
+        Self {
+            gain: self.gain * gain,
+            ..self.clone()
+        }
+    }
+
+    /// Moves the sound to the stereo position `pan`, like [`Sound::pan`](crate::sound::Sound::pan).
+    pub fn pan(&self, pan: f32) -> Self {
+        Self {
+            pan,
+            ..self.clone()
+        }
+    }
+
+    /// Decodes the whole file to find its highest absolute sample value, with `gain` applied.
+    pub fn peak(&self) -> anyhow::Result<f32> {
+        let mut decoder = WavReader::open(&self.path)?;
+        let mut peak = 0.0;
+        for sample in wav_samples(&mut decoder) {
+            peak = f32::max(peak, sample?.abs());
+        }
+        Ok(peak * self.gain)
+    }
+
+    /// Spawns a thread that starts decoding the sound for playback at `speed`.
+    pub fn start(self: &Arc<Self>, speed: f32) -> Stream {
+        let (sender, receiver) = mpsc::sync_channel(BUFFERED_CHUNKS);
+        let sound = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = sound.decode(speed, sender) {
+                error!("failed to stream '{}': {e}", sound.path.display());
+            }
+        });
+        Stream {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Decodes and converts the sound, sending it to `sender` in chunks.
+    ///
+    /// Stops early when the receiving [`Stream`] is dropped.
+    fn decode(&self, speed: f32, sender: SyncSender<Vec<f32>>) -> anyhow::Result<()> {
+        let mut decoder = WavReader::open(&self.path)?;
+        let mut samples = wav_samples(&mut decoder);
+        let from = usize::from(self.file_channels);
+        let to = usize::from(self.channels);
+        let mut next_frame = || -> anyhow::Result<Option<Vec<f32>>> {
+            let mut frame = Vec::with_capacity(from);
+            for _ in 0..from {
+                match samples.next() {
+                    Some(sample) => frame.push(sample?),
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(frame))
+        };
+
+        // This works like `Sound::resample` and `Sound::fade_out`, but only keeps the two frames
+        // that are being interpolated between.
+        let step =
+            f64::from(self.file_sample_rate) / f64::from(self.sample_rate) * f64::from(speed);
+        let out_frames = (f64::from(self.frames) / step).round() as usize;
+        let fade = (self.fade.as_secs_f64() * f64::from(self.sample_rate)) as usize;
+        let fade = cmp::min(fade, out_frames);
+        let (left, right) = pan_gains(self.pan);
+
+        let Some(mut a) = next_frame()? else {
+            return Ok(());
+        };
+        let mut b = next_frame()?.unwrap_or_else(|| a.clone());
+        let mut index = 0;
+        let mut frame = vec![0.0; from];
+        let mut chunk = Vec::with_capacity(CHUNK_FRAMES * to);
+        for out_frame in 0..out_frames {
+            let pos = out_frame as f64 * step;
+            while pos as usize > index {
+                // At the end of the file, keep repeating the last frame.
+                a = match next_frame()? {
+                    Some(next) => mem::replace(&mut b, next),
+                    None => b.clone(),
+                };
+                index += 1;
+            }
+
+            let t = (pos - index as f64) as f32;
+            for ((out, a), b) in frame.iter_mut().zip(&a).zip(&b) {
+                *out = a + (b - a) * t;
+            }
+            let mut gain = self.gain;
+            if out_frame >= out_frames - fade {
+                gain *= (out_frames - 1 - out_frame) as f32 / fade as f32;
+            }
+            let start = chunk.len();
+            convert_frame(&frame, to, &mut chunk);
+            for sample in &mut chunk[start..] {
+                *sample *= gain;
+            }
+            if to >= 2 {
+                chunk[start] *= left;
+                chunk[start + 1] *= right;
+            }
+
+            if chunk.len() >= CHUNK_FRAMES * to {
+                let full = mem::replace(&mut chunk, Vec::with_capacity(CHUNK_FRAMES * to));
+                if sender.send(full).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            sender.send(chunk).ok();
+        }
+        Ok(())
+    }
+}
+
+/// The playing end of a [`StreamedSound`].
+pub struct Stream {
+    receiver: Receiver<Vec<f32>>,
+    /// The chunk that is currently being played, and the position of the next sample in it.
+    chunk: Vec<f32>,
+    pos: usize,
+    /// Whether the decoder thread has sent all chunks.
+    finished: bool,
+}
+
+impl Stream {
+    /// Adds as many decoded samples to `data` as are available, scaled by `volume`.
+    ///
+    /// If the decoder falls behind, the rest of `data` is left unchanged; playback continues on
+    /// the next call.
+    pub fn mix(&mut self, data: &mut [f32], volume: f32) {
+        let mut written = 0;
+        while written < data.len() {
+            if self.pos == self.chunk.len() {
+                match self.receiver.try_recv() {
+                    Ok(chunk) => {
+                        self.chunk = chunk;
+                        self.pos = 0;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.finished = true;
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            let n = cmp::min(data.len() - written, self.chunk.len() - self.pos);
+            let samples = &self.chunk[self.pos..][..n];
+            for (out, sample) in data[written..][..n].iter_mut().zip(samples) {
+                *out += sample * volume;
+            }
+            written += n;
+            self.pos += n;
+        }
+    }
+
+    /// Returns whether the whole sound has been played.
+    pub fn is_finished(&self) -> bool {
+        self.finished && self.pos == self.chunk.len()
+    }
+}
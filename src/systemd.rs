@@ -0,0 +1,39 @@
+//! A minimal implementation of the systemd service notification protocol (see `sd_notify(3)`).
+
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+};
+
+use crate::log::warn;
+
+/// Sends a newline-separated list of `KEY=value` assignments to the service manager.
+///
+/// This does nothing if `clickd` isn't running as a `Type=notify` service. Failures are only
+/// reported, since the service manager will eventually notice that we're not talking to it.
+pub fn notify(state: &str) {
+    if let Err(e) = try_notify(state) {
+        warn!("failed to notify service manager: {e}");
+    }
+}
+
+fn try_notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    // A leading `@` denotes a socket in the abstract namespace.
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
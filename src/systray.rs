@@ -1,84 +1,291 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use ksni::{Icon, Tray, TrayService};
-use png::{BitDepth, ColorType};
+use anyhow::{bail, Context};
+use ksni::{
+    menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu},
+    Handle, Icon, MenuItem, Status, ToolTip, Tray, TrayService,
+};
+use png::{BitDepth, ColorType, Transformations};
+
+use crate::{
+    config::TrayToggle,
+    input::DeviceInfo,
+    log::{error, warn},
+    mixer::Volume,
+    notification, Control,
+};
+
+/// The volume levels selectable from the tray menu.
+const VOLUME_LEVELS: &[f32] = &[0.0, 0.25, 0.5, 0.75, 1.0];
+
+/// The durations (in minutes) clicking can be muted for from the tray menu.
+const MUTE_MINUTES: &[u64] = &[15, 30, 60];
 
-#[derive(Clone)]
 pub struct SystrayIcon {
-    enabled: Arc<AtomicBool>,
+    handle: Handle<TrayImpl>,
+    thread: JoinHandle<()>,
 }
 
 impl SystrayIcon {
-    pub fn new() -> anyhow::Result<Self> {
-        let enabled = Arc::new(AtomicBool::new(true));
-
-        let icon_enabled = decode_png(include_bytes!("../assets/icon_enabled.png"));
-        let icon_disabled = decode_png(include_bytes!("../assets/icon_disabled.png"));
+    /// Spawns the tray icon service.
+    ///
+    /// Clicking the icon toggles `enabled` (unless `toggle_on` is [`TrayToggle::MenuOnly`]), which
+    /// shows a notification if `notifications` is set.
+    /// `control_sender` is told when a temporary mute ends, and when "Quit" is selected in the
+    /// menu.
+    ///
+    /// The icons are loaded from the PNG files at `icon_enabled` and `icon_disabled`, if given.
+    pub fn new(
+        enabled: Arc<AtomicBool>,
+        volume: Arc<Volume>,
+        notifications: bool,
+        toggle_on: TrayToggle,
+        control_sender: Sender<Control>,
+        icon_enabled: Option<&Path>,
+        icon_disabled: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let icon_enabled = load_icon(icon_enabled, include_bytes!("../assets/icon_enabled.png"))?;
+        let icon_disabled =
+            load_icon(icon_disabled, include_bytes!("../assets/icon_disabled.png"))?;
 
         let service = TrayService::new(TrayImpl {
-            enabled: enabled.clone(),
+            enabled,
+            volume,
+            stream_errors: 0,
+            notifications,
+            toggle_on,
+            devices: Vec::new(),
+            mute_until: None,
+            mute_generation: Arc::new(AtomicU64::new(0)),
+            control_sender,
             icon_enabled,
             icon_disabled,
         });
-        service.spawn();
+        let handle = service.handle();
+        let thread = thread::spawn(move || {
+            if let Err(e) = service.run() {
+                error!("tray icon error: {e}");
+            }
+        });
+
+        Ok(Self { handle, thread })
+    }
 
-        Ok(Self { enabled })
+    /// Redraws the tray icon and menu, after `enabled`, the volume or the number of
+    /// `stream_errors` the audio stream recovered from were changed elsewhere.
+    pub fn refresh(&self, stream_errors: u64) {
+        self.handle
+            .update(|tray| tray.stream_errors = stream_errors);
     }
 
-    pub fn service_enabled(&self) -> bool {
-        self.enabled.load(Ordering::Relaxed)
+    /// Shows the open input `devices` in the tooltip and the menu, or a warning if there are
+    /// none.
+    pub fn set_devices(&self, devices: Vec<DeviceInfo>) {
+        self.handle.update(|tray| tray.devices.clone_from(&devices));
+    }
+
+    /// Removes the tray icon, and waits for the tray service to stop.
+    pub fn shutdown(self) {
+        self.handle.shutdown();
+        if self.thread.join().is_err() {
+            error!("tray icon service panicked");
+        }
     }
 }
 
-fn decode_png(png: &[u8]) -> Icon {
-    let decoder = png::Decoder::new(png);
-    let mut reader = decoder.read_info().unwrap();
-    assert_eq!(
-        reader.output_color_type(),
-        (ColorType::Rgba, BitDepth::Eight)
-    );
+/// Loads the icon at `path`, or decodes the `embedded` icon if there's no path or it can't be
+/// loaded.
+fn load_icon(path: Option<&Path>, embedded: &[u8]) -> anyhow::Result<Icon> {
+    if let Some(path) = path {
+        let res = fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|png| decode_png(&png));
+        match res.with_context(|| format!("failed to load tray icon '{}'", path.display())) {
+            Ok(icon) => return Ok(icon),
+            Err(e) => warn!("{e:#}; using the default icon"),
+        }
+    }
+    decode_png(embedded).context("failed to decode built-in tray icon")
+}
 
-    let mut buf = vec![0; reader.info().width as usize * reader.info().height as usize * 4];
-    reader.next_frame(&mut buf).unwrap();
+/// Decodes a PNG image of any color type into an icon.
+fn decode_png(png: &[u8]) -> anyhow::Result<Icon> {
+    let mut decoder = png::Decoder::new(png);
+    // Expands palettes and low bit depths, and turns 16-bit samples into 8-bit ones.
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (color_type, bit_depth) = reader.output_color_type();
+    let samples = match (color_type, bit_depth) {
+        (ColorType::Grayscale, BitDepth::Eight) => 1,
+        (ColorType::GrayscaleAlpha, BitDepth::Eight) => 2,
+        (ColorType::Rgb, BitDepth::Eight) => 3,
+        (ColorType::Rgba, BitDepth::Eight) => 4,
+        _ => bail!("unsupported PNG format {color_type:?} with bit depth {bit_depth:?}"),
+    };
 
-    for pix in buf.chunks_exact_mut(4) {
-        let pix: &mut [u8; 4] = pix.try_into().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
 
-        let [r, g, b, a] = *pix;
-        *pix = [a, r, g, b];
-    }
+    // The tray expects ARGB pixels.
+    let buf = buf
+        .chunks_exact(samples)
+        .flat_map(|pix| match *pix {
+            [l] => [0xff, l, l, l],
+            [l, a] => [a, l, l, l],
+            [r, g, b] => [0xff, r, g, b],
+            [r, g, b, a] => [a, r, g, b],
+            _ => unreachable!(),
+        })
+        .collect();
 
-    Icon {
+    Ok(Icon {
         width: reader.info().width as _,
         height: reader.info().height as _,
         data: buf,
-    }
+    })
 }
 
 struct TrayImpl {
     enabled: Arc<AtomicBool>,
+    volume: Arc<Volume>,
+    /// The number of playback errors the audio stream recovered from, shown in the tooltip.
+    stream_errors: u64,
+    notifications: bool,
+    toggle_on: TrayToggle,
+    /// The open input devices.
+    devices: Vec<DeviceInfo>,
+    /// When the current temporary mute ends, if one was started.
+    mute_until: Option<Instant>,
+    /// Incremented whenever a temporary mute is started or cancelled, so that the timers of
+    /// earlier mutes know to do nothing.
+    mute_generation: Arc<AtomicU64>,
+    control_sender: Sender<Control>,
     icon_enabled: Icon,
     icon_disabled: Icon,
 }
 
+impl TrayImpl {
+    fn toggle(&mut self) {
+        self.cancel_mute();
+        let enabled = !self.enabled.fetch_xor(true, Ordering::Relaxed);
+        self.changed();
+        if self.notifications {
+            notification::show(if enabled {
+                "clickd enabled"
+            } else {
+                "clickd disabled"
+            });
+        }
+    }
+
+    /// Tells the main thread that `enabled` or the volume were changed from the menu.
+    fn changed(&self) {
+        self.control_sender.send(Control::StateChanged).ok();
+    }
+
+    /// Disables clicking, and re-enables it after `minutes` have passed.
+    fn mute(&mut self, minutes: u64) {
+        let duration = Duration::from_secs(minutes * 60);
+        let generation = self.mute_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        self.mute_until = Some(Instant::now() + duration);
+        self.enabled.store(false, Ordering::Relaxed);
+        self.changed();
+
+        let mute_generation = self.mute_generation.clone();
+        let enabled = self.enabled.clone();
+        let control_sender = self.control_sender.clone();
+        let notifications = self.notifications;
+        thread::spawn(move || {
+            thread::sleep(duration);
+            if mute_generation.load(Ordering::Relaxed) == generation {
+                enabled.store(true, Ordering::Relaxed);
+                control_sender.send(Control::StateChanged).ok();
+                if notifications {
+                    notification::show("clickd enabled");
+                }
+            }
+        });
+    }
+
+    /// Returns whether a temporary mute is in effect.
+    fn is_muted(&self) -> bool {
+        self.mute_until
+            .is_some_and(|until| until > Instant::now() && !self.enabled.load(Ordering::Relaxed))
+    }
+
+    /// Stops the timer of the current temporary mute, if any, without re-enabling clicking.
+    fn cancel_mute(&mut self) {
+        self.mute_generation.fetch_add(1, Ordering::Relaxed);
+        self.mute_until = None;
+    }
+}
+
 impl Tray for TrayImpl {
     fn id(&self) -> String {
         "clickd".into()
     }
 
     fn title(&self) -> String {
-        if self.enabled.load(Ordering::Relaxed) {
-            "clickd - enabled (click to disable)".into()
+        if self.devices.is_empty() {
+            "clickd - no input devices found".into()
         } else {
-            "clickd - disabled (click to enable)".into()
+            match (self.enabled.load(Ordering::Relaxed), self.toggle_on) {
+                (true, TrayToggle::Activate) => "clickd - enabled (click to disable)".into(),
+                (false, TrayToggle::Activate) => "clickd - disabled (click to enable)".into(),
+                (true, TrayToggle::MenuOnly) => "clickd - enabled".into(),
+                (false, TrayToggle::MenuOnly) => "clickd - disabled".into(),
+            }
+        }
+    }
+
+    fn status(&self) -> Status {
+        if self.devices.is_empty() {
+            Status::NeedsAttention
+        } else {
+            Status::Active
+        }
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        let mut description = if self.devices.is_empty() {
+            "No input device matches the configuration.".into()
+        } else {
+            let mut description = format!("Listening to {} input device(s):", self.devices.len());
+            for device in &self.devices {
+                description += "\n";
+                description += &device.name;
+                if !device.enabled.load(Ordering::Relaxed) {
+                    description += " (disabled)";
+                }
+            }
+            description
+        };
+        match self.stream_errors {
+            0 => {}
+            errors => description += &format!("\n{errors} audio playback error(s) since startup"),
+        }
+        ToolTip {
+            title: self.title(),
+            description,
+            ..Default::default()
         }
     }
 
     fn activate(&mut self, _x: i32, _y: i32) {
-        self.enabled.fetch_xor(true, Ordering::Relaxed);
+        if self.toggle_on == TrayToggle::Activate {
+            self.toggle();
+        }
     }
 
     fn icon_pixmap(&self) -> Vec<ksni::Icon> {
@@ -88,4 +295,105 @@ impl Tray for TrayImpl {
             vec![self.icon_disabled.clone()]
         }
     }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        // Select whichever level is closest to the current volume, which may have been set to any
+        // value in the config.
+        let volume = self.volume.get();
+        let selected = (0..VOLUME_LEVELS.len())
+            .min_by(|&a, &b| {
+                let dist = |i: usize| (VOLUME_LEVELS[i] - volume).abs();
+                dist(a).total_cmp(&dist(b))
+            })
+            .unwrap();
+
+        vec![
+            CheckmarkItem {
+                label: "Enabled".into(),
+                checked: self.enabled.load(Ordering::Relaxed),
+                activate: Box::new(|this: &mut Self| this.toggle()),
+                ..Default::default()
+            }
+            .into(),
+            SubMenu {
+                label: "Volume".into(),
+                submenu: vec![RadioGroup {
+                    selected,
+                    select: Box::new(|this: &mut Self, i| {
+                        this.volume.set(VOLUME_LEVELS[i]);
+                        this.changed();
+                    }),
+                    options: VOLUME_LEVELS
+                        .iter()
+                        .map(|level| RadioItem {
+                            label: format!("{}%", level * 100.0),
+                            ..Default::default()
+                        })
+                        .collect(),
+                }
+                .into()],
+                ..Default::default()
+            }
+            .into(),
+            SubMenu {
+                label: "Mute for".into(),
+                submenu: MUTE_MINUTES
+                    .iter()
+                    .map(|&minutes| {
+                        StandardItem {
+                            label: format!("{minutes} minutes"),
+                            activate: Box::new(move |this: &mut Self| this.mute(minutes)),
+                            ..Default::default()
+                        }
+                        .into()
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Unmute".into(),
+                enabled: self.is_muted(),
+                activate: Box::new(|this: &mut Self| {
+                    this.cancel_mute();
+                    this.enabled.store(true, Ordering::Relaxed);
+                    this.changed();
+                }),
+                ..Default::default()
+            }
+            .into(),
+            SubMenu {
+                label: "Devices".into(),
+                enabled: !self.devices.is_empty(),
+                submenu: self
+                    .devices
+                    .iter()
+                    .map(|device| {
+                        let enabled = device.enabled.clone();
+                        CheckmarkItem {
+                            label: device.name.clone(),
+                            checked: enabled.load(Ordering::Relaxed),
+                            activate: Box::new(move |_: &mut Self| {
+                                enabled.fetch_xor(true, Ordering::Relaxed);
+                            }),
+                            ..Default::default()
+                        }
+                        .into()
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            StandardItem {
+                label: "Quit".into(),
+                activate: Box::new(|this: &mut Self| {
+                    // The main thread is already shutting down if Quit was clicked before.
+                    this.control_sender.send(Control::Quit).ok();
+                }),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
 }
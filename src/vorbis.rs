@@ -0,0 +1,1882 @@
+//! A decoder for Ogg Vorbis files.
+//!
+//! A Vorbis stream starts with three header packets: the identification header with the format,
+//! the comment header with tags, and the setup header with the codebooks and the configurations
+//! that audio packets refer to. Each audio packet holds a block of MDCT coefficients for every
+//! channel, stored as a coarse spectral envelope (the floor) that is multiplied by a finer
+//! residue. Consecutive blocks overlap by half after the inverse MDCT, and the overlapping parts
+//! are added together. See the Vorbis I specification for the details.
+
+use std::{
+    f64::consts::{FRAC_PI_2, PI},
+    ops::{Add, Mul, Range, Sub},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{ogg, sound::Sound};
+
+const TRUNCATED: &str = "Vorbis header is truncated";
+
+/// The sync pattern that starts every codebook.
+const CODEBOOK_SYNC: u32 = 0x56_4342;
+
+/// The range of a floor's Y coordinates for each multiplier.
+const FLOOR_RANGES: [i32; 4] = [256, 128, 86, 64];
+
+/// The position in Vorbis' channel order of each channel in WAV's, for the layouts that Vorbis
+/// defines (which differ in where the center and LFE channels go).
+const CHANNEL_ORDER: [&[usize]; 8] = [
+    &[0],
+    &[0, 1],
+    &[0, 2, 1],
+    &[0, 1, 2, 3],
+    &[0, 2, 1, 3, 4],
+    &[0, 2, 1, 5, 3, 4],
+    &[0, 2, 1, 6, 5, 3, 4],
+    &[0, 2, 1, 7, 5, 6, 3, 4],
+];
+
+/// Decodes an Ogg Vorbis file.
+pub fn decode(data: &[u8]) -> anyhow::Result<Sound> {
+    let stream = ogg::read(data)?;
+    let mut packets = stream.packets.iter();
+    let identification = packets.next().context("Ogg file is empty")?;
+    if identification.starts_with(b"OpusHead") {
+        bail!("Ogg Opus files are not supported");
+    }
+    if !identification.starts_with(b"\x01vorbis") {
+        bail!("Ogg file doesn't contain a Vorbis stream");
+    }
+    let info = Info::parse(&identification[7..])?;
+    if !packets
+        .next()
+        .is_some_and(|packet| packet.starts_with(b"\x03vorbis"))
+    {
+        bail!("Vorbis comment header is missing");
+    }
+    let setup = match packets.next() {
+        Some(packet) if packet.starts_with(b"\x05vorbis") => Setup::parse(&packet[7..], &info)?,
+        _ => bail!("Vorbis setup header is missing"),
+    };
+
+    let mut decoder = Decoder::new(&info, setup);
+    let mut channels = vec![Vec::new(); info.channels];
+    for packet in packets {
+        decoder.decode(packet, &mut channels)?;
+    }
+
+    let mut frames = channels[0].len();
+    if let Some(granule) = stream.granule {
+        frames = frames.min(usize::try_from(granule).unwrap_or(usize::MAX));
+    }
+    let order = CHANNEL_ORDER.get(info.channels - 1).copied().unwrap_or(&[]);
+    let mut samples = Vec::with_capacity(frames * channels.len());
+    for i in 0..frames {
+        samples.extend((0..channels.len()).map(|channel| {
+            let channel = order.get(channel).copied().unwrap_or(channel);
+            channels[channel][i]
+        }));
+    }
+
+    Ok(Sound {
+        channels: info.channels as u16,
+        sample_rate: info.sample_rate,
+        samples,
+    })
+}
+
+/// The contents of the identification header that are needed for decoding.
+struct Info {
+    channels: usize,
+    sample_rate: u32,
+    /// The sizes of short and long blocks.
+    block_sizes: [usize; 2],
+}
+
+impl Info {
+    fn parse(header: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = BitReader::new(header);
+        let version = reader.bits(32)?;
+        let channels = reader.bits(8)? as usize;
+        let sample_rate = reader.bits(32)?;
+        // Skip the maximum, nominal and minimum bitrates.
+        for _ in 0..3 {
+            reader.bits(32)?;
+        }
+        let block_sizes = [1 << reader.bits(4)?, 1 << reader.bits(4)?];
+        if version != 0 {
+            bail!("Vorbis version {version} is not supported");
+        }
+        if channels == 0 {
+            bail!("Vorbis file has no channels");
+        }
+        if sample_rate == 0 {
+            bail!("Vorbis file has an invalid sample rate of 0");
+        }
+        if block_sizes[0] < 64 || block_sizes[0] > block_sizes[1] || block_sizes[1] > 8192 {
+            bail!("Vorbis file has invalid block sizes {block_sizes:?}");
+        }
+        if reader.bits(1)? != 1 {
+            bail!("Vorbis identification header is invalid");
+        }
+        Ok(Self {
+            channels,
+            sample_rate,
+            block_sizes,
+        })
+    }
+}
+
+/// Reads a packet as a stream of bits, starting with the lowest bit of each byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// The position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Reads `count` bits (at most 32), or returns `None` if the packet ends first. Every read
+    /// after that fails too.
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let end = self.pos + count as usize;
+        if end > self.data.len() * 8 {
+            self.pos = self.data.len() * 8;
+            return None;
+        }
+        let mut value = 0u64;
+        let mut shift = 0;
+        while self.pos < end {
+            let offset = self.pos % 8;
+            let take = (8 - offset).min(end - self.pos);
+            let bits = u64::from(self.data[self.pos / 8] >> offset) & ((1 << take) - 1);
+            value |= bits << shift;
+            shift += take;
+            self.pos += take;
+        }
+        Some(value as u32)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        self.read(1).map(|bit| bit == 1)
+    }
+
+    /// Reads `count` bits of a header, where the end of the packet is an error.
+    fn bits(&mut self, count: u32) -> anyhow::Result<u32> {
+        self.read(count).context(TRUNCATED)
+    }
+
+    /// Reads an index into a list of `len` things that the setup header defines.
+    fn index(&mut self, count: u32, len: usize, what: &str) -> anyhow::Result<usize> {
+        let index = self.bits(count)? as usize;
+        if index >= len {
+            bail!("Vorbis setup header refers to {what} {index}, which doesn't exist");
+        }
+        Ok(index)
+    }
+}
+
+/// Returns the number of bits needed to store `x`.
+fn ilog(x: u32) -> u32 {
+    32 - x.leading_zeros()
+}
+
+/// Unpacks the floating point format of codebook lookup tables.
+fn unpack_float(x: u32) -> f32 {
+    let mantissa = f64::from(x & 0x1F_FFFF);
+    let exponent = (x >> 21 & 0x3FF) as i32 - 788;
+    let value = (mantissa * 2f64.powi(exponent)) as f32;
+    if x & 0x8000_0000 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Returns the number of values in a lookup table of type 1, which is the largest integer whose
+/// `dimensions`-th power is at most `entries`.
+fn lookup1_values(entries: usize, dimensions: usize) -> usize {
+    let fits = |values: usize| {
+        u32::try_from(dimensions)
+            .ok()
+            .and_then(|dimensions| values.checked_pow(dimensions))
+            .is_some_and(|count| count <= entries)
+    };
+    let mut values = (entries as f64).powf(1.0 / dimensions as f64) as usize;
+    // Fix up rounding errors of the estimate.
+    while fits(values + 1) {
+        values += 1;
+    }
+    while values > 0 && !fits(values) {
+        values -= 1;
+    }
+    values
+}
+
+/// Assigns Huffman codewords to the entries of a codebook, given their lengths (or 0 for unused
+/// entries). Each codeword is returned with its length, left-aligned in the `u32`.
+///
+/// Entries get the numerically smallest codeword that doesn't have an earlier codeword as a
+/// prefix, in order.
+fn codewords(lengths: &[u8]) -> anyhow::Result<Vec<Option<(u32, u8)>>> {
+    let mut codewords = vec![None; lengths.len()];
+    // The next free codeword of each length, left-aligned, or 0 if there is none.
+    let mut available = [0u32; 33];
+    let mut first = true;
+    for (entry, &len) in lengths.iter().enumerate() {
+        if len == 0 {
+            continue;
+        }
+        let len = usize::from(len);
+        if first {
+            first = false;
+            codewords[entry] = Some((0, len as u8));
+            for (i, available) in available.iter_mut().enumerate().take(len + 1).skip(1) {
+                *available = 1 << (32 - i);
+            }
+            continue;
+        }
+        // Take the longest free codeword that isn't longer than this one, and free up the
+        // codewords that branch off of the rest of the way.
+        let Some(found) = (1..=len).rev().find(|&i| available[i] != 0) else {
+            bail!("Vorbis codebook has invalid codeword lengths");
+        };
+        let codeword = available[found];
+        available[found] = 0;
+        codewords[entry] = Some((codeword, len as u8));
+        for (i, available) in available
+            .iter_mut()
+            .enumerate()
+            .take(len + 1)
+            .skip(found + 1)
+        {
+            *available = codeword + (1 << (32 - i));
+        }
+    }
+    Ok(codewords)
+}
+
+#[derive(Clone, Copy)]
+enum Node {
+    Empty,
+    Branch(u32),
+    Entry(u32),
+}
+
+struct Codebook {
+    dimensions: usize,
+    /// A binary tree for decoding entries: each node has children for reading a 0 and a 1.
+    tree: Vec<[Node; 2]>,
+    /// The `dimensions` values of each entry, if the codebook has a lookup table.
+    values: Option<Vec<f32>>,
+}
+
+impl Codebook {
+    fn parse(reader: &mut BitReader) -> anyhow::Result<Self> {
+        if reader.bits(24)? != CODEBOOK_SYNC {
+            bail!("Vorbis codebook is invalid");
+        }
+        let dimensions = reader.bits(16)? as usize;
+        let entries = reader.bits(24)? as usize;
+
+        let mut lengths = vec![0u8; entries];
+        let ordered = reader.bits(1)? == 1;
+        if ordered {
+            // Runs of entries with increasing lengths.
+            let mut len = reader.bits(5)? + 1;
+            let mut entry = 0;
+            while entry < entries {
+                let count = reader.bits(ilog((entries - entry) as u32))? as usize;
+                if len > 32 || entry + count > entries {
+                    bail!("Vorbis codebook has invalid codeword lengths");
+                }
+                lengths[entry..entry + count].fill(len as u8);
+                entry += count;
+                len += 1;
+            }
+        } else {
+            let sparse = reader.bits(1)? == 1;
+            for len in &mut lengths {
+                if !sparse || reader.bits(1)? == 1 {
+                    *len = reader.bits(5)? as u8 + 1;
+                }
+            }
+        }
+
+        let lookup = reader.bits(4)?;
+        let values = match lookup {
+            0 => None,
+            1 | 2 => {
+                if dimensions == 0 || entries * dimensions > 1 << 24 {
+                    bail!("Vorbis codebook has an invalid size");
+                }
+                let minimum = unpack_float(reader.bits(32)?);
+                let delta = unpack_float(reader.bits(32)?);
+                let value_bits = reader.bits(4)? + 1;
+                let sequence = reader.bits(1)? == 1;
+                let count = if lookup == 1 {
+                    lookup1_values(entries, dimensions)
+                } else {
+                    entries * dimensions
+                };
+                let multiplicands = (0..count)
+                    .map(|_| reader.bits(value_bits))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+
+                let mut values = Vec::with_capacity(entries * dimensions);
+                for entry in 0..entries {
+                    let mut last = 0.0;
+                    let mut divisor = 1;
+                    for i in 0..dimensions {
+                        // Type 1 tables are a lattice, with an entry's number made of one digit
+                        // for each dimension, while type 2 tables list every value.
+                        let offset = if lookup == 1 {
+                            let offset = entry / divisor % count;
+                            divisor *= count;
+                            offset
+                        } else {
+                            entry * dimensions + i
+                        };
+                        let value = multiplicands[offset] as f32 * delta + minimum + last;
+                        if sequence {
+                            last = value;
+                        }
+                        values.push(value);
+                    }
+                }
+                Some(values)
+            }
+            _ => bail!("Vorbis codebook has an invalid lookup type {lookup}"),
+        };
+
+        let codewords = codewords(&lengths)?;
+        let mut tree = vec![[Node::Empty; 2]];
+        let mut used = codewords
+            .iter()
+            .enumerate()
+            .filter_map(|(entry, codeword)| Some((entry as u32, (*codeword)?)));
+        if let (Some((entry, _)), None) = (used.clone().next(), used.clone().nth(1)) {
+            // A single codeword is decoded from any one bit.
+            tree[0] = [Node::Entry(entry); 2];
+        } else {
+            for (entry, (codeword, len)) in &mut used {
+                let mut node = 0;
+                for i in 0..u32::from(len) {
+                    let bit = (codeword >> (31 - i) & 1) as usize;
+                    if i + 1 == u32::from(len) {
+                        tree[node][bit] = Node::Entry(entry);
+                    } else if let Node::Branch(next) = tree[node][bit] {
+                        node = next as usize;
+                    } else {
+                        tree.push([Node::Empty; 2]);
+                        tree[node][bit] = Node::Branch(tree.len() as u32 - 1);
+                        node = tree.len() - 1;
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            dimensions,
+            tree,
+            values,
+        })
+    }
+
+    /// Reads the number of an entry, or returns `None` at the end of the packet or for a
+    /// codeword that doesn't belong to any entry.
+    fn entry(&self, reader: &mut BitReader) -> Option<u32> {
+        let mut node = 0;
+        loop {
+            match self.tree[node][reader.read(1)? as usize] {
+                Node::Empty => return None,
+                Node::Branch(next) => node = next as usize,
+                Node::Entry(entry) => return Some(entry),
+            }
+        }
+    }
+
+    /// Reads an entry and returns its values.
+    fn vector(&self, reader: &mut BitReader) -> Option<&[f32]> {
+        let entry = self.entry(reader)? as usize;
+        let values = self.values.as_ref()?;
+        Some(&values[entry * self.dimensions..(entry + 1) * self.dimensions])
+    }
+}
+
+struct FloorClass {
+    dimensions: usize,
+    subclass_bits: u32,
+    /// The codebook that selects the subclass of each point, if there is more than one.
+    masterbook: Option<usize>,
+    /// The codebook of each subclass, or `None` for points that are predicted exactly.
+    books: Vec<Option<usize>>,
+}
+
+/// A floor of type 1, which is a piecewise linear curve (on a dB scale) through points whose
+/// X coordinates are fixed and whose Y coordinates are stored in every packet.
+struct Floor {
+    /// The class of each partition of points.
+    partitions: Vec<usize>,
+    classes: Vec<FloorClass>,
+    multiplier: i32,
+    /// The X coordinates of the points, in the order their Y coordinates are stored in.
+    xs: Vec<usize>,
+    /// The indices of the points, sorted by their X coordinates.
+    sorted: Vec<usize>,
+    /// The points from which the Y coordinate of each point from the third on is predicted:
+    /// the earlier ones that are closest to it on the left and right.
+    neighbors: Vec<(usize, usize)>,
+}
+
+impl Floor {
+    fn parse(reader: &mut BitReader, codebooks: usize) -> anyhow::Result<Self> {
+        let partitions = (0..reader.bits(5)?)
+            .map(|_| Ok(reader.bits(4)? as usize))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let class_count = partitions.iter().max().map_or(0, |&class| class + 1);
+        let mut classes = Vec::with_capacity(class_count);
+        for _ in 0..class_count {
+            let dimensions = reader.bits(3)? as usize + 1;
+            let subclass_bits = reader.bits(2)?;
+            let masterbook = match subclass_bits {
+                0 => None,
+                _ => Some(reader.index(8, codebooks, "codebook")?),
+            };
+            let books = (0..1 << subclass_bits)
+                .map(|_| match reader.bits(8)? {
+                    0 => Ok(None),
+                    book if book as usize > codebooks => {
+                        bail!(
+                            "Vorbis setup header refers to codebook {}, which doesn't exist",
+                            book - 1
+                        )
+                    }
+                    book => Ok(Some(book as usize - 1)),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            classes.push(FloorClass {
+                dimensions,
+                subclass_bits,
+                masterbook,
+                books,
+            });
+        }
+        let multiplier = reader.bits(2)? as i32 + 1;
+        let range_bits = reader.bits(4)?;
+
+        let mut xs = vec![0, 1 << range_bits];
+        for &class in &partitions {
+            for _ in 0..classes[class].dimensions {
+                xs.push(reader.bits(range_bits)? as usize);
+            }
+        }
+        if xs.len() > 65 {
+            bail!("Vorbis floor has too many points");
+        }
+        let mut sorted = (0..xs.len()).collect::<Vec<_>>();
+        sorted.sort_by_key(|&i| xs[i]);
+        if sorted.windows(2).any(|pair| xs[pair[0]] == xs[pair[1]]) {
+            bail!("Vorbis floor has several points with the same X coordinate");
+        }
+        let neighbors = (2..xs.len())
+            .map(|i| {
+                let before = (0..i).filter(|&j| xs[j] < xs[i]);
+                let after = (0..i).filter(|&j| xs[j] > xs[i]);
+                // The first two points are at both ends, so these always exist.
+                let low = before.max_by_key(|&j| xs[j]).unwrap();
+                let high = after.min_by_key(|&j| xs[j]).unwrap();
+                (low, high)
+            })
+            .collect();
+
+        Ok(Self {
+            partitions,
+            classes,
+            multiplier,
+            xs,
+            sorted,
+            neighbors,
+        })
+    }
+
+    /// Reads the coded Y coordinates of the points, or returns `None` if the channel is unused
+    /// in this packet (which the end of the packet means too).
+    fn read(&self, reader: &mut BitReader, codebooks: &[Codebook]) -> Option<Vec<i32>> {
+        if !reader.flag()? {
+            return None;
+        }
+        let range = FLOOR_RANGES[self.multiplier as usize - 1];
+        let bits = ilog(range as u32 - 1);
+        let mut ys = Vec::with_capacity(self.xs.len());
+        ys.push(reader.read(bits)? as i32);
+        ys.push(reader.read(bits)? as i32);
+        for &class in &self.partitions {
+            let class = &self.classes[class];
+            let mut subclasses = match class.masterbook {
+                Some(book) => codebooks[book].entry(reader)?,
+                None => 0,
+            };
+            for _ in 0..class.dimensions {
+                let book = class.books[(subclasses & ((1 << class.subclass_bits) - 1)) as usize];
+                subclasses >>= class.subclass_bits;
+                ys.push(match book {
+                    Some(book) => codebooks[book].entry(reader)? as i32,
+                    None => 0,
+                });
+            }
+        }
+        Some(ys)
+    }
+
+    /// Computes the `n` values of the curve, given the coded Y coordinates of the points.
+    fn curve(&self, coded: &[i32], n: usize, inverse_db: &[f32; 256]) -> Vec<f32> {
+        // Each point after the first two is stored as its offset from the line between its
+        // neighbors, and points that are exactly on that line are left out of the curve.
+        let range = FLOOR_RANGES[self.multiplier as usize - 1];
+        let mut ys = coded.to_vec();
+        let mut used = vec![true; ys.len()];
+        for (i, &(low, high)) in self.neighbors.iter().enumerate() {
+            let i = i + 2;
+            let predicted =
+                render_point(self.xs[low], ys[low], self.xs[high], ys[high], self.xs[i]);
+            let value = coded[i];
+            let high_room = range - predicted;
+            let low_room = predicted;
+            let room = high_room.min(low_room) * 2;
+            if value == 0 {
+                used[i] = false;
+                ys[i] = predicted;
+                continue;
+            }
+            used[low] = true;
+            used[high] = true;
+            ys[i] = if value >= room {
+                if high_room > low_room {
+                    value - low_room + predicted
+                } else {
+                    predicted - value + high_room - 1
+                }
+            } else if value % 2 == 1 {
+                predicted - (value + 1) / 2
+            } else {
+                predicted + value / 2
+            };
+        }
+
+        let mut curve = vec![0; n];
+        let (mut x0, mut y0) = (0, ys[0] * self.multiplier);
+        for &i in &self.sorted[1..] {
+            if used[i] {
+                let (x1, y1) = (self.xs[i], ys[i] * self.multiplier);
+                render_line(x0, y0, x1, y1, &mut curve);
+                (x0, y0) = (x1, y1);
+            }
+        }
+        if x0 < n {
+            render_line(x0, y0, n, y0, &mut curve);
+        }
+        curve
+            .into_iter()
+            .map(|y| inverse_db[y.clamp(0, 255) as usize])
+            .collect()
+    }
+}
+
+/// Returns the Y coordinate at `x` of the line between two points, rounded towards the first.
+fn render_point(x0: usize, y0: i32, x1: usize, y1: i32, x: usize) -> i32 {
+    let dy = y1 - y0;
+    let offset = dy.abs() * (x - x0) as i32 / (x1 - x0) as i32;
+    if dy < 0 {
+        y0 - offset
+    } else {
+        y0 + offset
+    }
+}
+
+/// Draws the line between two points into `curve`, from `x0` up to but excluding `x1`, with
+/// integer steps like Bresenham's algorithm.
+fn render_line(x0: usize, y0: i32, x1: usize, y1: i32, curve: &mut [i32]) {
+    let dy = y1 - y0;
+    let dx = (x1 - x0) as i32;
+    let base = dy / dx;
+    let step = if dy < 0 { base - 1 } else { base + 1 };
+    let remainder = dy.abs() - base.abs() * dx;
+    let mut y = y0;
+    let mut error = 0;
+    for x in x0..x1.min(curve.len()) {
+        if x > x0 {
+            error += remainder;
+            if error >= dx {
+                error -= dx;
+                y += step;
+            } else {
+                y += base;
+            }
+        }
+        curve[x] = y;
+    }
+}
+
+/// Returns the table that maps the floor's dB scale to amplitudes, which covers 140 dB.
+fn inverse_db_table() -> [f32; 256] {
+    std::array::from_fn(|i| 10f64.powf(-7.0 * (255 - i) as f64 / 256.0) as f32)
+}
+
+struct Residue {
+    /// 0 and 1 store each channel's vector separately, with the values of entries interleaved
+    /// or in order, respectively. 2 interleaves all channels into one vector.
+    kind: u32,
+    begin: usize,
+    end: usize,
+    partition_size: usize,
+    classifications: usize,
+    classbook: usize,
+    /// The codebook of each class in each of the 8 passes, if it has one.
+    books: Vec<[Option<usize>; 8]>,
+}
+
+impl Residue {
+    fn parse(reader: &mut BitReader, kind: u32, codebooks: &[Codebook]) -> anyhow::Result<Self> {
+        let begin = reader.bits(24)? as usize;
+        let end = reader.bits(24)? as usize;
+        let partition_size = reader.bits(24)? as usize + 1;
+        let classifications = reader.bits(6)? as usize + 1;
+        let classbook = reader.index(8, codebooks.len(), "codebook")?;
+        let cascades = (0..classifications)
+            .map(|_| {
+                let low = reader.bits(3)?;
+                let high = if reader.bits(1)? == 1 {
+                    reader.bits(5)?
+                } else {
+                    0
+                };
+                Ok(high << 3 | low)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut books = Vec::with_capacity(classifications);
+        for cascade in cascades {
+            let mut passes = [None; 8];
+            for (pass, book) in passes.iter_mut().enumerate() {
+                if cascade & 1 << pass != 0 {
+                    let index = reader.index(8, codebooks.len(), "codebook")?;
+                    if codebooks[index].values.is_none() {
+                        bail!("Vorbis residue uses a codebook without values");
+                    }
+                    *book = Some(index);
+                }
+            }
+            books.push(passes);
+        }
+        if codebooks[classbook].dimensions == 0 {
+            bail!("Vorbis residue uses an invalid codebook for classifications");
+        }
+        Ok(Self {
+            kind,
+            begin,
+            end,
+            partition_size,
+            classifications,
+            classbook,
+            books,
+        })
+    }
+
+    /// Decodes the residue vectors of the channels that aren't `skipped` into `vectors`, which
+    /// are all 0.
+    fn decode(
+        &self,
+        reader: &mut BitReader,
+        codebooks: &[Codebook],
+        vectors: &mut [Vec<f32>],
+        skipped: &[bool],
+    ) {
+        if self.kind != 2 {
+            self.decode_partitions(reader, codebooks, vectors, skipped);
+            return;
+        }
+        if skipped.iter().all(|&skipped| skipped) {
+            return;
+        }
+        let channels = vectors.len();
+        let mut interleaved = vec![vec![0.0; vectors[0].len() * channels]];
+        self.decode_partitions(reader, codebooks, &mut interleaved, &[false]);
+        for (i, frame) in interleaved[0].chunks(channels).enumerate() {
+            for (vector, &value) in vectors.iter_mut().zip(frame) {
+                vector[i] = value;
+            }
+        }
+    }
+
+    /// Decodes the partitions of each vector in turn. Returns `None` at the end of the packet,
+    /// which leaves the rest of the residue at 0.
+    fn decode_partitions(
+        &self,
+        reader: &mut BitReader,
+        codebooks: &[Codebook],
+        vectors: &mut [Vec<f32>],
+        skipped: &[bool],
+    ) -> Option<()> {
+        let len = vectors[0].len();
+        let begin = self.begin.min(len);
+        let end = self.end.min(len);
+        let partitions = end.saturating_sub(begin) / self.partition_size;
+        let classbook = &codebooks[self.classbook];
+        // Each entry of the classbook holds the classes of this many partitions.
+        let per_entry = classbook.dimensions;
+        let mut classes = vec![vec![0; partitions + per_entry]; vectors.len()];
+
+        for pass in 0..8 {
+            let mut partition = 0;
+            while partition < partitions {
+                if pass == 0 {
+                    for (classes, _) in classes.iter_mut().zip(skipped).filter(|(_, &s)| !s) {
+                        let mut entry = classbook.entry(reader)? as usize;
+                        for class in classes[partition..partition + per_entry].iter_mut().rev() {
+                            *class = entry % self.classifications;
+                            entry /= self.classifications;
+                        }
+                    }
+                }
+                for _ in 0..per_entry {
+                    if partition >= partitions {
+                        break;
+                    }
+                    let start = begin + partition * self.partition_size;
+                    for (channel, vector) in vectors.iter_mut().enumerate() {
+                        if skipped[channel] {
+                            continue;
+                        }
+                        let Some(book) = self.books[classes[channel][partition]][pass] else {
+                            continue;
+                        };
+                        let book = &codebooks[book];
+                        let partition = &mut vector[start..start + self.partition_size];
+                        if self.kind == 0 {
+                            let step = self.partition_size / book.dimensions;
+                            for i in 0..step {
+                                let values = book.vector(reader)?;
+                                for (j, value) in values.iter().enumerate() {
+                                    partition[i + j * step] += value;
+                                }
+                            }
+                        } else {
+                            let mut i = 0;
+                            while i < self.partition_size {
+                                for value in book.vector(reader)? {
+                                    if let Some(sample) = partition.get_mut(i) {
+                                        *sample += value;
+                                    }
+                                    i += 1;
+                                }
+                            }
+                        }
+                    }
+                    partition += 1;
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+struct Mapping {
+    /// Pairs of channels whose residues are stored as a magnitude and an angle.
+    coupling: Vec<(usize, usize)>,
+    /// The submap of each channel.
+    mux: Vec<usize>,
+    /// The floor and residue of each submap.
+    submaps: Vec<(usize, usize)>,
+}
+
+impl Mapping {
+    fn parse(
+        reader: &mut BitReader,
+        channels: usize,
+        floors: usize,
+        residues: usize,
+    ) -> anyhow::Result<Self> {
+        let kind = reader.bits(16)?;
+        if kind != 0 {
+            bail!("Vorbis mapping type {kind} is not supported");
+        }
+        let submap_count = if reader.bits(1)? == 1 {
+            reader.bits(4)? as usize + 1
+        } else {
+            1
+        };
+        let mut coupling = Vec::new();
+        if reader.bits(1)? == 1 {
+            let bits = ilog(channels as u32 - 1);
+            for _ in 0..reader.bits(8)? + 1 {
+                let magnitude = reader.index(bits, channels, "channel")?;
+                let angle = reader.index(bits, channels, "channel")?;
+                if magnitude == angle {
+                    bail!("Vorbis mapping couples a channel with itself");
+                }
+                coupling.push((magnitude, angle));
+            }
+        }
+        if reader.bits(2)? != 0 {
+            bail!("Vorbis mapping is invalid");
+        }
+        let mux = if submap_count > 1 {
+            (0..channels)
+                .map(|_| reader.index(4, submap_count, "submap"))
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            vec![0; channels]
+        };
+        let submaps = (0..submap_count)
+            .map(|_| {
+                // An unused time configuration.
+                reader.bits(8)?;
+                let floor = reader.index(8, floors, "floor")?;
+                let residue = reader.index(8, residues, "residue")?;
+                Ok((floor, residue))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            coupling,
+            mux,
+            submaps,
+        })
+    }
+}
+
+struct Mode {
+    long: bool,
+    mapping: usize,
+}
+
+/// The contents of the setup header.
+struct Setup {
+    codebooks: Vec<Codebook>,
+    floors: Vec<Floor>,
+    residues: Vec<Residue>,
+    mappings: Vec<Mapping>,
+    modes: Vec<Mode>,
+}
+
+impl Setup {
+    fn parse(header: &[u8], info: &Info) -> anyhow::Result<Self> {
+        let mut reader = BitReader::new(header);
+        let codebooks = (0..reader.bits(8)? + 1)
+            .map(|_| Codebook::parse(&mut reader))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Placeholders for time domain transforms, which Vorbis I doesn't have.
+        for _ in 0..reader.bits(6)? + 1 {
+            if reader.bits(16)? != 0 {
+                bail!("Vorbis setup header is invalid");
+            }
+        }
+
+        let floors = (0..reader.bits(6)? + 1)
+            .map(|_| match reader.bits(16)? {
+                // Floor 0 was replaced by floor 1 before Vorbis was released, and no released
+                // encoder produces it.
+                0 => bail!("Vorbis files with floor type 0 are not supported"),
+                1 => Floor::parse(&mut reader, codebooks.len()),
+                kind => bail!("Vorbis floor type {kind} is invalid"),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let residues = (0..reader.bits(6)? + 1)
+            .map(|_| match reader.bits(16)? {
+                kind @ 0..=2 => Residue::parse(&mut reader, kind, &codebooks),
+                kind => bail!("Vorbis residue type {kind} is invalid"),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mappings = (0..reader.bits(6)? + 1)
+            .map(|_| Mapping::parse(&mut reader, info.channels, floors.len(), residues.len()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let modes = (0..reader.bits(6)? + 1)
+            .map(|_| {
+                let long = reader.bits(1)? == 1;
+                let window = reader.bits(16)?;
+                let transform = reader.bits(16)?;
+                if window != 0 || transform != 0 {
+                    bail!("Vorbis mode is invalid");
+                }
+                let mapping = reader.index(8, mappings.len(), "mapping")?;
+                Ok(Mode { long, mapping })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if reader.bits(1)? != 1 {
+            bail!("Vorbis setup header is invalid");
+        }
+        Ok(Self {
+            codebooks,
+            floors,
+            residues,
+            mappings,
+            modes,
+        })
+    }
+}
+
+/// Where the slopes of a block's window are. A long block next to a short one has a short slope
+/// on that side, with zeros beyond it and ones within.
+struct Window {
+    left: Range<usize>,
+    right: Range<usize>,
+    left_long: bool,
+    right_long: bool,
+}
+
+impl Window {
+    fn new(block_sizes: [usize; 2], long: bool, previous_long: bool, next_long: bool) -> Self {
+        let n = block_sizes[usize::from(long)];
+        let short = block_sizes[0] / 4;
+        let left_long = long && previous_long;
+        let right_long = long && next_long;
+        Self {
+            left: if long && !left_long {
+                n / 4 - short..n / 4 + short
+            } else {
+                0..n / 2
+            },
+            right: if long && !right_long {
+                n * 3 / 4 - short..n * 3 / 4 + short
+            } else {
+                n / 2..n
+            },
+            left_long,
+            right_long,
+        }
+    }
+
+    /// Multiplies `block` by the window. `slopes` are the rising slopes for short and long
+    /// blocks.
+    fn apply(&self, block: &mut [f32], slopes: &[Vec<f32>; 2]) {
+        block[..self.left.start].fill(0.0);
+        let left = &slopes[usize::from(self.left_long)];
+        for (sample, weight) in block[self.left.clone()].iter_mut().zip(left) {
+            *sample *= weight;
+        }
+        let right = &slopes[usize::from(self.right_long)];
+        for (sample, weight) in block[self.right.clone()].iter_mut().zip(right.iter().rev()) {
+            *sample *= weight;
+        }
+        block[self.right.end..].fill(0.0);
+    }
+}
+
+/// Returns the rising slope of the Vorbis window, which has `len` samples.
+fn slope(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let x = (i as f64 + 0.5) / len as f64 * FRAC_PI_2;
+            (FRAC_PI_2 * x.sin().powi(2)).sin() as f32
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    /// Returns `e^(-i * angle)`.
+    fn unit(angle: f64) -> Self {
+        Self {
+            re: angle.cos() as f32,
+            im: -angle.sin() as f32,
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            re: self.re - other.re,
+            im: self.im - other.im,
+        }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+/// The inverse MDCT for one block size, which is computed as a DCT-IV of half the block size,
+/// which in turn is computed with a complex FFT of a quarter of the block size.
+struct Imdct {
+    /// `e^(-iπ(k + 1/8) / (n/2))` for each `k < n/4`, which rotate the FFT's input and output.
+    twiddles: Vec<Complex>,
+    /// `e^(-2πik / (n/4))` for each `k < n/8`, the FFT's own twiddle factors.
+    roots: Vec<Complex>,
+}
+
+impl Imdct {
+    fn new(n: usize) -> Self {
+        let half = n / 2;
+        let quarter = n / 4;
+        Self {
+            twiddles: (0..quarter)
+                .map(|k| Complex::unit(PI * (k as f64 + 0.125) / half as f64))
+                .collect(),
+            roots: (0..quarter / 2)
+                .map(|k| Complex::unit(2.0 * PI * k as f64 / quarter as f64))
+                .collect(),
+        }
+    }
+
+    /// Computes the `n` samples of a block from its `n/2` coefficients, as
+    /// `y[t] = Σ X[k] cos(2π/n (t + 1/2 + n/4)(k + 1/2))`.
+    fn inverse(&self, coefficients: &[f32], block: &mut [f32]) {
+        let half = coefficients.len();
+        let quarter = half / 2;
+        let mut data = (0..quarter)
+            .map(|k| {
+                let value = Complex {
+                    re: coefficients[2 * k],
+                    im: coefficients[half - 1 - 2 * k],
+                };
+                value * self.twiddles[k]
+            })
+            .collect::<Vec<_>>();
+        fft(&mut data, &self.roots);
+
+        // The DCT-IV is symmetric around the block's quarters in the inverse MDCT.
+        let mut dct = vec![0.0; half];
+        for (k, &value) in data.iter().enumerate() {
+            let value = value * self.twiddles[k];
+            dct[2 * k] = value.re;
+            dct[half - 1 - 2 * k] = -value.im;
+        }
+        for t in 0..quarter {
+            block[t] = dct[quarter + t];
+            block[quarter + t] = -dct[half - 1 - t];
+            block[half + t] = -dct[quarter - 1 - t];
+            block[half + quarter + t] = -dct[t];
+        }
+    }
+}
+
+/// Computes the discrete Fourier transform of `data` in place, whose length is a power of two.
+/// `roots` are `e^(-2πik / data.len())` for the first half of `k`s.
+fn fft(data: &mut [Complex], roots: &[Complex]) {
+    let len = data.len();
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let mut size = 2;
+    while size <= len {
+        let stride = len / size;
+        for chunk in data.chunks_mut(size) {
+            let (low, high) = chunk.split_at_mut(size / 2);
+            for (k, (low, high)) in low.iter_mut().zip(high).enumerate() {
+                let odd = *high * roots[k * stride];
+                (*low, *high) = (*low + odd, *low - odd);
+            }
+        }
+        size *= 2;
+    }
+}
+
+/// The second half of the last block, which overlaps with the next one.
+struct Tail {
+    /// The windowed samples of each channel.
+    channels: Vec<Vec<f32>>,
+    block_size: usize,
+    /// Where the window's falling slope starts, from the start of the block.
+    slope_start: usize,
+}
+
+struct Decoder {
+    block_sizes: [usize; 2],
+    setup: Setup,
+    imdcts: [Imdct; 2],
+    slopes: [Vec<f32>; 2],
+    inverse_db: [f32; 256],
+    tail: Option<Tail>,
+}
+
+impl Decoder {
+    fn new(info: &Info, setup: Setup) -> Self {
+        let [short, long] = info.block_sizes;
+        Self {
+            block_sizes: info.block_sizes,
+            setup,
+            imdcts: [Imdct::new(short), Imdct::new(long)],
+            slopes: [slope(short / 2), slope(long / 2)],
+            inverse_db: inverse_db_table(),
+            tail: None,
+        }
+    }
+
+    /// Decodes an audio packet, and appends the samples that are finished to each of `output`'s
+    /// channels. The first packet only fills the overlap with the second.
+    fn decode(&mut self, packet: &[u8], output: &mut [Vec<f32>]) -> anyhow::Result<()> {
+        let setup = &self.setup;
+        let mut reader = BitReader::new(packet);
+        // Other packets are ignored, as are empty ones.
+        if reader.read(1) != Some(0) {
+            return Ok(());
+        }
+        let Some(mode) = reader.read(ilog(setup.modes.len() as u32 - 1)) else {
+            return Ok(());
+        };
+        let mode = setup
+            .modes
+            .get(mode as usize)
+            .context("Vorbis audio packet uses a mode that doesn't exist")?;
+        let (previous_long, next_long) = match mode.long {
+            true => match (reader.flag(), reader.flag()) {
+                (Some(previous), Some(next)) => (previous, next),
+                _ => return Ok(()),
+            },
+            false => (false, false),
+        };
+        let n = self.block_sizes[usize::from(mode.long)];
+        let half = n / 2;
+        let mapping = &setup.mappings[mode.mapping];
+        let channels = output.len();
+
+        let floors = (0..channels)
+            .map(|channel| {
+                let floor = &setup.floors[mapping.submaps[mapping.mux[channel]].0];
+                let coded = floor.read(&mut reader, &setup.codebooks)?;
+                Some(floor.curve(&coded, half, &self.inverse_db))
+            })
+            .collect::<Vec<_>>();
+
+        // Coupled channels are decoded together if either of them is used.
+        let mut skipped = floors.iter().map(Option::is_none).collect::<Vec<_>>();
+        for &(magnitude, angle) in &mapping.coupling {
+            if !skipped[magnitude] || !skipped[angle] {
+                skipped[magnitude] = false;
+                skipped[angle] = false;
+            }
+        }
+        let mut residues = vec![vec![0.0; half]; channels];
+        for (submap, &(_, residue)) in mapping.submaps.iter().enumerate() {
+            let members = (0..channels)
+                .filter(|&channel| mapping.mux[channel] == submap)
+                .collect::<Vec<_>>();
+            if members.is_empty() {
+                continue;
+            }
+            let mut vectors = members
+                .iter()
+                .map(|&channel| std::mem::take(&mut residues[channel]))
+                .collect::<Vec<_>>();
+            let skipped = members
+                .iter()
+                .map(|&channel| skipped[channel])
+                .collect::<Vec<_>>();
+            setup.residues[residue].decode(&mut reader, &setup.codebooks, &mut vectors, &skipped);
+            for (&channel, vector) in members.iter().zip(vectors) {
+                residues[channel] = vector;
+            }
+        }
+
+        for &(magnitude, angle) in mapping.coupling.iter().rev() {
+            let mut angles = std::mem::take(&mut residues[angle]);
+            for (m, a) in residues[magnitude].iter_mut().zip(&mut angles) {
+                (*m, *a) = match (*m > 0.0, *a > 0.0) {
+                    (true, true) => (*m, *m - *a),
+                    (true, false) => (*m + *a, *m),
+                    (false, true) => (*m, *m + *a),
+                    (false, false) => (*m - *a, *m),
+                };
+            }
+            residues[angle] = angles;
+        }
+
+        let window = Window::new(self.block_sizes, mode.long, previous_long, next_long);
+        let tail = self.tail.take();
+        let mut tails = Vec::with_capacity(channels);
+        let mut block = vec![0.0; n];
+        for (channel, (floor, residue)) in floors.iter().zip(&mut residues).enumerate() {
+            block.fill(0.0);
+            if let Some(floor) = floor {
+                for (value, floor) in residue.iter_mut().zip(floor) {
+                    *value *= floor;
+                }
+                self.imdcts[usize::from(mode.long)].inverse(residue, &mut block);
+                window.apply(&mut block, &self.slopes);
+            }
+            if let Some(tail) = &tail {
+                // Output from the center of the last block to the center of this one, which is
+                // where both windows' slopes line up.
+                let frames = tail.block_size / 4 + n / 4;
+                let shift = tail.slope_start as isize
+                    - window.left.start as isize
+                    - (tail.block_size / 2) as isize;
+                let previous = &tail.channels[channel];
+                output[channel].extend((0..frames).map(|t| {
+                    let current = usize::try_from(t as isize - shift)
+                        .ok()
+                        .and_then(|t| block.get(t));
+                    previous.get(t).unwrap_or(&0.0) + current.unwrap_or(&0.0)
+                }));
+            }
+            tails.push(block[half..].to_vec());
+        }
+        self.tail = Some(Tail {
+            channels: tails,
+            block_size: n,
+            slope_start: window.right.start,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes bits starting with the lowest bit of each byte, for building test files.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn new(prefix: &[u8]) -> Self {
+            Self {
+                data: prefix.to_vec(),
+                bits: prefix.len() * 8,
+            }
+        }
+
+        fn push(&mut self, value: u32, count: u32) {
+            for i in 0..count {
+                if self.bits.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                let bit = (value >> i & 1) as u8;
+                *self.data.last_mut().unwrap() |= bit << (self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn codeword(&mut self, codewords: &[Option<(u32, u8)>], entry: usize) {
+            let (codeword, len) = codewords[entry].unwrap();
+            for i in 0..u32::from(len) {
+                self.push(codeword >> (31 - i) & 1, 1);
+            }
+        }
+    }
+
+    /// Packs an integer in the floating point format of lookup tables.
+    fn pack_float(value: i32) -> u32 {
+        let sign = if value < 0 { 1 << 31 } else { 0 };
+        sign | 788 << 21 | value.unsigned_abs()
+    }
+
+    const BLOCK_SIZES: [usize; 2] = [128, 512];
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// The codebooks of the test files: the values of floor points, the subclasses of floor
+    /// points, the classes of residue partitions, and scalar and 2D residue values.
+    fn codebook_lengths() -> [Vec<u8>; 5] {
+        [
+            vec![8; 256],
+            vec![2; 4],
+            vec![2; 4],
+            (0..255).map(|i| if i == 127 { 1 } else { 9 }).collect(),
+            vec![14; 121 * 121],
+        ]
+    }
+
+    /// The residue of each mapping: type 1 and type 0 for mono files, and type 2 for stereo ones.
+    fn residues(channels: usize) -> [usize; 2] {
+        if channels == 1 {
+            [0, 1]
+        } else {
+            [2, 2]
+        }
+    }
+
+    /// The largest coded value of each residue type.
+    fn residue_limit(kind: usize) -> i32 {
+        if kind == 0 {
+            60
+        } else {
+            127
+        }
+    }
+
+    fn identification_header(channels: usize) -> Vec<u8> {
+        let mut writer = BitWriter::new(b"\x01vorbis");
+        writer.push(0, 32);
+        writer.push(channels as u32, 8);
+        writer.push(SAMPLE_RATE, 32);
+        for _ in 0..3 {
+            writer.push(0, 32);
+        }
+        writer.push(BLOCK_SIZES[0].trailing_zeros(), 4);
+        writer.push(BLOCK_SIZES[1].trailing_zeros(), 4);
+        writer.push(1, 1);
+        writer.data
+    }
+
+    fn setup_header(channels: usize) -> Vec<u8> {
+        let mut writer = BitWriter::new(b"\x05vorbis");
+        let [values, subclasses, classes, scalars, pairs] = codebook_lengths();
+        writer.push(4, 8);
+
+        // The values of floor points, with ordered lengths.
+        writer.push(CODEBOOK_SYNC, 24);
+        writer.push(1, 16);
+        writer.push(values.len() as u32, 24);
+        writer.push(1, 1);
+        writer.push(7, 5);
+        writer.push(256, 9);
+        writer.push(0, 4);
+
+        // The subclasses of floor points, and the classes of residue partitions, which is sparse.
+        for (dimensions, lengths, sparse) in [(1, &subclasses, 0), (2, &classes, 1)] {
+            writer.push(CODEBOOK_SYNC, 24);
+            writer.push(dimensions, 16);
+            writer.push(lengths.len() as u32, 24);
+            writer.push(0, 1);
+            writer.push(sparse, 1);
+            for &len in lengths {
+                writer.push(sparse, sparse);
+                writer.push(u32::from(len) - 1, 5);
+            }
+            writer.push(0, 4);
+        }
+
+        // Scalar residue values from -127 to 127, in a lattice.
+        writer.push(CODEBOOK_SYNC, 24);
+        writer.push(1, 16);
+        writer.push(scalars.len() as u32, 24);
+        writer.push(0, 2);
+        for &len in &scalars {
+            writer.push(u32::from(len) - 1, 5);
+        }
+        writer.push(1, 4);
+        writer.push(pack_float(-127), 32);
+        writer.push(pack_float(1), 32);
+        writer.push(7, 4);
+        writer.push(0, 1);
+        for value in 0..255 {
+            writer.push(value, 8);
+        }
+
+        // Pairs of residue values from -60 to 60, listed one by one.
+        writer.push(CODEBOOK_SYNC, 24);
+        writer.push(2, 16);
+        writer.push(pairs.len() as u32, 24);
+        writer.push(0, 2);
+        for &len in &pairs {
+            writer.push(u32::from(len) - 1, 5);
+        }
+        writer.push(2, 4);
+        writer.push(pack_float(-60), 32);
+        writer.push(pack_float(1), 32);
+        writer.push(6, 4);
+        writer.push(0, 1);
+        for entry in 0..pairs.len() as u32 {
+            writer.push(entry % 121, 7);
+            writer.push(entry / 121, 7);
+        }
+
+        // No time domain transforms.
+        writer.push(0, 6);
+        writer.push(0, 16);
+
+        // One floor for each block size, with two points between the ends.
+        writer.push(1, 6);
+        for (range_bits, xs) in [(6, [16, 40]), (8, [64, 160])] {
+            writer.push(1, 16);
+            writer.push(1, 5);
+            writer.push(0, 4);
+            writer.push(1, 3);
+            writer.push(1, 2);
+            writer.push(1, 8);
+            writer.push(0, 8);
+            writer.push(1, 8);
+            writer.push(0, 2);
+            writer.push(range_bits, 4);
+            for x in xs {
+                writer.push(x, range_bits);
+            }
+        }
+
+        // Residues of type 1 for short blocks, 0 for long blocks, and 2 for both.
+        writer.push(2, 6);
+        for (kind, end, partition_size, book) in [(1, 64, 16, 3), (0, 256, 32, 4), (2, 512, 16, 3)]
+        {
+            writer.push(kind, 16);
+            writer.push(0, 24);
+            writer.push(end, 24);
+            writer.push(partition_size - 1, 24);
+            writer.push(1, 6);
+            writer.push(2, 8);
+            // Class 0 is silent, and class 1 uses `book` in the first pass.
+            writer.push(0, 3);
+            writer.push(0, 1);
+            writer.push(1, 3);
+            writer.push(0, 1);
+            writer.push(book, 8);
+        }
+
+        // A mapping for each block size.
+        writer.push(1, 6);
+        for (floor, residue) in residues(channels).into_iter().enumerate() {
+            writer.push(0, 16);
+            writer.push(0, 1);
+            if channels == 2 {
+                writer.push(1, 1);
+                writer.push(0, 8);
+                writer.push(0, 1);
+                writer.push(1, 1);
+            } else {
+                writer.push(0, 1);
+            }
+            writer.push(0, 2);
+            writer.push(0, 8);
+            writer.push(floor as u32, 8);
+            writer.push(residue as u32, 8);
+        }
+
+        // A mode for each block size.
+        writer.push(1, 6);
+        for long in [0, 1] {
+            writer.push(long, 1);
+            writer.push(0, 16);
+            writer.push(0, 16);
+            writer.push(long, 8);
+        }
+        writer.push(1, 1);
+        writer.data
+    }
+
+    /// Returns the coefficients of a windowed block, scaled to undo the inverse MDCT.
+    fn mdct(block: &[f32]) -> Vec<f32> {
+        let n = block.len();
+        (0..n / 2)
+            .map(|k| {
+                let sum = block.iter().enumerate().map(|(t, &x)| {
+                    let angle = 2.0 * PI / n as f64 * (t as f64 + 0.5 + n as f64 / 4.0);
+                    f64::from(x) * (angle * (k as f64 + 0.5)).cos()
+                });
+                (sum.sum::<f64>() * 4.0 / n as f64) as f32
+            })
+            .collect()
+    }
+
+    /// Returns the coupled magnitude and angle that decode to `left` and `right`.
+    fn couple(left: i32, right: i32) -> (i32, i32) {
+        if left > 0 && left > right {
+            (left, left - right)
+        } else if right > 0 && left <= right {
+            (right, left - right)
+        } else if left <= 0 && right > left {
+            (left, right - left)
+        } else {
+            (right, right - left)
+        }
+    }
+
+    /// Writes the residue of one submap like `Residue::decode` reads it.
+    fn write_residue(
+        writer: &mut BitWriter,
+        codewords: &[Vec<Option<(u32, u8)>>],
+        residue: &Residue,
+        vectors: &[Vec<i32>],
+        skipped: &[bool],
+    ) {
+        let (vectors, skipped) = if residue.kind == 2 {
+            if skipped.iter().all(|&skipped| skipped) {
+                return;
+            }
+            let interleaved = (0..vectors[0].len())
+                .flat_map(|i| vectors.iter().map(move |vector| vector[i]))
+                .collect();
+            (vec![interleaved], vec![false])
+        } else {
+            (vectors.to_vec(), skipped.to_vec())
+        };
+        let end = residue.end.min(vectors[0].len());
+        let size = residue.partition_size;
+        let partitions = end / size;
+        let classes = vectors
+            .iter()
+            .map(|vector| {
+                let mut classes = (0..partitions)
+                    .map(|p| usize::from(vector[p * size..(p + 1) * size].iter().any(|&v| v != 0)))
+                    .collect::<Vec<_>>();
+                classes.push(0);
+                classes
+            })
+            .collect::<Vec<_>>();
+        for partition in (0..partitions).step_by(2) {
+            for (channel, classes) in classes.iter().enumerate() {
+                if !skipped[channel] {
+                    let entry = classes[partition] * 2 + classes[partition + 1];
+                    writer.codeword(&codewords[residue.classbook], entry);
+                }
+            }
+            for partition in partition..(partition + 2).min(partitions) {
+                for (channel, vector) in vectors.iter().enumerate() {
+                    if skipped[channel] || classes[channel][partition] == 0 {
+                        continue;
+                    }
+                    let book = residue.books[1][0].unwrap();
+                    let values = &vector[partition * size..(partition + 1) * size];
+                    if residue.kind == 0 {
+                        let step = size / 2;
+                        for i in 0..step {
+                            let entry = (values[i] + 60) + 121 * (values[i + step] + 60);
+                            writer.codeword(&codewords[book], entry as usize);
+                        }
+                    } else {
+                        for &value in values {
+                            writer.codeword(&codewords[book], (value + 127) as usize);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Encodes `channels` into an Ogg Vorbis file, with blocks whose sizes follow `pattern`.
+    fn encode(channels: &[Vec<f32>], pattern: &[bool]) -> Vec<u8> {
+        let frames = channels[0].len();
+        let headers = [
+            identification_header(channels.len()),
+            b"\x03vorbis\0\0\0\0\0\0\0\0\x01".to_vec(),
+            setup_header(channels.len()),
+        ];
+        let info = Info::parse(&headers[0][7..]).unwrap();
+        let setup = Setup::parse(&headers[2][7..], &info).unwrap();
+        let codewords = codebook_lengths()
+            .iter()
+            .map(|lengths| super::codewords(lengths).unwrap())
+            .collect::<Vec<_>>();
+        let slopes = [slope(BLOCK_SIZES[0] / 2), slope(BLOCK_SIZES[1] / 2)];
+        let inverse_db = inverse_db_table();
+
+        // Pick block sizes until the blocks' centers are past the end.
+        let long = |i: usize| pattern[i % pattern.len()];
+        let mut starts = vec![-(BLOCK_SIZES[usize::from(long(0))] as isize) / 2];
+        let mut center = 0;
+        while center < frames {
+            let i = starts.len() - 1;
+            let previous = Window::new(BLOCK_SIZES, long(i), long(i + 1), long(i + 1));
+            let current = Window::new(BLOCK_SIZES, long(i + 1), long(i), long(i + 2));
+            let start = starts[i] + previous.right.start as isize - current.left.start as isize;
+            starts.push(start);
+            center = (start + BLOCK_SIZES[usize::from(long(i + 1))] as isize / 2) as usize;
+        }
+
+        let mut packets = Vec::new();
+        for (i, &start) in starts.iter().enumerate() {
+            let long_block = long(i);
+            let previous_long = i == 0 || long(i - 1);
+            let n = BLOCK_SIZES[usize::from(long_block)];
+            let window = Window::new(BLOCK_SIZES, long_block, previous_long, long(i + 1));
+            let mapping = usize::from(long_block);
+            let floor = &setup.floors[mapping];
+            let residue = &setup.residues[residues(channels.len())[mapping]];
+
+            let mut writer = BitWriter::default();
+            writer.push(0, 1);
+            writer.push(u32::from(long_block), 1);
+            if long_block {
+                writer.push(u32::from(previous_long), 1);
+                writer.push(u32::from(long(i + 1)), 1);
+            }
+            let mut vectors = Vec::new();
+            let mut skipped = Vec::new();
+            for channel in channels {
+                let mut block = (0..n as isize)
+                    .map(|t| {
+                        let t = usize::try_from(start + t).ok();
+                        t.and_then(|t| channel.get(t)).copied().unwrap_or(0.0)
+                    })
+                    .collect::<Vec<_>>();
+                window.apply(&mut block, &slopes);
+                let coefficients = mdct(&block);
+                let max = coefficients.iter().fold(0f32, |max, x| max.max(x.abs()));
+                if max < 1e-4 {
+                    writer.push(0, 1);
+                    vectors.push(vec![0; n / 2]);
+                    skipped.push(true);
+                    continue;
+                }
+
+                // The floor is as low as it can be while the residue fits with some room to spare,
+                // with a point above the line between the ends and, in long blocks, one below.
+                let limit = residue_limit(residue.kind as usize);
+                let base = (3..253)
+                    .find(|&y| inverse_db[y] * (limit * 2 / 5) as f32 >= max)
+                    .unwrap() as i32;
+                let coded = [base, base, 4, if long_block { 3 } else { 0 }];
+                writer.push(1, 1);
+                writer.push(base as u32, 8);
+                writer.push(base as u32, 8);
+                let subclasses = usize::from(coded[2] != 0) | usize::from(coded[3] != 0) << 1;
+                writer.codeword(&codewords[1], subclasses);
+                for &value in &coded[2..] {
+                    if value != 0 {
+                        writer.codeword(&codewords[0], value as usize);
+                    }
+                }
+                let curve = floor.curve(&coded, n / 2, &inverse_db);
+                vectors.push(
+                    coefficients
+                        .iter()
+                        .zip(&curve)
+                        .map(|(x, floor)| ((x / floor).round() as i32).clamp(-limit, limit))
+                        .collect(),
+                );
+                skipped.push(false);
+            }
+            if let [magnitudes, angles] = &mut vectors[..] {
+                for (m, a) in magnitudes.iter_mut().zip(angles) {
+                    (*m, *a) = couple(*m, *a);
+                }
+                if skipped.contains(&false) {
+                    skipped = vec![false; 2];
+                }
+            }
+            write_residue(&mut writer, &codewords, residue, &vectors, &skipped);
+            packets.push(writer.data);
+        }
+
+        let mut writer = ogg::Writer::new(1);
+        writer.packets(&headers[..1], 0, false);
+        writer.packets(&headers[1..], 0, false);
+        writer.max_segments = 8;
+        writer.packets(&packets, frames as u64, true);
+        writer.data
+    }
+
+    fn sine(frames: usize, start: usize, frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..frames)
+            .map(|t| match t.checked_sub(start) {
+                Some(t) => {
+                    let phase = 2.0 * std::f32::consts::PI * frequency * t as f32;
+                    amplitude * (phase / SAMPLE_RATE as f32).sin()
+                }
+                None => 0.0,
+            })
+            .collect()
+    }
+
+    /// Returns the RMS of the difference between a decoded sound and what was encoded, which is
+    /// mostly the test encoder's quantization noise.
+    fn error(sound: &Sound, channels: &[Vec<f32>]) -> f32 {
+        assert_eq!(usize::from(sound.channels), channels.len());
+        assert_eq!(sound.samples.len(), channels[0].len() * channels.len());
+        let mut sum = 0.0;
+        for (frame, samples) in sound.samples.chunks(channels.len()).enumerate() {
+            for (channel, sample) in samples.iter().enumerate() {
+                sum += (sample - channels[channel][frame]).powi(2);
+            }
+        }
+        (sum / sound.samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn bits() {
+        let mut reader = BitReader::new(&[0b1010_1100, 0b0000_0011, 0xFF]);
+        assert_eq!(reader.read(2), Some(0b00));
+        assert_eq!(reader.read(3), Some(0b011));
+        assert_eq!(reader.read(5), Some(0b11_101));
+        assert_eq!(reader.read(6), Some(0));
+        assert_eq!(reader.read(9), None);
+        assert_eq!(reader.read(1), None);
+
+        assert_eq!(
+            [0, 1, 2, 3, 4, 7, 8, 255].map(ilog),
+            [0, 1, 2, 2, 3, 3, 4, 8]
+        );
+    }
+
+    #[test]
+    fn huffman_codewords() {
+        // The example from the specification.
+        let codewords = codewords(&[2, 4, 4, 4, 4, 2, 3, 3]).unwrap();
+        let codewords = codewords
+            .iter()
+            .map(|codeword| {
+                let (codeword, len) = codeword.unwrap();
+                format!("{:0len$b}", codeword >> (32 - len), len = usize::from(len))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codewords,
+            ["00", "0100", "0101", "0110", "0111", "10", "110", "111"]
+        );
+
+        let codewords = super::codewords(&[1, 0, 2, 0, 2]).unwrap();
+        assert_eq!(
+            codewords,
+            [
+                Some((0, 1)),
+                None,
+                Some((1 << 31, 2)),
+                None,
+                Some((3 << 30, 2))
+            ]
+        );
+
+        assert!(super::codewords(&[1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn lookup_tables() {
+        assert_eq!(unpack_float(pack_float(-127)), -127.0);
+        assert_eq!(unpack_float(pack_float(1)), 1.0);
+        // 1.5 is 3 * 2^-1.
+        assert_eq!(unpack_float(787 << 21 | 3), 1.5);
+        assert_eq!(unpack_float(0), 0.0);
+
+        assert_eq!(lookup1_values(255, 1), 255);
+        assert_eq!(lookup1_values(961, 2), 31);
+        assert_eq!(lookup1_values(960, 2), 30);
+        assert_eq!(lookup1_values(1000, 3), 10);
+        assert_eq!(lookup1_values(999, 3), 9);
+        assert_eq!(lookup1_values(1 << 24, 24), 2);
+    }
+
+    #[test]
+    fn floor_curve() {
+        let mut writer = BitWriter::default();
+        writer.push(0, 5);
+        writer.push(0, 2);
+        writer.push(4, 4);
+        let floor = Floor::parse(&mut BitReader::new(&writer.data), 0).unwrap();
+        assert_eq!(floor.xs, [0, 16]);
+
+        let mut floor = floor;
+        floor.xs.extend([8, 4]);
+        floor.sorted = vec![0, 3, 2, 1];
+        floor.neighbors = vec![(0, 1), (0, 2)];
+        // The third point is predicted to be at 75, and 10 moves it up by 5. The fourth is
+        // exactly on the line, so the curve goes straight from the first point to the third.
+        let inverse_db = std::array::from_fn(|i| i as f32);
+        let curve = floor.curve(&[100, 50, 10, 0], 20, &inverse_db);
+        assert_eq!(
+            curve,
+            [
+                100.0, 98.0, 95.0, 93.0, 90.0, 88.0, 85.0, 83.0, 80.0, 77.0, 73.0, 69.0, 65.0,
+                62.0, 58.0, 54.0, 50.0, 50.0, 50.0, 50.0
+            ]
+        );
+
+        let table = inverse_db_table();
+        assert!((table[0] - 1.0649863e-07).abs() < 1e-12);
+        assert_eq!(table[255], 1.0);
+    }
+
+    #[test]
+    fn fast_imdct() {
+        for n in [64, 512, 2048] {
+            let coefficients = (0..n / 2)
+                .map(|k| ((k * 7919 % 101) as f32 - 50.0) / 50.0)
+                .collect::<Vec<_>>();
+            let mut block = vec![0.0; n];
+            Imdct::new(n).inverse(&coefficients, &mut block);
+            for (t, &sample) in block.iter().enumerate() {
+                let expected = coefficients.iter().enumerate().map(|(k, &x)| {
+                    let angle = 2.0 * PI / n as f64 * (t as f64 + 0.5 + n as f64 / 4.0);
+                    f64::from(x) * (angle * (k as f64 + 0.5)).cos()
+                });
+                let expected = expected.sum::<f64>() as f32;
+                assert!(
+                    (sample - expected).abs() < 1e-3,
+                    "{n}: {sample} != {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mono() {
+        let channels = [sine(3000, 500, 440.0, 0.5)];
+        // Short and long blocks next to each other, which use residues of type 1 and 0.
+        let data = encode(
+            &channels,
+            &[true, true, false, false, false, true, false, true],
+        );
+        let sound = decode(&data).unwrap();
+        assert_eq!(sound.sample_rate, SAMPLE_RATE);
+        let error = error(&sound, &channels);
+        assert!(error < 0.02, "{error}");
+    }
+
+    #[test]
+    fn coupled_stereo() {
+        // The right channel starts later, so for a while only one of the coupled channels is
+        // used.
+        let channels = [sine(3000, 300, 440.0, 0.5), sine(3000, 1100, 660.0, 0.4)];
+        let data = encode(&channels, &[true, false, false, true, true, false]);
+        let sound = decode(&data).unwrap();
+        let error = error(&sound, &channels);
+        assert!(error < 0.02, "{error}");
+    }
+
+    #[test]
+    fn broken_files() {
+        let channels = [sine(2000, 0, 440.0, 0.5)];
+        let data = encode(&channels, &[true]);
+
+        let error = decode(&data[..data.len() - 20]).err().unwrap();
+        assert_eq!(error.to_string(), "Ogg file is truncated");
+
+        // Headers are on their own pages, so this cuts off the setup header.
+        let mut writer = ogg::Writer::new(1);
+        let setup = setup_header(1);
+        writer.packets(
+            &[
+                identification_header(1),
+                b"\x03vorbis".to_vec(),
+                setup[..100].to_vec(),
+            ],
+            0,
+            true,
+        );
+        let error = decode(&writer.data).err().unwrap();
+        assert_eq!(error.to_string(), TRUNCATED);
+
+        let mut writer = ogg::Writer::new(1);
+        writer.packets(&[b"OpusHead".to_vec()], 0, true);
+        let error = decode(&writer.data).err().unwrap();
+        assert_eq!(error.to_string(), "Ogg Opus files are not supported");
+    }
+}
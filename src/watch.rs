@@ -0,0 +1,134 @@
+//! Watches the filesystem for changes that `clickd` reacts to: edits to the config file, and
+//! input devices being plugged in.
+
+use std::{ffi::OsStr, fs, os::unix::io::AsRawFd, path::Path, sync::mpsc::Sender, thread};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+};
+
+use crate::{
+    log::{info, warn},
+    Control,
+};
+
+/// How long to wait for more changes before reacting, in milliseconds.
+///
+/// Editors often save a file in several steps, and udev adjusts the permissions of new devices
+/// right after they appear, which should only cause one reload or rescan.
+const DEBOUNCE_MS: i32 = 200;
+
+/// The directory the evdev device nodes are created in.
+const INPUT_DIR: &str = "/dev/input";
+
+/// Spawns a thread that sends `Control::Reload` to `control_sender` whenever the config file at
+/// `path` has been written to or replaced.
+///
+/// Failure to watch the file is only reported, since `SIGHUP` still works.
+pub fn spawn_config(path: &Path, control_sender: Sender<Control>) {
+    let res = fs::canonicalize(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|path| {
+            // Editors often replace the file instead of writing to it, so watch its directory.
+            let dir = path
+                .parent()
+                .context("config file has no parent directory")?;
+            let name = path.file_name().context("config file has no name")?;
+            let name = name.to_os_string();
+            let flags = AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_CREATE;
+            watch(
+                dir,
+                flags,
+                move |changed| changed == name,
+                || {
+                    info!("configuration file changed");
+                    Control::Reload
+                },
+                control_sender,
+            )
+        });
+    if let Err(e) = res {
+        warn!("failed to watch '{}' for changes: {e:#}", path.display());
+    }
+}
+
+/// Spawns a thread that sends `Control::Rescan` to `control_sender` whenever an input device
+/// appears, or its permissions change.
+pub fn spawn_devices(control_sender: Sender<Control>) {
+    // udev creates the device as root first, so wait for it to grant access.
+    let flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB;
+    let matches = |name: &OsStr| name.to_string_lossy().starts_with("event");
+    let res = watch(
+        Path::new(INPUT_DIR),
+        flags,
+        matches,
+        || Control::Rescan,
+        control_sender,
+    );
+    if let Err(e) = res {
+        warn!("failed to watch {INPUT_DIR} for new devices: {e:#}");
+    }
+}
+
+/// Watches the directory `dir` for `flags` events, and sends `control()` once changes to files
+/// whose name `matches` have settled.
+fn watch(
+    dir: &Path,
+    flags: AddWatchFlags,
+    matches: impl Fn(&OsStr) -> bool + Send + 'static,
+    control: impl Fn() -> Control + Send + 'static,
+    control_sender: Sender<Control>,
+) -> anyhow::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+    inotify.add_watch(dir, flags)?;
+    let dir = dir.to_path_buf();
+    thread::spawn(move || {
+        if let Err(e) = run(inotify, matches, control, control_sender) {
+            warn!("stopped watching {}: {e}", dir.display());
+        }
+    });
+    Ok(())
+}
+
+fn run(
+    inotify: Inotify,
+    matches: impl Fn(&OsStr) -> bool,
+    control: impl Fn() -> Control,
+    control_sender: Sender<Control>,
+) -> nix::Result<()> {
+    let mut pending = false;
+    loop {
+        let timeout = if pending { DEBOUNCE_MS } else { -1 };
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) => {
+                pending = false;
+                // This fails once `main` is shutting down.
+                control_sender.send(control()).ok();
+                continue;
+            }
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+
+        match inotify.read_events() {
+            Ok(events) => {
+                if events
+                    .iter()
+                    .any(|event| event.name.as_deref().is_some_and(&matches))
+                {
+                    // Restart the debounce timer with every change.
+                    pending = true;
+                }
+            }
+            Err(Errno::EAGAIN | Errno::EINTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Translates keysyms (the symbols printed on keys, like `a` or `odiaeresis`) to the evdev keys
+//! that produce them with a keyboard layout, using `libxkbcommon`.
+//!
+//! The library is loaded at runtime, so that `clickd` still works without it, as long as the
+//! `keysyms` setting isn't used.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr,
+};
+
+use anyhow::{bail, Context};
+
+/// The soname of the library, which is also installed without the development files.
+const LIBRARY: &CStr = c"libxkbcommon.so.0";
+
+/// Offset between xkb keycodes and evdev key codes, for historical X11 reasons.
+const EVDEV_OFFSET: u32 = 8;
+
+/// `XKB_KEYSYM_CASE_INSENSITIVE`
+const CASE_INSENSITIVE: c_int = 1;
+
+/// `struct xkb_rule_names`, which selects a keymap. Null fields use the defaults, which can be set
+/// with the `XKB_DEFAULT_*` environment variables.
+#[repr(C)]
+struct RuleNames {
+    rules: *const c_char,
+    model: *const c_char,
+    layout: *const c_char,
+    variant: *const c_char,
+    options: *const c_char,
+}
+
+/// The functions of `libxkbcommon` we use.
+struct Library {
+    handle: *mut c_void,
+    context_new: unsafe extern "C" fn(c_int) -> *mut c_void,
+    context_unref: unsafe extern "C" fn(*mut c_void),
+    keymap_new_from_names:
+        unsafe extern "C" fn(*mut c_void, *const RuleNames, c_int) -> *mut c_void,
+    keymap_unref: unsafe extern "C" fn(*mut c_void),
+    keymap_min_keycode: unsafe extern "C" fn(*mut c_void) -> u32,
+    keymap_max_keycode: unsafe extern "C" fn(*mut c_void) -> u32,
+    keymap_num_levels_for_key: unsafe extern "C" fn(*mut c_void, u32, u32) -> u32,
+    keymap_key_get_syms_by_level:
+        unsafe extern "C" fn(*mut c_void, u32, u32, u32, *mut *const u32) -> c_int,
+    keysym_from_name: unsafe extern "C" fn(*const c_char, c_int) -> u32,
+}
+
+impl Library {
+    fn load() -> anyhow::Result<Self> {
+        // SAFETY: libxkbcommon has no initialization routines with requirements of their own.
+        let handle = unsafe { libc::dlopen(LIBRARY.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            bail!("failed to load {}", LIBRARY.to_string_lossy());
+        }
+        // SAFETY: the signatures match `xkbcommon.h`.
+        unsafe {
+            Ok(Self {
+                handle,
+                context_new: symbol(handle, c"xkb_context_new")?,
+                context_unref: symbol(handle, c"xkb_context_unref")?,
+                keymap_new_from_names: symbol(handle, c"xkb_keymap_new_from_names")?,
+                keymap_unref: symbol(handle, c"xkb_keymap_unref")?,
+                keymap_min_keycode: symbol(handle, c"xkb_keymap_min_keycode")?,
+                keymap_max_keycode: symbol(handle, c"xkb_keymap_max_keycode")?,
+                keymap_num_levels_for_key: symbol(handle, c"xkb_keymap_num_levels_for_key")?,
+                keymap_key_get_syms_by_level: symbol(handle, c"xkb_keymap_key_get_syms_by_level")?,
+                keysym_from_name: symbol(handle, c"xkb_keysym_from_name")?,
+            })
+        }
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        // SAFETY: nothing from the library is used after this.
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+/// Looks up the function `name` in the library `handle`.
+///
+/// # Safety
+///
+/// `T` must be a function pointer type with the function's signature.
+unsafe fn symbol<T>(handle: *mut c_void, name: &CStr) -> anyhow::Result<T> {
+    let ptr = libc::dlsym(handle, name.as_ptr());
+    if ptr.is_null() {
+        bail!(
+            "{} has no `{}`",
+            LIBRARY.to_string_lossy(),
+            name.to_string_lossy()
+        );
+    }
+    Ok(std::mem::transmute_copy(&ptr))
+}
+
+/// A compiled keyboard layout.
+pub struct Keymap {
+    keymap: *mut c_void,
+    lib: Library,
+}
+
+impl Keymap {
+    /// Compiles the keymap for the XKB `layout` (like `de` or `fr`), or for the default layout
+    /// if `layout` is `None`.
+    pub fn new(layout: Option<&str>) -> anyhow::Result<Self> {
+        let lib = Library::load()?;
+        let layout = layout.map(CString::new).transpose()?;
+        let names = RuleNames {
+            rules: ptr::null(),
+            model: ptr::null(),
+            layout: layout
+                .as_ref()
+                .map_or(ptr::null(), |layout| layout.as_ptr()),
+            variant: ptr::null(),
+            options: ptr::null(),
+        };
+        // SAFETY: the context is only used to compile the keymap, which keeps its own reference.
+        let keymap = unsafe {
+            let context = (lib.context_new)(0);
+            if context.is_null() {
+                bail!("failed to create an xkb context");
+            }
+            let keymap = (lib.keymap_new_from_names)(context, &names, 0);
+            (lib.context_unref)(context);
+            keymap
+        };
+        if keymap.is_null() {
+            bail!("failed to compile the keyboard layout");
+        }
+        Ok(Self { keymap, lib })
+    }
+
+    /// Returns the keys that produce the keysym called `name` with the first layout of the
+    /// keymap, on any shift level.
+    ///
+    /// Keysym names are matched exactly, or else ignoring case.
+    pub fn keys(&self, name: &str) -> anyhow::Result<Vec<evdev::Key>> {
+        let c_name = CString::new(name).context("invalid keysym name")?;
+        // SAFETY: the keymap is valid until `self` is dropped, and `syms` points into it.
+        unsafe {
+            let mut keysym = (self.lib.keysym_from_name)(c_name.as_ptr(), 0);
+            if keysym == 0 {
+                keysym = (self.lib.keysym_from_name)(c_name.as_ptr(), CASE_INSENSITIVE);
+            }
+            if keysym == 0 {
+                bail!("unknown keysym `{name}`");
+            }
+
+            let mut keys = Vec::new();
+            let min = (self.lib.keymap_min_keycode)(self.keymap).max(EVDEV_OFFSET);
+            let max = (self.lib.keymap_max_keycode)(self.keymap);
+            for keycode in min..=max {
+                let levels = (self.lib.keymap_num_levels_for_key)(self.keymap, keycode, 0);
+                let produces = (0..levels).any(|level| {
+                    let mut syms = ptr::null();
+                    let n = (self.lib.keymap_key_get_syms_by_level)(
+                        self.keymap,
+                        keycode,
+                        0,
+                        level,
+                        &mut syms,
+                    );
+                    n > 0 && std::slice::from_raw_parts(syms, n as usize).contains(&keysym)
+                });
+                if produces {
+                    keys.push(evdev::Key::new((keycode - EVDEV_OFFSET) as u16));
+                }
+            }
+            Ok(keys)
+        }
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        // SAFETY: the keymap isn't used after this.
+        unsafe { (self.lib.keymap_unref)(self.keymap) }
+    }
+}